@@ -0,0 +1,243 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bincode::serialized_size;
+use shared::{Request, Response};
+
+use crate::ids::PhysicsEntityId;
+
+/// Number of frames the accounting window spans.
+pub const BANDWIDTH_WINDOW: usize = 60;
+
+/// Bytes sent and received during one frame.
+///
+/// Sizes are measured by serializing every item on its own, before compression.
+#[derive(Debug, Default, Clone)]
+pub struct BandwidthSample {
+    pub by_request: HashMap<&'static str, u64>,
+    pub by_entity: HashMap<PhysicsEntityId, u64>,
+}
+
+impl BandwidthSample {
+    fn add(&mut self, other: &BandwidthSample) {
+        for (name, bytes) in &other.by_request {
+            *self.by_request.entry(name).or_default() += bytes;
+        }
+        for (id, bytes) in &other.by_entity {
+            *self.by_entity.entry(*id).or_default() += bytes;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.by_request.values().sum()
+    }
+}
+
+#[derive(Default)]
+pub struct BandwidthAccounting {
+    current: BandwidthSample,
+    window: VecDeque<BandwidthSample>,
+}
+
+impl BandwidthAccounting {
+    pub fn record_request(&mut self, request: &Request) {
+        match request {
+            Request::BulkRequest(requests) => {
+                for request in requests {
+                    self.record_request(request);
+                }
+                return;
+            }
+            Request::CreateBodies(bodies) => {
+                for body in bodies {
+                    *self
+                        .current
+                        .by_entity
+                        .entry(PhysicsEntityId(body.id))
+                        .or_default() += serialized_size(body).unwrap_or_default();
+                }
+            }
+            Request::CreateColliders(colliders) => {
+                for collider in colliders {
+                    *self
+                        .current
+                        .by_entity
+                        .entry(PhysicsEntityId(collider.id))
+                        .or_default() += serialized_size(collider).unwrap_or_default();
+                }
+            }
+            Request::CreateJoints(joints) => {
+                for joint in joints {
+                    *self
+                        .current
+                        .by_entity
+                        .entry(PhysicsEntityId(joint.id))
+                        .or_default() += serialized_size(joint).unwrap_or_default();
+                }
+            }
+            _ => {}
+        }
+        *self.current.by_request.entry(request.name()).or_default() +=
+            serialized_size(request).unwrap_or_default();
+    }
+
+    pub fn record_response(&mut self, response: &Response) {
+        match response {
+            Response::BulkResponse(responses) => {
+                for response in responses {
+                    self.record_response(response);
+                }
+                return;
+            }
+            Response::SimulationResult(bodies, _) | Response::SimulationDelta(bodies, _) => {
+                for body in bodies {
+                    *self
+                        .current
+                        .by_entity
+                        .entry(PhysicsEntityId(body.0))
+                        .or_default() += serialized_size(body).unwrap_or_default();
+                }
+            }
+            _ => {}
+        }
+        *self.current.by_request.entry(response.name()).or_default() +=
+            serialized_size(response).unwrap_or_default();
+    }
+
+    /// Closes the current frame and drops frames older than the window.
+    fn end_frame(&mut self) {
+        self.window.push_back(std::mem::take(&mut self.current));
+        while self.window.len() > BANDWIDTH_WINDOW {
+            self.window.pop_front();
+        }
+    }
+
+    /// Bytes per request type and entity over the whole window.
+    pub fn window_total(&self) -> BandwidthSample {
+        let mut total = BandwidthSample::default();
+        for sample in &self.window {
+            total.add(sample);
+        }
+        total
+    }
+
+    /// The `count` entities with the most traffic in the window, largest first.
+    pub fn top_entities(&self, count: usize) -> Vec<(PhysicsEntityId, u64)> {
+        let mut entities: Vec<_> = self.window_total().by_entity.into_iter().collect();
+        entities.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        entities.truncate(count);
+        entities
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct BandwidthStats(pub Arc<Mutex<BandwidthAccounting>>);
+
+/// Share of the window's traffic above which a single entity is reported.
+#[derive(Resource, Clone, Copy)]
+pub struct BandwidthAlarm(pub f32);
+
+/// Sent when one entity uses more than the [`BandwidthAlarm`] share of the traffic.
+#[derive(Debug, Clone)]
+pub struct BandwidthHotspot {
+    pub id: PhysicsEntityId,
+    pub bytes: u64,
+    pub share: f32,
+}
+
+pub fn check_bandwidth(
+    stats: Res<BandwidthStats>,
+    alarm: Res<BandwidthAlarm>,
+    mut hotspots: EventWriter<BandwidthHotspot>,
+    mut last_hotspot: Local<Option<PhysicsEntityId>>,
+) {
+    let mut accounting = stats.0.lock().unwrap();
+    accounting.end_frame();
+
+    let total = accounting.window_total().total();
+    let hotspot = accounting.top_entities(1).pop().and_then(|(id, bytes)| {
+        let share = bytes as f32 / total.max(1) as f32;
+        (share > alarm.0).then_some(BandwidthHotspot { id, bytes, share })
+    });
+
+    // Only report an entity again after it stopped being the hotspot.
+    if hotspot.as_ref().map(|hotspot| hotspot.id) == *last_hotspot {
+        return;
+    }
+    *last_hotspot = hotspot.as_ref().map(|hotspot| hotspot.id);
+
+    if let Some(hotspot) = hotspot {
+        warn!(
+            "Entity {} used {:.0}% ({} bytes) of the traffic in the last {} frames",
+            hotspot.id,
+            hotspot.share * 100.0,
+            hotspot.bytes,
+            BANDWIDTH_WINDOW
+        );
+        hotspots.send(hotspot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+    use bevy::ecs::system::System;
+    use bevy_rapier3d::prelude::Velocity;
+
+    use super::*;
+
+    /// A step result with only the body `id`.
+    fn step_result(id: u64) -> Response {
+        Response::SimulationResult(vec![(id, Transform::default(), Velocity::zero())], vec![])
+    }
+
+    #[test]
+    fn window_drops_frames_older_than_the_window() {
+        let mut accounting = BandwidthAccounting::default();
+        accounting.record_response(&step_result(1));
+        accounting.end_frame();
+        let recorded = accounting.window_total();
+        assert!(recorded.total() > 0);
+        assert!(recorded.by_entity[&PhysicsEntityId(1)] > 0);
+
+        for _ in 1..BANDWIDTH_WINDOW {
+            accounting.end_frame();
+        }
+        assert_eq!(accounting.window_total().total(), recorded.total());
+
+        accounting.end_frame();
+        let total = accounting.window_total();
+        assert_eq!(total.total(), 0);
+        assert!(total.by_entity.is_empty());
+    }
+
+    #[test]
+    fn alarm_fires_once_per_hotspot() {
+        let stats = BandwidthStats(Arc::new(Mutex::new(BandwidthAccounting::default())));
+        let mut world = World::new();
+        world.insert_resource(stats.clone());
+        world.insert_resource(BandwidthAlarm(0.5));
+        world.init_resource::<Events<BandwidthHotspot>>();
+        let mut system = IntoSystem::into_system(check_bandwidth);
+        system.initialize(&mut world);
+        let mut frames = |id, count| {
+            for _ in 0..count {
+                stats.0.lock().unwrap().record_response(&step_result(id));
+                system.run((), &mut world);
+            }
+        };
+
+        // Stays the hotspot for a while, then another entity takes over the window.
+        frames(1, 10);
+        frames(2, 2 * BANDWIDTH_WINDOW);
+
+        let events = world.resource::<Events<BandwidthHotspot>>();
+        let hotspots: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .map(|hotspot| hotspot.id)
+            .collect();
+        assert_eq!(hotspots, [PhysicsEntityId(1), PhysicsEntityId(2)]);
+    }
+}
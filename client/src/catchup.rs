@@ -0,0 +1,164 @@
+use bevy::prelude::*;
+
+/// What happens to frame time beyond [`CatchupConfig::max_catchup_time`], e.g. after a
+/// debugger pause or the OS suspending the process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchupPolicy {
+    /// Discards the excess, the simulation falls behind wall time but stays responsive.
+    Drop,
+    /// Simulates the excess over the following frames, at most `budget` extra seconds
+    /// per frame. Whatever is owed beyond `max_backlog` seconds is dropped after all.
+    Backlog { budget: f32, max_backlog: f32 },
+}
+
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct CatchupConfig {
+    /// Longest frame that is simulated in one step request, in seconds.
+    pub max_catchup_time: f32,
+    pub policy: CatchupPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The frame was longer than `max_catchup_time`.
+    Clamped,
+    /// The backlog grew beyond `max_backlog`.
+    BacklogFull,
+}
+
+/// Sent whenever frame time is discarded instead of simulated.
+#[derive(Debug, Clone, Copy)]
+pub struct DroppedSimulationTime {
+    pub seconds: f32,
+    pub reason: DropReason,
+}
+
+/// How far the simulated time is behind wall time.
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct SimulationTimeStats {
+    /// Seconds dropped since startup.
+    pub dropped: f32,
+    /// Seconds still owed to the simulation under [`CatchupPolicy::Backlog`].
+    pub backlog: f32,
+}
+
+impl CatchupConfig {
+    /// Returns the seconds to simulate for a frame that took `delta` seconds.
+    pub fn apply(
+        &self,
+        delta: f32,
+        stats: &mut SimulationTimeStats,
+        dropped: &mut EventWriter<DroppedSimulationTime>,
+    ) -> f32 {
+        let excess = (delta - self.max_catchup_time).max(0.0);
+
+        let (simulated, discarded, reason) = match self.policy {
+            CatchupPolicy::Drop => (delta - excess, excess, DropReason::Clamped),
+            CatchupPolicy::Backlog {
+                budget,
+                max_backlog,
+            } => {
+                let owed = stats.backlog + excess;
+                let overflow = (owed - max_backlog).max(0.0);
+                let catchup = (owed - overflow).min(budget);
+                stats.backlog = owed - overflow - catchup;
+                (delta - excess + catchup, overflow, DropReason::BacklogFull)
+            }
+        };
+
+        if discarded > 0.0 {
+            stats.dropped += discarded;
+            dropped.send(DroppedSimulationTime {
+                seconds: discarded,
+                reason,
+            });
+        }
+        simulated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+    use bevy::ecs::system::System;
+
+    use super::*;
+
+    const STALL: f32 = 30.0;
+    const FRAME: f32 = 1.0 / 60.0;
+
+    fn frame(
+        In(delta): In<f32>,
+        config: Res<CatchupConfig>,
+        mut stats: ResMut<SimulationTimeStats>,
+        mut dropped: EventWriter<DroppedSimulationTime>,
+    ) -> f32 {
+        config.apply(delta, &mut stats, &mut dropped)
+    }
+
+    /// Runs a 30 second stall followed by `frames` regular frames, returns the simulated
+    /// seconds of every frame and the events sent.
+    fn stall(
+        policy: CatchupPolicy,
+        frames: usize,
+    ) -> (Vec<f32>, Vec<DroppedSimulationTime>, SimulationTimeStats) {
+        let mut world = World::new();
+        world.insert_resource(CatchupConfig {
+            max_catchup_time: 0.25,
+            policy,
+        });
+        world.init_resource::<SimulationTimeStats>();
+        world.init_resource::<Events<DroppedSimulationTime>>();
+
+        let mut system = IntoSystem::into_system(frame);
+        system.initialize(&mut world);
+        let simulated = std::iter::once(STALL)
+            .chain(std::iter::repeat(FRAME).take(frames))
+            .map(|delta| system.run(delta, &mut world))
+            .collect();
+
+        let events = world
+            .resource_mut::<Events<DroppedSimulationTime>>()
+            .drain()
+            .collect();
+        (simulated, events, *world.resource::<SimulationTimeStats>())
+    }
+
+    #[test]
+    fn drop_discards_the_stall() {
+        let (simulated, events, stats) = stall(CatchupPolicy::Drop, 3);
+
+        assert_eq!(simulated[0], 0.25);
+        assert!(simulated[1..].iter().all(|seconds| *seconds == FRAME));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, DropReason::Clamped);
+        assert!((events[0].seconds - (STALL - 0.25)).abs() < 1e-4);
+        assert!((stats.dropped - (STALL - 0.25)).abs() < 1e-4);
+        assert_eq!(stats.backlog, 0.0);
+    }
+
+    #[test]
+    fn backlog_catches_up_within_its_budget() {
+        let policy = CatchupPolicy::Backlog {
+            budget: 0.05,
+            max_backlog: 1.0,
+        };
+        let (simulated, events, stats) = stall(policy, 30);
+
+        // Everything owed beyond a second is dropped right away, the rest is simulated
+        // 0.05 seconds per frame on top of the regular frames.
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, DropReason::BacklogFull);
+        assert!((events[0].seconds - (STALL - 0.25 - 1.0)).abs() < 1e-3);
+        assert!((simulated[0] - 0.3).abs() < 1e-5);
+        assert!(simulated[1..20]
+            .iter()
+            .all(|seconds| (seconds - (FRAME + 0.05)).abs() < 1e-5));
+        assert!(simulated[21..].iter().all(|seconds| *seconds == FRAME));
+
+        let total: f32 = simulated.iter().sum();
+        assert!((total - (0.25 + 1.0 + 30.0 * FRAME)).abs() < 1e-3);
+        assert!(stats.backlog.abs() < 1e-5);
+        assert!((stats.dropped - events[0].seconds).abs() < 1e-5);
+    }
+}
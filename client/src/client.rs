@@ -1,27 +1,138 @@
 use std::{
     io::{Read, Write},
-    net::TcpStream,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use bevy::{prelude::*, utils::Instant};
 use bincode::{deserialize, serialize};
 use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Decompress};
 use shared::*;
-use tungstenite::{connect, stream::MaybeTlsStream, Message, WebSocket};
+use tungstenite::connect;
 use url::Url;
 
 use human_bytes::human_bytes;
 
-use crate::error::Result;
+use crate::bandwidth::BandwidthAccounting;
+use crate::error::{Error, ErrorKind, Result};
+use crate::shadow::ShadowServer;
+use crate::transport::{SocketOptions, Transport, WebSocketTransport};
+
+/// Time spent in each stage of a request. Checksums count towards
+/// compression and decompression.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequestTimings {
+    pub serialize: Duration,
+    pub compress: Duration,
+    pub round_trip: Duration,
+    pub decompress: Duration,
+    pub deserialize: Duration,
+}
+
+impl AddAssign for RequestTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.serialize += other.serialize;
+        self.compress += other.compress;
+        self.round_trip += other.round_trip;
+        self.decompress += other.decompress;
+        self.deserialize += other.deserialize;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkTimings {
+    pub last: RequestTimings,
+    pub total: RequestTimings,
+    pub requests: u64,
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// `session` counts the reconnects that started over with an empty server world,
+    /// reconnects that resumed the previous world keep it.
+    Connected {
+        session: u32,
+    },
+    Reconnecting {
+        attempt: u32,
+    },
+    /// Every reconnect attempt failed, the client keeps trying every few seconds.
+    Disconnected,
+}
+
+/// When to try connecting again after the connection dropped.
+struct Backoff {
+    attempt: u32,
+    delay: Duration,
+    next_attempt: Instant,
+}
 
 pub struct PhysicsClient {
-    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    transport: Box<dyn Transport>,
+    /// Where to reconnect to, `None` for custom transports.
+    endpoint: Option<(Url, SocketOptions)>,
+    state: Arc<Mutex<ConnectionState>>,
+    session: u32,
+    reconnect: Option<Backoff>,
+    shadow: Option<ShadowServer>,
+    timings: Arc<Mutex<NetworkTimings>>,
+    bandwidth: Option<Arc<Mutex<BandwidthAccounting>>>,
+    closed: bool,
+}
+
+/// Compresses and checksums a serialized request, depending on the enabled features.
+fn encode_payload(serialized: Vec<u8>) -> Result<Vec<u8>> {
+    #[cfg(feature = "compression")]
+    let serialized = {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&serialized)?;
+        encoder.finish()?
+    };
+
+    #[cfg(feature = "checksum")]
+    let serialized = shared::checksum::append(serialized);
+
+    Ok(serialized)
+}
+
+/// Whether the error means the connection is gone, rather than a bad message.
+fn is_disconnect(err: &Error) -> bool {
+    use tungstenite::Error::{AlreadyClosed, ConnectionClosed, Io};
+    matches!(
+        **err,
+        ErrorKind::Io(_) | ErrorKind::Network(ConnectionClosed | AlreadyClosed | Io(_))
+    )
+}
+
+/// Connects and returns whether the server resumed the session of the url.
+fn open_transport(url: &Url, options: SocketOptions) -> Result<(WebSocketTransport, bool)> {
+    let (socket, response) = connect(url.clone())?;
+    let resumed = response
+        .headers()
+        .get(SESSION_RESUMED_HEADER)
+        .map_or(false, |value| value == "true");
+    let transport = WebSocketTransport(socket);
+    transport.configure(options)?;
+    Ok((transport, resumed))
 }
 
 impl PhysicsClient {
-    pub fn new(url: Url) -> Self {
+    pub fn new(url: Url, options: SocketOptions) -> Self {
+        Self::try_connect(url, options).expect("Can't connect to physics server")
+    }
+
+    pub fn try_new(url: Url) -> Result<Self> {
+        Self::try_connect(url, SocketOptions::default())
+    }
+
+    pub fn try_connect(url: Url, options: SocketOptions) -> Result<Self> {
         println!("Connecting to {}", url);
-        let (socket, response) = connect(url).expect("Can't connect to physics server");
+        let (socket, response) = connect(url.clone())?;
 
         println!("Connected to the server");
         println!("Response HTTP code: {}", response.status());
@@ -30,28 +141,120 @@ impl PhysicsClient {
             println!("* {}", header);
         }
 
-        Self { socket }
+        let transport = WebSocketTransport(socket);
+        transport.configure(options)?;
+
+        let mut client = Self::with_transport(transport);
+        client.endpoint = Some((url, options));
+        Ok(client)
     }
 
-    pub fn send_request(&mut self, request: Request) -> Result<Response> {
-        let serialized = serialize(&request)?;
+    /// Sends the requests over `transport` instead of connecting to a server.
+    pub fn with_transport(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            endpoint: None,
+            state: Arc::new(Mutex::new(ConnectionState::Connected { session: 0 })),
+            session: 0,
+            reconnect: None,
+            shadow: None,
+            timings: Default::default(),
+            bandwidth: None,
+            closed: false,
+        }
+    }
 
-        let msg = {
-            #[cfg(feature = "compression")]
-            {
-                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-                encoder.write_all(&serialized)?;
-                let compressed = encoder.finish()?;
+    /// Reconnects to `url` when the transport drops, like a client that connected to it.
+    #[cfg(test)]
+    pub(crate) fn with_endpoint(mut self, url: Url) -> Self {
+        self.endpoint = Some((url, SocketOptions::default()));
+        self
+    }
+
+    /// Mirrors every request to `shadow` after the primary server answered it.
+    pub fn with_shadow(mut self, shadow: ShadowServer) -> Self {
+        self.shadow = Some(shadow);
+        self
+    }
 
-                Message::Binary(compressed)
+    /// Attributes the size of every request and response to request types and entities.
+    pub fn with_bandwidth_accounting(mut self, bandwidth: Arc<Mutex<BandwidthAccounting>>) -> Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    /// Timings of the requests sent by this client, shared with the `NetworkStats` resource.
+    pub fn timings(&self) -> Arc<Mutex<NetworkTimings>> {
+        self.timings.clone()
+    }
+
+    pub fn connection_state(&self) -> Arc<Mutex<ConnectionState>> {
+        self.state.clone()
+    }
+
+    /// Whether requests can be sent. While reconnecting, this tries to connect once
+    /// when the backoff delay has passed, it never waits for it.
+    pub fn poll_reconnect(&mut self) -> bool {
+        let Some(backoff) = &mut self.reconnect else {
+            return true;
+        };
+        if Instant::now() < backoff.next_attempt {
+            return false;
+        }
+
+        let (url, options) = self.endpoint.as_ref().expect("reconnect needs an endpoint");
+        match open_transport(url, *options) {
+            Ok((transport, resumed)) => {
+                info!(
+                    "Reconnected to {} after {} attempts (session resumed: {})",
+                    url, backoff.attempt, resumed
+                );
+                self.transport = Box::new(transport);
+                self.reconnect = None;
+                if !resumed {
+                    self.session += 1;
+                }
+                *self.state.lock().unwrap() = ConnectionState::Connected {
+                    session: self.session,
+                };
+                true
             }
-            #[cfg(not(feature = "compression"))]
-            {
-                Message::Binary(serialized)
+            Err(err) => {
+                warn!("Reconnect attempt {} failed: {}", backoff.attempt, err);
+                backoff.attempt += 1;
+                backoff.delay = (backoff.delay * 2).min(MAX_RECONNECT_DELAY);
+                backoff.next_attempt = Instant::now() + backoff.delay;
+                *self.state.lock().unwrap() = if backoff.attempt > MAX_RECONNECT_ATTEMPTS {
+                    ConnectionState::Disconnected
+                } else {
+                    ConnectionState::Reconnecting {
+                        attempt: backoff.attempt,
+                    }
+                };
+                false
             }
-        };
+        }
+    }
+
+    /// Sends the request and waits for the response. Fails with
+    /// [`ErrorKind::NotConnected`] while reconnecting, and hands the request back in
+    /// [`ErrorKind::ConnectionLost`] when the connection drops while sending it.
+    pub fn send_request(&mut self, request: Request) -> Result<Response> {
+        if !self.poll_reconnect() {
+            return Err(ErrorKind::NotConnected.into());
+        }
+
+        let mut timings = RequestTimings::default();
+
+        let checkpoint = Instant::now();
+        let serialized = serialize(&request)?;
+        timings.serialize = checkpoint.elapsed();
 
-        let msg_len = msg.len();
+        let checkpoint = Instant::now();
+        let payload = encode_payload(serialized)?;
+        timings.compress = checkpoint.elapsed();
+
+        let msg_len = payload.len();
         let request_type = request.name();
 
         debug!(
@@ -64,11 +267,32 @@ impl PhysicsClient {
         trace!("Sending request: {:?}", request);
 
         let start = Instant::now();
-        self.socket.write_message(msg)?;
+        let msg_data = match self.transport.send(payload) {
+            // The server keeps the world of a dropped session, so the request is sent
+            // again after reconnecting, see `process_requests`.
+            Err(err) if self.endpoint.is_some() && !self.closed && is_disconnect(&err) => {
+                warn!("Lost the connection to the server: {}", err);
+                self.reconnect = Some(Backoff {
+                    attempt: 1,
+                    delay: MIN_RECONNECT_DELAY,
+                    next_attempt: Instant::now() + MIN_RECONNECT_DELAY,
+                });
+                *self.state.lock().unwrap() = ConnectionState::Reconnecting { attempt: 1 };
+                return Err(ErrorKind::ConnectionLost {
+                    source: err,
+                    request,
+                }
+                .into());
+            }
+            result => result?,
+        };
+        timings.round_trip = start.elapsed();
+        let msg_len = msg_data.len();
+
+        let checkpoint = Instant::now();
 
-        let msg = self.socket.read_message()?;
-        let msg_len = msg.len();
-        let msg_data = msg.into_data();
+        #[cfg(feature = "checksum")]
+        let msg_data = shared::checksum::verify(msg_data).ok_or(ErrorKind::ChecksumMismatch)?;
 
         let serialized = {
             #[cfg(feature = "compression")]
@@ -84,7 +308,18 @@ impl PhysicsClient {
                 msg_data
             }
         };
+        timings.decompress = checkpoint.elapsed();
+
+        let checkpoint = Instant::now();
         let response = deserialize::<Response>(serialized.as_slice())?;
+        timings.deserialize = checkpoint.elapsed();
+
+        if let Some(bandwidth) = &self.bandwidth {
+            let mut bandwidth = bandwidth.lock().unwrap();
+            bandwidth.record_request(&request);
+            bandwidth.record_response(&response);
+        }
+
         let response_type = response.name();
         let elapsed = start.elapsed();
 
@@ -92,6 +327,11 @@ impl PhysicsClient {
             msg_len,
             response_type,
             latency_in_nanos = elapsed.as_nanos(),
+            serialize_in_nanos = timings.serialize.as_nanos(),
+            compress_in_nanos = timings.compress.as_nanos(),
+            round_trip_in_nanos = timings.round_trip.as_nanos(),
+            decompress_in_nanos = timings.decompress.as_nanos(),
+            deserialize_in_nanos = timings.deserialize.as_nanos(),
             "Received response <{}> ({}) in {:?}",
             response_type,
             human_bytes(msg_len as f64),
@@ -99,6 +339,156 @@ impl PhysicsClient {
         );
         trace!("Received response: {:?}", response);
 
+        {
+            let mut network_timings = self.timings.lock().unwrap();
+            network_timings.last = timings;
+            network_timings.total += timings;
+            network_timings.requests += 1;
+        }
+
+        if let Some(shadow) = &self.shadow {
+            shadow.mirror(request, &response);
+        }
+
         Ok(response)
     }
+
+    /// Casts a ray against the server world and blocks until the hit arrives,
+    /// see [`Request::CastRay`].
+    pub fn cast_ray(
+        &mut self,
+        origin: Vec3,
+        dir: Vec3,
+        max_toi: f32,
+        solid: bool,
+    ) -> Result<Option<(u64, f32, Vec3, Option<Vec<u8>>)>> {
+        let request = Request::CastRay {
+            origin,
+            dir,
+            max_toi,
+            solid,
+        };
+        match self.send_request(request)? {
+            Response::RayHit(hit) => Ok(hit),
+            other => Err(ErrorKind::UnexpectedResponse(other.name()).into()),
+        }
+    }
+
+    /// Tells the server to drop this connection's world and waits until it closed the connection.
+    pub fn goodbye(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        let payload = encode_payload(serialize(&Request::Goodbye)?)?;
+        self.transport.close(payload)
+    }
+}
+
+impl Drop for PhysicsClient {
+    fn drop(&mut self) {
+        if let Err(err) = self.goodbye() {
+            warn!("Failed to say goodbye to the server: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+
+    use super::*;
+
+    /// Answers requests with canned responses and records what it was sent.
+    #[derive(Default)]
+    struct TestTransport {
+        requests: Arc<Mutex<Vec<Request>>>,
+        responses: VecDeque<Response>,
+    }
+
+    /// Undoes `encode_payload`, the way the server decodes a request.
+    fn decode_payload(payload: Vec<u8>) -> Request {
+        #[cfg(feature = "checksum")]
+        let payload = shared::checksum::verify(payload).unwrap();
+
+        #[cfg(feature = "compression")]
+        let payload = {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(payload.as_slice())
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            decompressed
+        };
+
+        deserialize(&payload).unwrap()
+    }
+
+    impl Transport for TestTransport {
+        fn send(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+            self.requests.lock().unwrap().push(decode_payload(bytes));
+            let Some(response) = self.responses.pop_front() else {
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
+            };
+            encode_payload(serialize(&response)?)
+        }
+
+        fn close(&mut self, goodbye: Vec<u8>) -> Result<()> {
+            self.requests.lock().unwrap().push(decode_payload(goodbye));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn requests_go_through_the_transport() {
+        let transport = TestTransport {
+            responses: VecDeque::from([Response::SimTime(1.5)]),
+            ..default()
+        };
+        let requests = transport.requests.clone();
+        let mut client = PhysicsClient::with_transport(transport);
+
+        let response = client.send_request(Request::QuerySimTime).unwrap();
+        assert!(matches!(response, Response::SimTime(time) if time == 1.5));
+        assert_eq!(client.timings.lock().unwrap().requests, 1);
+
+        drop(client);
+        let names: Vec<_> = requests.lock().unwrap().iter().map(Request::name).collect();
+        assert_eq!(names, ["QuerySimTime", "Goodbye"]);
+    }
+
+    #[test]
+    fn custom_transports_dont_reconnect() {
+        let mut client = PhysicsClient::with_transport(TestTransport::default());
+        let state = client.connection_state();
+
+        let err = client.send_request(Request::QuerySimTime).unwrap_err();
+        assert!(is_disconnect(&err));
+        assert_eq!(
+            *state.lock().unwrap(),
+            ConnectionState::Connected { session: 0 }
+        );
+        assert!(client.poll_reconnect());
+    }
+
+    #[test]
+    fn requests_lost_with_the_connection_are_handed_back() {
+        let url = Url::parse("ws://127.0.0.1:1/socket").unwrap();
+        let mut client = PhysicsClient::with_transport(TestTransport::default()).with_endpoint(url);
+        let state = client.connection_state();
+
+        let err = client.send_request(Request::QuerySimTime).unwrap_err();
+        let ErrorKind::ConnectionLost { source, request } = *err else {
+            panic!("unexpected error {}", err);
+        };
+        assert!(is_disconnect(&source));
+        assert!(matches!(request, Request::QuerySimTime));
+        assert_eq!(
+            *state.lock().unwrap(),
+            ConnectionState::Reconnecting { attempt: 1 }
+        );
+        // Waits for the backoff delay before trying to reconnect.
+        assert!(!client.poll_reconnect());
+    }
 }
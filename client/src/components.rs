@@ -0,0 +1,26 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::Velocity;
+
+/// Number of colliders currently overlapping this sensor, updated after every step.
+///
+/// Adding this component to a sensor collider before it is registered makes the
+/// server report its overlap count.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SensorOverlapCount(pub u32);
+
+/// Small gameplay data the server keeps with the rigid body, e.g. a team or material id.
+///
+/// Limited to [`shared::MAX_USER_META_LEN`] bytes, changes are sent to the server.
+#[derive(Component, Debug, Default, Clone, PartialEq, Eq)]
+pub struct UserMeta(pub Vec<u8>);
+
+/// Velocity of the body in the latest simulation result.
+///
+/// Velocities equal to it were written by the writeback and aren't sent back to the server.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
+pub struct ServerVelocity(pub Velocity);
+
+/// Registers a collider whose shape is the convex hull of these points, computed by
+/// the server. Used instead of a `Collider` for procedurally generated chunks.
+#[derive(Component, Debug, Default, Clone)]
+pub struct ConvexHullPoints(pub Vec<Vec3>);
@@ -0,0 +1,309 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use shared::Request;
+
+use crate::error::{ErrorKind, Result};
+use crate::ids::PhysicsIdMap;
+use crate::plugin::RequestQueue;
+
+/// Name and argument hint of a console command, for completion in console UIs.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub help: &'static str,
+}
+
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "phys.gravity",
+        args: "<x> <y> <z>",
+        help: "set the gravity of the remote world",
+    },
+    CommandInfo {
+        name: "phys.pause",
+        args: "",
+        help: "stop stepping the remote world",
+    },
+    CommandInfo {
+        name: "phys.resume",
+        args: "",
+        help: "resume stepping the remote world",
+    },
+    CommandInfo {
+        name: "phys.wake",
+        args: "",
+        help: "wake up all registered bodies",
+    },
+    CommandInfo {
+        name: "phys.clear_forces",
+        args: "",
+        help: "reset the forces and torques of all registered bodies",
+    },
+    CommandInfo {
+        name: "phys.stats",
+        args: "",
+        help: "print the size of the remote world and the pairs of its last step",
+    },
+    CommandInfo {
+        name: "phys.debug_render",
+        args: "",
+        help: "toggle the debug rendering of the local context",
+    },
+    CommandInfo {
+        name: "phys.help",
+        args: "",
+        help: "list the available commands",
+    },
+];
+
+/// A parsed console command that acts on the remote world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleCommand {
+    Gravity(Vect),
+    Pause,
+    Resume,
+    Wake,
+    ClearForces,
+    Stats,
+    ToggleDebugRender,
+}
+
+/// Routes `phys.*` console commands to the physics backend.
+///
+/// Commands are queued by [`PhysicsConsole::execute`] and sent with the
+/// requests of the next frame. Answers that arrive later, like the stats of
+/// the remote world, are collected with [`PhysicsConsole::take_output`].
+#[derive(Resource, Default)]
+pub struct PhysicsConsole {
+    pending: Vec<ConsoleCommand>,
+    output: Vec<String>,
+}
+
+impl PhysicsConsole {
+    pub fn commands(&self) -> &'static [CommandInfo] {
+        COMMANDS
+    }
+
+    /// Lines printed since the last call, oldest first.
+    pub fn take_output(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub(crate) fn print(&mut self, line: String) {
+        self.output.push(line);
+    }
+
+    pub fn execute(&mut self, line: &str) -> Result<String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args = words.collect::<Vec<_>>();
+
+        let command = match (name, args.as_slice()) {
+            ("phys.gravity", [x, y, z]) => {
+                let gravity = Vect::new(parse_arg(x)?, parse_arg(y)?, parse_arg(z)?);
+                ConsoleCommand::Gravity(gravity)
+            }
+            ("phys.pause", []) => ConsoleCommand::Pause,
+            ("phys.resume", []) => ConsoleCommand::Resume,
+            ("phys.wake", []) => ConsoleCommand::Wake,
+            ("phys.clear_forces", []) => ConsoleCommand::ClearForces,
+            ("phys.stats", []) => ConsoleCommand::Stats,
+            ("phys.debug_render", []) => ConsoleCommand::ToggleDebugRender,
+            ("phys.help", []) => {
+                return Ok(COMMANDS
+                    .iter()
+                    .map(|info| format!("{} {} - {}", info.name, info.args, info.help))
+                    .collect::<Vec<_>>()
+                    .join("\n"));
+            }
+            _ => {
+                let usage = COMMANDS
+                    .iter()
+                    .find(|info| info.name == name)
+                    .map(|info| format!("usage: {} {}", info.name, info.args))
+                    .unwrap_or_else(|| format!("unknown command: {}", name));
+                return Err(ErrorKind::InvalidCommand(usage).into());
+            }
+        };
+
+        self.pending.push(command);
+        Ok(format!("{:?}", command))
+    }
+}
+
+fn parse_arg(arg: &str) -> Result<f32> {
+    arg.parse()
+        .map_err(|_| ErrorKind::InvalidCommand(format!("not a number: {}", arg)).into())
+}
+
+pub fn apply_console_commands(
+    mut console: ResMut<PhysicsConsole>,
+    mut config: ResMut<RapierConfiguration>,
+    rigid_bodies: Query<Entity, With<RapierRigidBodyHandle>>,
+    mut debug_render: Option<ResMut<DebugRenderContext>>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    if console.pending.is_empty() {
+        return;
+    }
+
+    let mut body_ids = || {
+        rigid_bodies
+            .iter()
+            .map(|entity| ids.register(entity).0)
+            .collect()
+    };
+
+    // Config changes are picked up by `update_config` in the same frame.
+    for command in std::mem::take(&mut console.pending) {
+        match command {
+            ConsoleCommand::Gravity(gravity) => config.gravity = gravity,
+            ConsoleCommand::Pause => config.physics_pipeline_active = false,
+            ConsoleCommand::Resume => config.physics_pipeline_active = true,
+            ConsoleCommand::Wake => request_queue.0.push(Request::WakeBodies(body_ids())),
+            ConsoleCommand::ClearForces => request_queue.0.push(Request::ClearForces(body_ids())),
+            // Answered by the writeback, see `PhysicsConsole::take_output`.
+            ConsoleCommand::Stats => request_queue.0.push(Request::GetDiagnostics),
+            ConsoleCommand::ToggleDebugRender => match debug_render.as_deref_mut() {
+                Some(debug_render) => {
+                    debug_render.enabled = !debug_render.enabled;
+                    let state = if debug_render.enabled { "on" } else { "off" };
+                    console.print(format!("debug render {}", state));
+                }
+                None => console.print("debug render needs RapierDebugRenderPlugin".to_string()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::System;
+    use bevy_rapier3d::rapier::prelude::RigidBodyHandle;
+
+    use super::*;
+
+    fn parse(line: &str) -> Result<ConsoleCommand> {
+        let mut console = PhysicsConsole::default();
+        console.execute(line)?;
+        Ok(console.pending[0])
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("phys.gravity 0 -3 0").unwrap(),
+            ConsoleCommand::Gravity(Vect::new(0.0, -3.0, 0.0))
+        );
+        assert_eq!(parse("phys.pause").unwrap(), ConsoleCommand::Pause);
+        assert_eq!(parse("  phys.resume ").unwrap(), ConsoleCommand::Resume);
+        assert_eq!(parse("phys.wake").unwrap(), ConsoleCommand::Wake);
+        assert_eq!(
+            parse("phys.clear_forces").unwrap(),
+            ConsoleCommand::ClearForces
+        );
+        assert_eq!(parse("phys.stats").unwrap(), ConsoleCommand::Stats);
+        assert_eq!(
+            parse("phys.debug_render").unwrap(),
+            ConsoleCommand::ToggleDebugRender
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for line in [
+            "phys.gravity 0 x 0",
+            "phys.gravity 0 -3",
+            "phys.pause now",
+            "phys.stats all",
+            "phys.fly",
+            "",
+        ] {
+            let err = parse(line).unwrap_err();
+            assert!(matches!(*err, ErrorKind::InvalidCommand(_)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn help_answers_without_queueing() {
+        let mut console = PhysicsConsole::default();
+        let help = console.execute("phys.help").unwrap();
+        assert_eq!(help.lines().count(), COMMANDS.len());
+        assert!(console.pending.is_empty());
+    }
+
+    #[test]
+    fn maps_commands_to_requests() {
+        let mut world = World::new();
+        world.init_resource::<PhysicsConsole>();
+        world.insert_resource(RapierConfiguration::default());
+        world.init_resource::<PhysicsIdMap>();
+        world.init_resource::<RequestQueue>();
+        let body = world
+            .spawn(RapierRigidBodyHandle(RigidBodyHandle::from_raw_parts(0, 0)))
+            .id();
+
+        let mut console = world.resource_mut::<PhysicsConsole>();
+        for line in [
+            "phys.gravity 0 -3 0",
+            "phys.pause",
+            "phys.wake",
+            "phys.clear_forces",
+            "phys.stats",
+        ] {
+            console.execute(line).unwrap();
+        }
+
+        let mut system = IntoSystem::into_system(apply_console_commands);
+        system.initialize(&mut world);
+        system.run((), &mut world);
+
+        let config = world.resource::<RapierConfiguration>();
+        assert_eq!(config.gravity, Vect::new(0.0, -3.0, 0.0));
+        assert!(!config.physics_pipeline_active);
+
+        let ids = vec![world.resource_mut::<PhysicsIdMap>().register(body).0];
+        let requests = &world.resource::<RequestQueue>().0;
+        assert_eq!(requests.len(), 3);
+        assert!(matches!(&requests[0], Request::WakeBodies(woken) if *woken == ids));
+        assert!(matches!(&requests[1], Request::ClearForces(cleared) if *cleared == ids));
+        assert!(matches!(requests[2], Request::GetDiagnostics));
+    }
+
+    #[test]
+    fn toggles_the_local_debug_render() {
+        let mut world = World::new();
+        world.init_resource::<PhysicsConsole>();
+        world.insert_resource(RapierConfiguration::default());
+        world.init_resource::<PhysicsIdMap>();
+        world.init_resource::<RequestQueue>();
+        let mut system = IntoSystem::into_system(apply_console_commands);
+        system.initialize(&mut world);
+
+        // Without the debug render plugin, there is nothing to toggle.
+        world
+            .resource_mut::<PhysicsConsole>()
+            .execute("phys.debug_render")
+            .unwrap();
+        system.run((), &mut world);
+        let output = world.resource_mut::<PhysicsConsole>().take_output();
+        assert_eq!(output, ["debug render needs RapierDebugRenderPlugin"]);
+
+        world.insert_resource(DebugRenderContext::default());
+        let enabled = world.resource::<DebugRenderContext>().enabled;
+        world
+            .resource_mut::<PhysicsConsole>()
+            .execute("phys.debug_render")
+            .unwrap();
+        system.run((), &mut world);
+        assert_eq!(world.resource::<DebugRenderContext>().enabled, !enabled);
+        assert_eq!(
+            world.resource_mut::<PhysicsConsole>().take_output().len(),
+            1
+        );
+        assert!(world.resource::<RequestQueue>().0.is_empty());
+    }
+}
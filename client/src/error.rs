@@ -1,5 +1,8 @@
 use std::error::Error as StdError;
 use std::fmt;
+
+use shared::Request;
+
 pub type Result<T> = std::result::Result<T, Error>;
 pub type Error = Box<ErrorKind>;
 
@@ -10,6 +13,17 @@ pub enum ErrorKind {
     Network(tungstenite::Error),
     Compression(flate2::CompressError),
     Decmpression(flate2::DecompressError),
+    ChecksumMismatch,
+    InvalidCommand(String),
+    UnexpectedResponse(&'static str),
+    /// The connection dropped and the client is waiting to reconnect.
+    NotConnected,
+    /// The connection dropped while sending `request`, which is handed back to be
+    /// sent again once the client reconnected.
+    ConnectionLost {
+        source: Error,
+        request: Request,
+    },
 }
 
 impl StdError for ErrorKind {
@@ -20,6 +34,11 @@ impl StdError for ErrorKind {
             ErrorKind::Network(ref err) => Some(err),
             ErrorKind::Compression(ref err) => Some(err),
             ErrorKind::Decmpression(ref err) => Some(err),
+            ErrorKind::ChecksumMismatch => None,
+            ErrorKind::InvalidCommand(_) => None,
+            ErrorKind::UnexpectedResponse(_) => None,
+            ErrorKind::NotConnected => None,
+            ErrorKind::ConnectionLost { ref source, .. } => Some(source.as_ref()),
         }
     }
 }
@@ -62,6 +81,19 @@ impl fmt::Display for ErrorKind {
             ErrorKind::Network(ref err) => write!(fmt, "network error: {}", err),
             ErrorKind::Compression(ref err) => write!(fmt, "compression error: {}", err),
             ErrorKind::Decmpression(ref err) => write!(fmt, "decompression error: {}", err),
+            ErrorKind::ChecksumMismatch => write!(fmt, "checksum mismatch"),
+            ErrorKind::InvalidCommand(ref usage) => write!(fmt, "invalid command: {}", usage),
+            ErrorKind::UnexpectedResponse(name) => write!(fmt, "unexpected response <{}>", name),
+            ErrorKind::NotConnected => write!(fmt, "not connected, reconnecting"),
+            ErrorKind::ConnectionLost {
+                ref source,
+                ref request,
+            } => write!(
+                fmt,
+                "connection lost sending <{}>: {}",
+                request.name(),
+                source
+            ),
         }
     }
 }
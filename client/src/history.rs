@@ -0,0 +1,152 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PhysicsSample {
+    pub time: f32,
+    pub transform: Transform,
+    pub velocity: Option<Velocity>,
+}
+
+/// Keeps the last `capacity` states the server sent for every rigid body, recorded by
+/// the writeback whenever a `SimulationResult` or `SimulationDelta` arrives.
+#[derive(Resource)]
+pub struct PhysicsHistory {
+    capacity: usize,
+    record_velocity: bool,
+    latest_time: f32,
+    samples: HashMap<Entity, VecDeque<PhysicsSample>>,
+}
+
+impl PhysicsHistory {
+    pub fn new(capacity: usize, record_velocity: bool) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            record_velocity,
+            latest_time: 0.0,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn record(
+        &mut self,
+        entity: Entity,
+        time: f32,
+        transform: Transform,
+        velocity: Option<Velocity>,
+    ) {
+        let samples = self
+            .samples
+            .entry(entity)
+            .or_insert_with(|| VecDeque::with_capacity(self.capacity));
+
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+
+        samples.push_back(PhysicsSample {
+            time,
+            transform,
+            velocity: velocity.filter(|_| self.record_velocity),
+        });
+
+        self.latest_time = self.latest_time.max(time);
+    }
+
+    /// Samples of the given entity, oldest first.
+    pub fn samples(&self, entity: Entity) -> impl Iterator<Item = &PhysicsSample> {
+        self.samples.get(&entity).into_iter().flatten()
+    }
+
+    pub fn latest(&self, entity: Entity) -> Option<&PhysicsSample> {
+        self.samples.get(&entity).and_then(|samples| samples.back())
+    }
+
+    /// Interpolated pose of the entity `seconds_ago` before the latest recorded sample.
+    /// Requests outside the stored range are clamped to the oldest/newest sample.
+    pub fn pose_at(&self, entity: Entity, seconds_ago: f32) -> Option<Transform> {
+        let samples = self.samples.get(&entity)?;
+        let target = self.latest_time - seconds_ago;
+
+        let first = samples.front()?;
+        if target <= first.time {
+            return Some(first.transform);
+        }
+
+        for (prev, next) in samples.iter().zip(samples.iter().skip(1)) {
+            if target <= next.time {
+                let span = next.time - prev.time;
+                let t = if span > 0.0 {
+                    (target - prev.time) / span
+                } else {
+                    1.0
+                };
+
+                return Some(Transform {
+                    translation: prev
+                        .transform
+                        .translation
+                        .lerp(next.transform.translation, t),
+                    rotation: prev.transform.rotation.slerp(next.transform.rotation, t),
+                    scale: prev.transform.scale.lerp(next.transform.scale, t),
+                });
+            }
+        }
+
+        samples.back().map(|sample| sample.transform)
+    }
+}
+
+/// Forgets the samples of despawned bodies.
+pub fn evict_history(
+    mut history: ResMut<PhysicsHistory>,
+    rigid_bodies: Query<(), With<RapierRigidBodyHandle>>,
+) {
+    // Removal trackers are cleared at the end of every frame, so despawned
+    // entities are detected by their absence from the query instead.
+    history
+        .samples
+        .retain(|entity, _| rigid_bodies.contains(*entity));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Transform {
+        Transform::from_xyz(x, 0.0, 0.0)
+    }
+
+    #[test]
+    fn keeps_only_the_last_samples() {
+        let entity = Entity::from_raw(0);
+        let mut history = PhysicsHistory::new(3, false);
+        for i in 0..5 {
+            history.record(entity, i as f32, at(i as f32), Some(Velocity::zero()));
+        }
+
+        let times: Vec<_> = history.samples(entity).map(|sample| sample.time).collect();
+        assert_eq!(times, [2.0, 3.0, 4.0]);
+        assert!(history.latest(entity).unwrap().velocity.is_none());
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let entity = Entity::from_raw(0);
+        let mut history = PhysicsHistory::new(8, true);
+        history.record(entity, 1.0, at(0.0), None);
+        history.record(entity, 2.0, at(10.0), None);
+
+        assert_eq!(history.pose_at(entity, 0.25).unwrap().translation.x, 7.5);
+        // Outside of the stored range, the closest sample is used.
+        assert_eq!(history.pose_at(entity, 5.0).unwrap().translation.x, 0.0);
+        assert_eq!(history.pose_at(entity, -1.0).unwrap().translation.x, 10.0);
+        assert!(history.pose_at(Entity::from_raw(1), 0.0).is_none());
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use bevy::prelude::*;
+
+/// Id of a body or collider as the server knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PhysicsEntityId(pub u64);
+
+impl fmt::Display for PhysicsEntityId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Maps server ids back to the entities that registered them.
+///
+/// Ids the client never sent (e.g. bodies of other clients in a shared world)
+/// have no entity, so lookups return `None` instead of a made-up `Entity`.
+#[derive(Resource, Default)]
+pub struct PhysicsIdMap {
+    entities: HashMap<PhysicsEntityId, Entity>,
+}
+
+impl PhysicsIdMap {
+    /// Registers the entity and returns the id it is sent with.
+    pub fn register(&mut self, entity: Entity) -> PhysicsEntityId {
+        let id = PhysicsEntityId(entity.to_bits());
+        self.entities.insert(id, entity);
+        id
+    }
+
+    /// Forgets the entity and returns the id it was sent with.
+    pub fn forget(&mut self, entity: Entity) -> PhysicsEntityId {
+        let id = PhysicsEntityId(entity.to_bits());
+        self.entities.remove(&id);
+        id
+    }
+
+    pub fn entity(&self, id: PhysicsEntityId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_ids_resolve_to_their_entity() {
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        let mut ids = PhysicsIdMap::default();
+
+        let id = ids.register(entity);
+        assert_eq!(id, PhysicsEntityId(entity.to_bits()));
+        assert_eq!(ids.entity(id), Some(entity));
+
+        ids.forget(entity);
+        assert_eq!(ids.entity(id), None);
+    }
+
+    #[test]
+    fn unknown_ids_have_no_entity() {
+        let ids = PhysicsIdMap::default();
+        assert_eq!(ids.entity(PhysicsEntityId(42)), None);
+    }
+}
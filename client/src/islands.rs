@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::{RapierRigidBodyHandle, Real};
+
+use shared::{IslandInfo, Request};
+
+use crate::ids::{PhysicsEntityId, PhysicsIdMap};
+use crate::plugin::RequestQueue;
+
+/// An island of active dynamic bodies connected by contacts.
+#[derive(Debug, Clone)]
+pub struct Island {
+    pub bodies: Vec<PhysicsEntityId>,
+    pub kinetic_energy: Real,
+}
+
+impl From<IslandInfo> for Island {
+    fn from(island: IslandInfo) -> Self {
+        Self {
+            bodies: island.bodies.into_iter().map(PhysicsEntityId).collect(),
+            kinetic_energy: island.kinetic_energy,
+        }
+    }
+}
+
+/// Islands of active bodies from the latest `GetIslands` request, sorted by their
+/// lowest body id.
+#[derive(Resource, Default)]
+pub struct PhysicsIslands(pub Vec<Island>);
+
+impl PhysicsIslands {
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn largest(&self) -> Option<&Island> {
+        self.0.iter().max_by_key(|island| island.bodies.len())
+    }
+
+    /// Index of the island the body belongs to, stable until the next update.
+    pub fn island_of(&self, id: PhysicsEntityId) -> Option<usize> {
+        self.0.iter().position(|island| island.bodies.contains(&id))
+    }
+}
+
+/// Seconds between two `GetIslands` requests.
+#[derive(Resource, Clone, Copy)]
+pub struct IslandRequestInterval(pub f32);
+
+pub fn request_islands(
+    time: Res<Time>,
+    interval: Res<IslandRequestInterval>,
+    mut elapsed: Local<f32>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    *elapsed += time.delta_seconds();
+    if *elapsed < interval.0 {
+        return;
+    }
+
+    *elapsed = 0.0;
+    request_queue.0.push(Request::GetIslands);
+}
+
+const ISLAND_COLORS: [Color; 6] = [
+    Color::RED,
+    Color::GREEN,
+    Color::BLUE,
+    Color::YELLOW,
+    Color::CYAN,
+    Color::FUCHSIA,
+];
+
+/// Colors bodies by their island while enabled, toggled with `toggle`.
+#[derive(Resource, Clone, Copy)]
+pub struct IslandColoring {
+    pub enabled: bool,
+    pub toggle: KeyCode,
+}
+
+/// The material a body had before it was colored by its island.
+#[derive(Component)]
+pub struct OwnMaterial(Handle<StandardMaterial>);
+
+/// Bodies outside of active islands keep their own material.
+pub fn color_islands(
+    mut commands: Commands,
+    keys: Option<Res<Input<KeyCode>>>,
+    mut coloring: ResMut<IslandColoring>,
+    islands: Res<PhysicsIslands>,
+    ids: Res<PhysicsIdMap>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut palette: Local<Vec<Handle<StandardMaterial>>>,
+    mut bodies: Query<
+        (Entity, &mut Handle<StandardMaterial>, Option<&OwnMaterial>),
+        With<RapierRigidBodyHandle>,
+    >,
+) {
+    if keys.map_or(false, |keys| keys.just_pressed(coloring.toggle)) {
+        coloring.enabled = !coloring.enabled;
+    }
+    if !coloring.is_changed() && !islands.is_changed() {
+        return;
+    }
+
+    if palette.is_empty() {
+        *palette = ISLAND_COLORS
+            .iter()
+            .map(|color| materials.add((*color).into()))
+            .collect();
+    }
+
+    let island_of: HashMap<Entity, usize> = if coloring.enabled {
+        islands
+            .0
+            .iter()
+            .enumerate()
+            .flat_map(|(i, island)| island.bodies.iter().map(move |id| (*id, i)))
+            .filter_map(|(id, i)| Some((ids.entity(id)?, i)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    for (entity, mut material, own) in &mut bodies {
+        match (island_of.get(&entity), own) {
+            (Some(island), _) => {
+                if own.is_none() {
+                    commands
+                        .entity(entity)
+                        .insert(OwnMaterial(material.clone()));
+                }
+                *material = palette[island % palette.len()].clone();
+            }
+            (None, Some(own)) => {
+                *material = own.0.clone();
+                commands.entity(entity).remove::<OwnMaterial>();
+            }
+            (None, None) => {}
+        }
+    }
+}
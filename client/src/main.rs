@@ -11,11 +11,22 @@ use rand::Rng;
 
 use color_space::{Lch, ToRgb};
 
+mod bandwidth;
+mod catchup;
 mod client;
+mod components;
+mod console;
 mod error;
+mod history;
+mod ids;
+mod islands;
 mod log;
+mod middleware;
 mod plugin;
+mod registration;
+mod shadow;
 mod systems;
+mod transport;
 
 #[derive(Component)]
 struct Shape;
@@ -42,6 +53,25 @@ struct SpawnTimerDuration(i32);
 #[derive(Resource)]
 struct BallLimit(i32);
 
+/// Samples kept per body, enough for the trail at 60 frames per second.
+const TRAIL_HISTORY: usize = 32;
+const TRAIL_DOTS: usize = 8;
+/// Time between two dots of a trail, in seconds.
+const TRAIL_SPACING: f32 = 0.05;
+
+/// One dot of the trail behind `body`, placed where the server had it `seconds_ago`.
+#[derive(Component)]
+struct TrailDot {
+    body: Entity,
+    seconds_ago: f32,
+}
+
+#[derive(Resource)]
+struct TrailData {
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
 fn main() {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "client=debug");
@@ -76,6 +106,9 @@ fn main() {
             .required(false)
             .value_parser(value_parser!(i32).range(1..)),
         )
+        .arg(arg!(
+            -t --trails "Draw trails behind the balls from the physics history"
+        ))
         .get_matches();
 
     let mut app = App::new();
@@ -87,6 +120,9 @@ fn main() {
     #[cfg(feature = "compression")]
     prefixes.push("comp");
 
+    #[cfg(feature = "checksum")]
+    prefixes.push("crc");
+
     let file_name = format!(
         "{}_{}.log",
         prefixes.join("_"),
@@ -113,8 +149,19 @@ fn main() {
         rapier_physics = rapier_physics.with_port(port);
     }
 
+    let trails = matches.get_flag("trails");
+    if trails {
+        rapier_physics = rapier_physics.with_history(TRAIL_HISTORY);
+    }
+
     app.add_plugin(rapier_physics);
 
+    if trails {
+        app.add_startup_system(setup_trails)
+            .add_system(spawn_trails)
+            .add_system(update_trails);
+    }
+
     if let Some(frames) = matches.get_one::<i32>("spawn") {
         app.insert_resource(SpawnTimerDuration(*frames))
         .add_system(add_balls_automatically);
@@ -229,6 +276,7 @@ fn setup_physics(
         Vec3::new(10.0, 2.0, 10.0),
         Vec3::new(4.0, 1.0, 4.0),
     );
+    spawn_pendulum(&mut commands, &ball_data, Vec3::new(-6.0, 7.0, 4.0));
 
     commands.spawn(DirectionalLightBundle {
         directional_light: DirectionalLight {
@@ -299,6 +347,34 @@ fn spawn_box(
     ));
 }
 
+/// A fixed ball with a second one hinged to it, released sideways so it swings.
+fn spawn_pendulum(commands: &mut Commands, ball_data: &BallData, pivot: Vec3) {
+    let arm = Vec3::new(3.0, 0.0, 0.0);
+    let anchor = commands
+        .spawn((
+            RigidBody::Fixed,
+            Collider::ball(0.5),
+            PbrBundle {
+                mesh: ball_data.mesh.clone(),
+                material: ball_data.materials[0].clone(),
+                transform: Transform::from_translation(pivot),
+                ..default()
+            },
+        ))
+        .id();
+    commands.spawn((
+        RigidBody::Dynamic,
+        Collider::ball(0.5),
+        ImpulseJoint::new(anchor, RevoluteJointBuilder::new(Vec3::Z).local_anchor2(-arm)),
+        PbrBundle {
+            mesh: ball_data.mesh.clone(),
+            material: ball_data.materials[1].clone(),
+            transform: Transform::from_translation(pivot + arm),
+            ..default()
+        },
+    ));
+}
+
 fn spawn_ball(
     commands: &mut Commands,
     ball_data: BallData,
@@ -406,4 +482,70 @@ fn close_after_n_balls(
     if balls_spawned.0 >= ball_limit.0 {
         exit.send(AppExit);
     }
-}
\ No newline at end of file
+}
+fn setup_trails(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.insert_resource(TrailData {
+        mesh: meshes.add(
+            shape::UVSphere {
+                radius: 0.1,
+                sectors: 8,
+                stacks: 4,
+            }
+            .into(),
+        ),
+        material: materials.add(StandardMaterial {
+            base_color: Color::rgba(1.0, 1.0, 1.0, 0.6),
+            unlit: true,
+            alpha_mode: AlphaMode::Blend,
+            ..default()
+        }),
+    });
+}
+
+fn spawn_trails(
+    mut commands: Commands,
+    trail_data: Res<TrailData>,
+    balls: Query<(Entity, &Transform), Added<Shape>>,
+) {
+    for (body, transform) in &balls {
+        for i in 1..=TRAIL_DOTS {
+            commands.spawn((
+                PbrBundle {
+                    mesh: trail_data.mesh.clone(),
+                    material: trail_data.material.clone(),
+                    transform: Transform::from_translation(transform.translation),
+                    ..default()
+                },
+                NotShadowCaster,
+                NotShadowReceiver,
+                TrailDot {
+                    body,
+                    seconds_ago: i as f32 * TRAIL_SPACING,
+                },
+            ));
+        }
+    }
+}
+
+/// Moves the dots to where the server had their ball, the trail lags behind the ball by
+/// up to `TRAIL_DOTS * TRAIL_SPACING` seconds.
+fn update_trails(
+    mut commands: Commands,
+    history: Res<history::PhysicsHistory>,
+    balls: Query<(), With<Shape>>,
+    mut dots: Query<(Entity, &TrailDot, &mut Transform)>,
+) {
+    for (entity, dot, mut transform) in &mut dots {
+        if !balls.contains(dot.body) {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        if let Some(pose) = history.pose_at(dot.body, dot.seconds_ago) {
+            transform.translation = pose.translation;
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use bevy::prelude::*;
+use shared::Request;
+
+/// What a middleware decided to do with an outgoing request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MiddlewareDecision {
+    /// Send the request unchanged.
+    Pass,
+    /// The request was mutated in place, send it.
+    Modify,
+    /// Don't send the request.
+    Drop(String),
+}
+
+/// Inspects every queued request right before it is sent.
+pub type RequestMiddleware = fn(&mut Request) -> MiddlewareDecision;
+
+/// Middlewares in the order they were added to the plugin.
+#[derive(Resource, Default, Clone)]
+pub struct RequestMiddlewares(pub Vec<RequestMiddleware>);
+
+/// Sent when a middleware drops a request.
+#[derive(Debug, Clone)]
+pub struct RequestDropped {
+    pub request: &'static str,
+    pub reason: String,
+}
+
+impl RequestMiddlewares {
+    /// Runs all middlewares over the requests, removing the dropped ones.
+    pub fn apply(&self, requests: &mut Vec<Request>, dropped: &mut EventWriter<RequestDropped>) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        requests.retain_mut(|request| {
+            for middleware in &self.0 {
+                if let MiddlewareDecision::Drop(reason) = middleware(request) {
+                    debug!("Dropped request <{}>: {}", request.name(), reason);
+                    dropped.send(RequestDropped {
+                        request: request.name(),
+                        reason,
+                    });
+                    return false;
+                }
+            }
+            true
+        });
+    }
+}
+
+pub const MAX_CREATES_PER_FRAME: usize = 256;
+
+/// Limits the number of bodies, colliders and joints created per frame.
+///
+/// The init systems already send at most this many, so this only cuts requests built
+/// elsewhere. Entities left out that way are sent again once their attempt times out.
+pub fn limit_creates(request: &mut Request) -> MiddlewareDecision {
+    let truncated = match request {
+        Request::CreateBodies(bodies) if bodies.len() > MAX_CREATES_PER_FRAME => {
+            bodies.truncate(MAX_CREATES_PER_FRAME);
+            true
+        }
+        Request::CreateColliders(colliders) if colliders.len() > MAX_CREATES_PER_FRAME => {
+            colliders.truncate(MAX_CREATES_PER_FRAME);
+            true
+        }
+        Request::CreateJoints(joints) if joints.len() > MAX_CREATES_PER_FRAME => {
+            joints.truncate(MAX_CREATES_PER_FRAME);
+            true
+        }
+        _ => false,
+    };
+
+    if truncated {
+        MiddlewareDecision::Modify
+    } else {
+        MiddlewareDecision::Pass
+    }
+}
+
+/// Largest force, torque, impulse or torque impulse [`clamp_forces`] lets through.
+pub const MAX_FORCE: f32 = 1000.0;
+
+/// Scales forces and impulses down to at most [`MAX_FORCE`], keeping their direction.
+pub fn clamp_forces(request: &mut Request) -> MiddlewareDecision {
+    fn clamp(vector: &mut Vec3) -> bool {
+        if vector.length() <= MAX_FORCE {
+            return false;
+        }
+        *vector = vector.clamp_length_max(MAX_FORCE);
+        true
+    }
+
+    let mut clamped = false;
+    match request {
+        Request::ApplyForces(forces) => {
+            for (_, force) in forces {
+                clamped |= clamp(&mut force.force);
+                clamped |= clamp(&mut force.torque);
+            }
+        }
+        Request::ApplyImpulses(impulses) => {
+            for (_, impulse) in impulses {
+                clamped |= clamp(&mut impulse.impulse);
+                clamped |= clamp(&mut impulse.torque_impulse);
+            }
+        }
+        Request::RadialImpulse { strength, .. } if strength.abs() > MAX_FORCE => {
+            *strength = strength.clamp(-MAX_FORCE, MAX_FORCE);
+            clamped = true;
+        }
+        _ => {}
+    }
+
+    if clamped {
+        MiddlewareDecision::Modify
+    } else {
+        MiddlewareDecision::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::serializable::{SerializableExternalForce, SerializableExternalImpulse};
+
+    use super::*;
+
+    #[test]
+    fn clamps_forces_and_impulses() {
+        let mut request = Request::ApplyForces(vec![
+            (
+                1,
+                SerializableExternalForce {
+                    force: Vec3::new(0.0, 3.0 * MAX_FORCE, 0.0),
+                    torque: Vec3::X,
+                },
+            ),
+            (
+                2,
+                SerializableExternalForce {
+                    force: Vec3::Y,
+                    torque: Vec3::Y,
+                },
+            ),
+        ]);
+        assert_eq!(clamp_forces(&mut request), MiddlewareDecision::Modify);
+        let Request::ApplyForces(forces) = &request else {
+            unreachable!();
+        };
+        assert!((forces[0].1.force - Vec3::new(0.0, MAX_FORCE, 0.0)).length() < 1e-3);
+        assert_eq!(forces[0].1.torque, Vec3::X);
+        assert_eq!(forces[1].1.force, Vec3::Y);
+
+        let mut request = Request::ApplyImpulses(vec![(
+            1,
+            SerializableExternalImpulse {
+                impulse: Vec3::ZERO,
+                torque_impulse: Vec3::splat(MAX_FORCE),
+            },
+        )]);
+        assert_eq!(clamp_forces(&mut request), MiddlewareDecision::Modify);
+        let Request::ApplyImpulses(impulses) = &request else {
+            unreachable!();
+        };
+        assert!((impulses[0].1.torque_impulse.length() - MAX_FORCE).abs() < 1e-3);
+    }
+
+    #[test]
+    fn passes_small_forces() {
+        let mut request = Request::ApplyForces(vec![(
+            1,
+            SerializableExternalForce {
+                force: Vec3::splat(10.0),
+                torque: Vec3::ZERO,
+            },
+        )]);
+        assert_eq!(clamp_forces(&mut request), MiddlewareDecision::Pass);
+        assert_eq!(
+            clamp_forces(&mut Request::QuerySimTime),
+            MiddlewareDecision::Pass
+        );
+    }
+}
@@ -1,12 +1,28 @@
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
 
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::Uuid};
 use bevy_rapier3d::prelude::*;
 
+use shared::serializable::ContentHash;
 use shared::{Request, Response};
 use url::Url;
 
-use crate::{client::PhysicsClient, error::Result, systems};
+use crate::{
+    bandwidth::{self, BandwidthAlarm, BandwidthHotspot, BandwidthStats},
+    catchup::{CatchupConfig, CatchupPolicy, DroppedSimulationTime, SimulationTimeStats},
+    client::{ConnectionState, NetworkTimings, PhysicsClient},
+    console::{self, PhysicsConsole},
+    error::Result,
+    history,
+    ids::PhysicsIdMap,
+    islands::{self, IslandColoring, IslandRequestInterval, PhysicsIslands},
+    middleware::{RequestDropped, RequestMiddleware, RequestMiddlewares},
+    registration::RegistrationFailedPermanently,
+    shadow::{ShadowReportWrapper, ShadowServer},
+    systems,
+    transport::SocketOptions,
+};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
 enum PhysicsStage {
@@ -17,6 +33,22 @@ enum PhysicsStage {
 pub struct RapierPhysicsPlugin {
     addr: String,
     port: u16,
+    history_length: usize,
+    history_velocity: bool,
+    local_context: bool,
+    middlewares: Vec<RequestMiddleware>,
+    prediction_distance: Option<Real>,
+    physics_scale: Real,
+    island_interval: Option<f32>,
+    result_interval: u32,
+    shadow_server: Option<Url>,
+    bandwidth_alarm: Option<f32>,
+    ccd_speed_threshold: Option<Real>,
+    socket_options: SocketOptions,
+    extrapolate_omitted: bool,
+    mesh_cache: bool,
+    catchup: Option<CatchupConfig>,
+    delta_epsilon: Option<Real>,
 }
 
 impl RapierPhysicsPlugin {
@@ -24,6 +56,22 @@ impl RapierPhysicsPlugin {
         Self {
             addr: "localhost".to_string(),
             port: 8080,
+            history_length: 0,
+            history_velocity: true,
+            local_context: true,
+            middlewares: Vec::new(),
+            prediction_distance: None,
+            physics_scale: 1.0,
+            island_interval: None,
+            result_interval: 1,
+            shadow_server: None,
+            bandwidth_alarm: None,
+            ccd_speed_threshold: None,
+            socket_options: SocketOptions::default(),
+            extrapolate_omitted: false,
+            mesh_cache: false,
+            catchup: None,
+            delta_epsilon: None,
         }
     }
 
@@ -36,11 +84,181 @@ impl RapierPhysicsPlugin {
         self.port = port;
         self
     }
+
+    /// Keep the last `length` written back states of every body in [`history::PhysicsHistory`].
+    pub fn with_history(mut self, length: usize) -> Self {
+        self.history_length = length;
+        self
+    }
+
+    pub fn with_history_velocity(mut self, record_velocity: bool) -> Self {
+        self.history_velocity = record_velocity;
+        self
+    }
+
+    /// Whether to insert a local `RapierContext` and `SimulationToRenderTime`.
+    /// The simulation runs on the server, so a thin client can skip them.
+    pub fn with_local_context(mut self, local_context: bool) -> Self {
+        self.local_context = local_context;
+        self
+    }
+
+    /// Whether to disable Nagle's algorithm on the connection, on by default.
+    pub fn with_tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Sets the send and receive buffer sizes of the connection in bytes.
+    pub fn with_socket_buffers(mut self, send: usize, recv: usize) -> Self {
+        self.socket_options.send_buffer_size = Some(send);
+        self.socket_options.recv_buffer_size = Some(recv);
+        self
+    }
+
+    /// Adds a middleware that runs on every request before it is sent.
+    /// Middlewares run in the order they were added.
+    pub fn with_request_middleware(mut self, middleware: RequestMiddleware) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Sets the contact prediction distance of the whole world, in world units.
+    /// Raising it helps with stability of stacked thin objects.
+    pub fn with_prediction_distance(mut self, distance: Real) -> Self {
+        self.prediction_distance = Some(distance);
+        self
+    }
+
+    /// Sets the length of one physics unit in world units, like bevy_rapier's
+    /// `with_physics_scale`. The server converts every request and response with it.
+    pub fn with_physics_scale(mut self, physics_scale: Real) -> Self {
+        self.physics_scale = physics_scale;
+        self
+    }
+
+    /// Only write back the server results every `interval` steps.
+    /// The server still simulates every step.
+    pub fn with_result_interval(mut self, interval: u32) -> Self {
+        self.result_interval = interval;
+        self
+    }
+
+    /// Mirrors all traffic to a second server and compares its step results
+    /// with the primary's in [`ShadowReportWrapper`]. Gameplay only uses the primary.
+    pub fn with_shadow_server(mut self, url: Url) -> Self {
+        self.shadow_server = Some(url);
+        self
+    }
+
+    /// Tracks the traffic per request type and entity in [`BandwidthStats`] and sends a
+    /// [`BandwidthHotspot`] when one entity takes more than `share` of it.
+    pub fn with_bandwidth_alarm(mut self, share: f32) -> Self {
+        self.bandwidth_alarm = Some(share);
+        self
+    }
+
+    /// Enables CCD on bodies while they move faster than `speed`, in world units per second.
+    /// CCD is expensive, so it is turned off again once they slow down.
+    pub fn with_ccd_speed_threshold(mut self, speed: Real) -> Self {
+        self.ccd_speed_threshold = Some(speed);
+        self
+    }
+
+    /// Moves bodies the server left out of a simulation result along their last known
+    /// velocity instead of keeping them in place.
+    pub fn with_extrapolation(mut self, extrapolate: bool) -> Self {
+        self.extrapolate_omitted = extrapolate;
+        self
+    }
+
+    /// Sends trimesh colliders by content hash, so the server can reuse shapes it
+    /// already got from an earlier connection instead of receiving them again.
+    /// Only colliders without sensor, mass, friction or restitution settings are sent this way.
+    pub fn with_mesh_cache(mut self, mesh_cache: bool) -> Self {
+        self.mesh_cache = mesh_cache;
+        self
+    }
+
+    /// Simulates at most `seconds` of a long frame right away, the rest is handled by
+    /// `policy`. Discarded time is reported with [`DroppedSimulationTime`] events.
+    /// Without this, every frame is simulated in full, however long it took.
+    pub fn with_max_catchup_time(mut self, seconds: f32, policy: CatchupPolicy) -> Self {
+        self.catchup = Some(CatchupConfig {
+            max_catchup_time: seconds,
+            policy,
+        });
+        self
+    }
+
+    /// Only receives the bodies that are awake or moved more than `epsilon` since they
+    /// were last received, see `Request::SimulateStepDelta`.
+    pub fn with_delta_results(mut self, epsilon: Real) -> Self {
+        self.delta_epsilon = Some(epsilon);
+        self
+    }
+
+    /// Requests the simulation islands every `interval` seconds into [`islands::PhysicsIslands`].
+    /// Pressing `I` colors the bodies by island.
+    pub fn with_island_debug(mut self, interval: f32) -> Self {
+        self.island_interval = Some(interval);
+        self
+    }
+}
+
+/// Contact prediction distance sent with the config, `None` for Rapier's default.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct PredictionDistance(pub Option<Real>);
+
+/// Number of steps between two simulation results.
+#[derive(Resource, Clone, Copy)]
+pub struct ResultInterval(pub u32);
+
+/// Whether bodies missing from a simulation result are extrapolated.
+#[derive(Resource, Clone, Copy)]
+pub struct ExtrapolateOmittedBodies(pub bool);
+
+/// Physics scale of the server world, sent with the config. Stands in for
+/// `RapierContext::physics_scale`, the local context keeps its default scale.
+#[derive(Resource, Clone, Copy)]
+pub struct PhysicsScale(pub Real);
+
+/// Translation and rotation the writeback last wrote to each body, so the
+/// transform sync can tell them apart from transforms changed by gameplay code.
+#[derive(Resource, Default)]
+pub struct WrittenTransforms(pub HashMap<Entity, (Vec3, Quat)>);
+
+/// Entities whose handles were removed to register them again after a reconnect,
+/// so the removal systems don't remove them from the server.
+#[derive(Resource, Default)]
+pub struct Reregistering(pub HashSet<Entity>);
+
+/// Steps with `SimulateStepDelta` and this epsilon.
+#[derive(Resource, Clone, Copy)]
+pub struct DeltaResultEpsilon(pub Real);
+
+/// Speed above which bodies get CCD enabled.
+#[derive(Resource, Clone, Copy)]
+pub struct CcdSpeedThreshold(pub Real);
+
+#[derive(Resource, Default)]
+pub struct MeshCache {
+    pub enabled: bool,
+    /// Hashes the server didn't have, their shapes are sent along on the next attempt.
+    pub misses: HashSet<ContentHash>,
 }
 
 #[derive(Resource)]
 pub struct PhysicsClientWrapper(pub Arc<Mutex<PhysicsClient>>);
 
+/// Connection state as seen by the request thread, mirrored into the `ConnectionState` resource.
+#[derive(Resource, Clone)]
+pub struct ConnectionStateWrapper(pub Arc<Mutex<ConnectionState>>);
+
+/// Where the time of the requests went, updated by the request thread.
+#[derive(Resource, Clone)]
+pub struct NetworkStats(pub Arc<Mutex<NetworkTimings>>);
+
 // Couldn't get futures working with Bevy
 // TODO: Implement this with futures instead of polling
 #[cfg(feature = "bulk-requests")]
@@ -65,6 +283,16 @@ impl Default for RequestResult {
     }
 }
 
+/// Shared with the request thread, so a frame doesn't start sending before the
+/// previous frame's requests are done.
+#[derive(Resource, Default)]
+pub struct RequestThread {
+    pub busy: Arc<AtomicBool>,
+    /// Requests not sent because the client was reconnecting, or lost with the connection
+    /// while sending them. They go out first with the next batch.
+    pub unsent: Arc<Mutex<Vec<Request>>>,
+}
+
 impl Plugin for RapierPhysicsPlugin {
     fn build(&self, app: &mut App) {
         // Default initialization
@@ -96,11 +324,37 @@ impl Plugin for RapierPhysicsPlugin {
             app.insert_resource(RapierConfiguration::default());
         }
 
-        app.insert_resource(SimulationToRenderTime::default())
-            .insert_resource(RapierContext::default());
+        if self.local_context {
+            app.insert_resource(SimulationToRenderTime::default())
+                .insert_resource(RapierContext::default());
+        }
 
+        app.insert_resource(PredictionDistance(self.prediction_distance))
+            .insert_resource(PhysicsScale(self.physics_scale))
+            .insert_resource(ResultInterval(self.result_interval))
+            .insert_resource(ExtrapolateOmittedBodies(self.extrapolate_omitted))
+            .insert_resource(MeshCache {
+                enabled: self.mesh_cache,
+                ..default()
+            });
         app.insert_resource(RequestQueue::default());
-        app.insert_resource(RequestResult::default());
+        app.insert_resource(RequestResult::default())
+            .insert_resource(RequestThread::default());
+        app.insert_resource(RequestMiddlewares(self.middlewares.clone()))
+            .add_event::<RequestDropped>()
+            .add_event::<RegistrationFailedPermanently>()
+            .add_event::<DroppedSimulationTime>()
+            .insert_resource(SimulationTimeStats::default());
+        if let Some(catchup) = self.catchup {
+            app.insert_resource(catchup);
+        }
+        if let Some(epsilon) = self.delta_epsilon {
+            app.insert_resource(DeltaResultEpsilon(epsilon));
+        }
+        app.insert_resource(PhysicsConsole::default());
+        app.insert_resource(PhysicsIdMap::default());
+        app.insert_resource(WrittenTransforms::default());
+        app.insert_resource(Reregistering::default());
 
         // Custom initialization
 
@@ -109,22 +363,111 @@ impl Plugin for RapierPhysicsPlugin {
             PhysicsStage::SyncBackend,
             SystemStage::parallel().with_system_set(
                 SystemSet::new()
-                    .with_system(systems::update_config)
-                    .with_system(systems::init_rigid_bodies.after(systems::update_config))
+                    .with_system(console::apply_console_commands)
+                    .with_system(systems::update_config.after(console::apply_console_commands))
+                    .with_system(systems::update_result_interval.after(systems::update_config))
+                    .with_system(systems::init_rigid_bodies.after(systems::update_result_interval))
                     .with_system(systems::init_colliders.after(systems::init_rigid_bodies))
-                    .with_system(systems::simulate_step.after(systems::init_colliders))
-                    .with_system(systems::process_requests.after(systems::simulate_step)),
+                    .with_system(systems::init_joints.after(systems::init_colliders))
+                    // Before the step, so the step already uses the new velocities.
+                    .with_system(systems::sync_velocities.after(systems::init_joints))
+                    .with_system(systems::sync_body_positions.after(systems::sync_velocities))
+                    .with_system(systems::sync_ccd.after(systems::sync_body_positions))
+                    .with_system(systems::simulate_step.after(systems::sync_ccd))
+                    .with_system(systems::sync_user_meta.after(systems::simulate_step))
+                    .with_system(systems::sync_external_forces.after(systems::sync_user_meta))
+                    .with_system(
+                        systems::sync_external_impulses.after(systems::sync_external_forces),
+                    )
+                    .with_system(systems::sync_damping.after(systems::sync_external_impulses))
+                    .with_system(systems::sync_gravity_scale.after(systems::sync_damping))
+                    .with_system(systems::sync_locked_axes.after(systems::sync_gravity_scale))
+                    .with_system(systems::process_requests.after(systems::sync_locked_axes)),
             ),
         );
 
         app.add_stage_before(
             PhysicsStage::SyncBackend,
             PhysicsStage::Writeback,
-            SystemStage::parallel().with_system(systems::writeback), //with_run_criteria(FixedTimestep::steps_per_second(1.0))
+            SystemStage::parallel()
+                .with_system(systems::writeback) //with_run_criteria(FixedTimestep::steps_per_second(1.0))
+                .with_system(systems::handle_reconnect.after(systems::writeback)),
         );
 
-        let url = Url::parse(format!("ws://{}:{}/socket", self.addr, self.port).as_str()).unwrap();
-        let client = PhysicsClient::new(url);
+        if self.history_length > 0 {
+            app.insert_resource(history::PhysicsHistory::new(
+                self.history_length,
+                self.history_velocity,
+            ))
+            .add_system_to_stage(
+                PhysicsStage::Writeback,
+                history::evict_history.after(systems::writeback),
+            );
+        }
+
+        if let Some(interval) = self.island_interval {
+            app.insert_resource(PhysicsIslands::default())
+                .insert_resource(IslandRequestInterval(interval))
+                .insert_resource(IslandColoring {
+                    enabled: false,
+                    toggle: KeyCode::I,
+                })
+                .add_system_to_stage(
+                    PhysicsStage::SyncBackend,
+                    islands::request_islands.before(systems::process_requests),
+                )
+                .add_system(islands::color_islands);
+        }
+
+        app.add_system_to_stage(CoreStage::PostUpdate, systems::remove_bodies)
+            .add_system_to_stage(CoreStage::PostUpdate, systems::remove_colliders)
+            .add_system_to_stage(CoreStage::PostUpdate, systems::remove_joints)
+            .add_system_to_stage(CoreStage::Last, systems::say_goodbye);
+
+        if let Some(speed) = self.ccd_speed_threshold {
+            // In the writeback stage, so the `Ccd` it inserts is there when it is synced.
+            app.insert_resource(CcdSpeedThreshold(speed))
+                .add_system_to_stage(
+                    PhysicsStage::Writeback,
+                    systems::update_ccd_by_speed.after(systems::writeback),
+                );
+        }
+
+        // A fresh session per app, so a reconnect gets the world of this app back.
+        let url = Url::parse(
+            format!(
+                "ws://{}:{}/socket?{}={}",
+                self.addr,
+                self.port,
+                shared::SESSION_QUERY_PARAM,
+                Uuid::new_v4()
+            )
+            .as_str(),
+        )
+        .unwrap();
+        let mut client = PhysicsClient::new(url, self.socket_options);
+
+        if let Some(shadow_url) = &self.shadow_server {
+            let report = ShadowReportWrapper(Default::default());
+            client = client.with_shadow(ShadowServer::spawn(shadow_url.clone(), report.0.clone()));
+            app.insert_resource(report);
+        }
+
+        if let Some(share) = self.bandwidth_alarm {
+            let stats = BandwidthStats(Default::default());
+            client = client.with_bandwidth_accounting(stats.0.clone());
+            app.insert_resource(stats)
+                .insert_resource(BandwidthAlarm(share))
+                .add_event::<BandwidthHotspot>()
+                .add_system_to_stage(
+                    PhysicsStage::Writeback,
+                    bandwidth::check_bandwidth.after(systems::writeback),
+                );
+        }
+
+        app.insert_resource(NetworkStats(client.timings()))
+            .insert_resource(ConnectionState::Connected { session: 0 })
+            .insert_resource(ConnectionStateWrapper(client.connection_state()));
         let wrapper = PhysicsClientWrapper(Arc::new(Mutex::new(client)));
         app.insert_resource(wrapper);
     }
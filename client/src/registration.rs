@@ -0,0 +1,281 @@
+use bevy::prelude::*;
+
+use crate::ids::PhysicsEntityId;
+
+/// Attempts after which an entity is given up on.
+pub const MAX_REGISTRATION_ATTEMPTS: u32 = 5;
+
+/// Seconds to wait for a handle before an attempt counts as failed.
+const PENDING_TIMEOUT: f32 = 1.0;
+
+const BASE_RETRY_DELAY: f32 = 0.25;
+const MAX_RETRY_DELAY: f32 = 8.0;
+
+/// Registration progress of a rigid body, collider or joint on the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegistrationState {
+    /// Sent, waiting for the handle.
+    Pending {
+        attempts: u32,
+        since: f32,
+    },
+    /// Rejected by the server, sent again at `next_retry`.
+    Failed {
+        attempts: u32,
+        next_retry: f32,
+    },
+    Registered,
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct BodyRegistration(pub RegistrationState);
+
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct ColliderRegistration(pub RegistrationState);
+
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct JointRegistration(pub RegistrationState);
+
+/// Sent once an entity ran out of registration attempts.
+#[derive(Debug, Clone)]
+pub struct RegistrationFailedPermanently {
+    pub entity: Entity,
+    /// Id the entity was sent with.
+    pub id: PhysicsEntityId,
+    pub error: String,
+}
+
+pub trait Registration: Component + Copy {
+    fn new(state: RegistrationState) -> Self;
+    fn state(&self) -> RegistrationState;
+}
+
+impl Registration for BodyRegistration {
+    fn new(state: RegistrationState) -> Self {
+        Self(state)
+    }
+
+    fn state(&self) -> RegistrationState {
+        self.0
+    }
+}
+
+impl Registration for ColliderRegistration {
+    fn new(state: RegistrationState) -> Self {
+        Self(state)
+    }
+
+    fn state(&self) -> RegistrationState {
+        self.0
+    }
+}
+
+impl Registration for JointRegistration {
+    fn new(state: RegistrationState) -> Self {
+        Self(state)
+    }
+
+    fn state(&self) -> RegistrationState {
+        self.0
+    }
+}
+
+fn retry_delay(attempts: u32) -> f32 {
+    (BASE_RETRY_DELAY * 2f32.powi(attempts as i32 - 1)).min(MAX_RETRY_DELAY)
+}
+
+/// Decides whether an unregistered entity should be sent this frame and updates its state.
+///
+/// Returns `false` while a previous attempt is in flight, while waiting for a retry,
+/// and after the entity was given up on.
+pub fn try_register<R: Registration>(
+    entity: Entity,
+    id: PhysicsEntityId,
+    registration: Option<&R>,
+    now: f32,
+    commands: &mut Commands,
+    failed: &mut EventWriter<RegistrationFailedPermanently>,
+) -> bool {
+    let attempts = match registration.map(|registration| registration.state()) {
+        None => 0,
+        Some(RegistrationState::Pending { attempts, since }) => {
+            if now - since < PENDING_TIMEOUT {
+                return false;
+            }
+            if attempts >= MAX_REGISTRATION_ATTEMPTS {
+                give_up::<R>(entity, attempts, commands);
+                failed.send(RegistrationFailedPermanently {
+                    entity,
+                    id,
+                    error: "timed out waiting for the server".to_string(),
+                });
+                return false;
+            }
+            attempts
+        }
+        Some(RegistrationState::Failed {
+            attempts,
+            next_retry,
+        }) => {
+            if now < next_retry {
+                return false;
+            }
+            attempts
+        }
+        Some(RegistrationState::Registered) => return false,
+    };
+
+    commands
+        .entity(entity)
+        .insert(R::new(RegistrationState::Pending {
+            attempts: attempts + 1,
+            since: now,
+        }));
+    true
+}
+
+/// Gives up on an entity that can't be registered at all, without sending it.
+pub fn fail<R: Registration>(
+    entity: Entity,
+    id: PhysicsEntityId,
+    error: String,
+    commands: &mut Commands,
+    failed: &mut EventWriter<RegistrationFailedPermanently>,
+) {
+    give_up::<R>(entity, 0, commands);
+    failed.send(RegistrationFailedPermanently { entity, id, error });
+}
+
+fn give_up<R: Registration>(entity: Entity, attempts: u32, commands: &mut Commands) {
+    commands
+        .entity(entity)
+        .insert(R::new(RegistrationState::Failed {
+            attempts,
+            next_retry: f32::INFINITY,
+        }));
+}
+
+/// Schedules a retry after the server rejected the entity, or gives up on it.
+pub fn reject<R: Registration>(
+    entity: Entity,
+    id: PhysicsEntityId,
+    error: String,
+    commands: &mut Commands,
+) {
+    commands.add(move |world: &mut World| {
+        let now = world.resource::<Time>().elapsed_seconds();
+        let Some(registration) = world.get::<R>(entity).copied() else {
+            return;
+        };
+        let RegistrationState::Pending { attempts, .. } = registration.state() else {
+            return;
+        };
+
+        if attempts >= MAX_REGISTRATION_ATTEMPTS {
+            world
+                .entity_mut(entity)
+                .insert(R::new(RegistrationState::Failed {
+                    attempts,
+                    next_retry: f32::INFINITY,
+                }));
+            world.send_event(RegistrationFailedPermanently { entity, id, error });
+        } else {
+            world
+                .entity_mut(entity)
+                .insert(R::new(RegistrationState::Failed {
+                    attempts,
+                    next_retry: now + retry_delay(attempts),
+                }));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::SystemState;
+
+    use super::*;
+    use crate::ids::PhysicsIdMap;
+
+    fn setup() -> (World, Entity) {
+        let mut world = World::new();
+        // Never updated, so rejections schedule retries relative to 0.
+        world.insert_resource(Time::default());
+        world.init_resource::<Events<RegistrationFailedPermanently>>();
+        let entity = world.spawn_empty().id();
+        (world, entity)
+    }
+
+    fn attempt(world: &mut World, entity: Entity, now: f32) -> bool {
+        let registration = world.get::<BodyRegistration>(entity).copied();
+        let mut state =
+            SystemState::<(Commands, EventWriter<RegistrationFailedPermanently>)>::new(world);
+        let (mut commands, mut failed) = state.get_mut(world);
+        let sent = try_register(
+            entity,
+            PhysicsIdMap::default().register(entity),
+            registration.as_ref(),
+            now,
+            &mut commands,
+            &mut failed,
+        );
+        state.apply(world);
+        sent
+    }
+
+    fn reject_attempt(world: &mut World, entity: Entity) {
+        let mut state = SystemState::<Commands>::new(world);
+        let mut commands = state.get_mut(world);
+        reject::<BodyRegistration>(
+            entity,
+            PhysicsIdMap::default().register(entity),
+            "rejected".to_string(),
+            &mut commands,
+        );
+        state.apply(world);
+    }
+
+    #[test]
+    fn rejected_twice_then_registered() {
+        let (mut world, entity) = setup();
+
+        assert!(attempt(&mut world, entity, 0.0));
+        reject_attempt(&mut world, entity);
+        assert_eq!(
+            world.get::<BodyRegistration>(entity),
+            Some(&BodyRegistration(RegistrationState::Failed {
+                attempts: 1,
+                next_retry: BASE_RETRY_DELAY,
+            }))
+        );
+        // Waits for the retry delay.
+        assert!(!attempt(&mut world, entity, BASE_RETRY_DELAY / 2.0));
+
+        assert!(attempt(&mut world, entity, 1.0));
+        reject_attempt(&mut world, entity);
+        assert_eq!(
+            world.get::<BodyRegistration>(entity),
+            Some(&BodyRegistration(RegistrationState::Failed {
+                attempts: 2,
+                next_retry: retry_delay(2),
+            }))
+        );
+
+        assert!(attempt(&mut world, entity, 2.0));
+        assert_eq!(
+            world.get::<BodyRegistration>(entity),
+            Some(&BodyRegistration(RegistrationState::Pending {
+                attempts: 3,
+                since: 2.0,
+            }))
+        );
+
+        world
+            .entity_mut(entity)
+            .insert(BodyRegistration(RegistrationState::Registered));
+        assert!(!attempt(&mut world, entity, 3.0));
+        assert!(world
+            .resource::<Events<RegistrationFailedPermanently>>()
+            .is_empty());
+    }
+}
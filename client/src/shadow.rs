@@ -0,0 +1,159 @@
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bevy::prelude::*;
+use shared::{Request, Response};
+use url::Url;
+
+use crate::client::PhysicsClient;
+
+/// Requests waiting for the shadow server before it is given up on.
+const SHADOW_QUEUE_LENGTH: usize = 64;
+
+/// Position difference above which a body counts as diverged.
+const DIVERGENCE_THRESHOLD: f32 = 1e-4;
+
+/// How far the shadow server's step results drifted from the primary's.
+#[derive(Debug, Default, Clone)]
+pub struct ShadowReport {
+    pub compared_steps: u64,
+    pub diverged_steps: u64,
+    pub max_position_delta: f32,
+    /// First body whose position diverged, or that only one server knew about.
+    pub first_diverging_entity: Option<u64>,
+    /// Requests the shadow server failed to answer.
+    pub errors: u64,
+    /// Set when a request couldn't be mirrored because the shadow server fell behind.
+    /// Its world differs from the primary's from then on, so nothing else is compared.
+    pub desynced: bool,
+}
+
+#[derive(Resource, Clone)]
+pub struct ShadowReportWrapper(pub Arc<Mutex<ShadowReport>>);
+
+/// Mirrors requests to a secondary server on its own thread.
+///
+/// The primary connection never waits for the shadow server. When it falls behind,
+/// mirroring stops and the report is marked as desynced. Its errors only end up in
+/// the report.
+pub struct ShadowServer {
+    sender: SyncSender<(Request, Response)>,
+    report: Arc<Mutex<ShadowReport>>,
+}
+
+impl ShadowServer {
+    pub fn spawn(url: Url, report: Arc<Mutex<ShadowReport>>) -> Self {
+        let (sender, receiver) = sync_channel::<(Request, Response)>(SHADOW_QUEUE_LENGTH);
+
+        let thread_report = report.clone();
+        thread::spawn(move || {
+            let mut client = match PhysicsClient::try_new(url) {
+                Ok(client) => client,
+                Err(err) => {
+                    error!("Failed to connect to the shadow server: {}", err);
+                    return;
+                }
+            };
+
+            for (request, primary) in receiver {
+                if thread_report.lock().unwrap().desynced {
+                    break;
+                }
+                match client.send_request(request) {
+                    Ok(shadow) => compare(&primary, &shadow, &mut thread_report.lock().unwrap()),
+                    Err(err) => {
+                        warn!("Shadow server error: {}", err);
+                        thread_report.lock().unwrap().errors += 1;
+                    }
+                }
+            }
+        });
+
+        Self { sender, report }
+    }
+
+    pub fn mirror(&self, request: Request, primary: &Response) {
+        if self.report.lock().unwrap().desynced {
+            return;
+        }
+        match self.sender.try_send((request, primary.clone())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("The shadow server fell behind, no longer comparing its results");
+                self.report.lock().unwrap().desynced = true;
+            }
+            // The shadow thread has stopped, its error was already logged.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+fn compare(primary: &Response, shadow: &Response, report: &mut ShadowReport) {
+    match (primary, shadow) {
+        (Response::BulkResponse(primary), Response::BulkResponse(shadow)) => {
+            for (primary, shadow) in primary.iter().zip(shadow) {
+                compare(primary, shadow, report);
+            }
+        }
+        (Response::SimulationResult(primary, _), Response::SimulationResult(shadow, _))
+        | (Response::SimulationDelta(primary, _), Response::SimulationDelta(shadow, _)) => {
+            report.compared_steps += 1;
+
+            let mut diverged = primary.len() != shadow.len();
+            if diverged && report.first_diverging_entity.is_none() {
+                report.first_diverging_entity = primary
+                    .iter()
+                    .map(|(id, _, _)| *id)
+                    .find(|id| shadow.binary_search_by_key(id, |(id, _, _)| *id).is_err());
+            }
+
+            // Both results are sorted by id.
+            for ((id, primary, _), (shadow_id, shadow, _)) in primary.iter().zip(shadow) {
+                let delta = if id == shadow_id {
+                    primary.translation.distance(shadow.translation)
+                } else {
+                    f32::INFINITY
+                };
+
+                if delta > DIVERGENCE_THRESHOLD {
+                    diverged = true;
+                    report.first_diverging_entity.get_or_insert(*id);
+                }
+                if delta.is_finite() {
+                    report.max_position_delta = report.max_position_delta.max(delta);
+                }
+            }
+
+            if diverged {
+                report.diverged_steps += 1;
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falling_behind_desyncs() {
+        let (sender, receiver) = sync_channel(1);
+        let report = Arc::new(Mutex::new(ShadowReport::default()));
+        let shadow = ShadowServer {
+            sender,
+            report: report.clone(),
+        };
+
+        shadow.mirror(Request::QuerySimTime, &Response::SimTime(0.0));
+        assert!(!report.lock().unwrap().desynced);
+        shadow.mirror(Request::QuerySimTime, &Response::SimTime(0.0));
+        assert!(report.lock().unwrap().desynced);
+
+        // Nothing is mirrored after the first skipped request.
+        receiver.recv().unwrap();
+        shadow.mirror(Request::QuerySimTime, &Response::SimTime(0.0));
+        assert!(receiver.try_recv().is_err());
+    }
+}
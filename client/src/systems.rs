@@ -1,12 +1,36 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::atomic::Ordering;
 use std::thread;
 
-use bevy::prelude::*;
+use bevy::{app::AppExit, ecs::entity::Entities, prelude::*};
 use bevy_rapier3d::prelude::*;
 
 use bevy_rapier3d::plugin::systems::RigidBodyWritebackComponents;
+use bevy_rapier3d::rapier::prelude::{
+    ColliderHandle, ImpulseJointHandle, RigidBodyHandle, ShapeType,
+};
 
-use crate::error::Result;
-use crate::plugin::{PhysicsClientWrapper, RequestQueue, RequestResult};
+use crate::catchup::{CatchupConfig, DroppedSimulationTime, SimulationTimeStats};
+use crate::client::ConnectionState;
+use crate::components::{ConvexHullPoints, SensorOverlapCount, ServerVelocity, UserMeta};
+use crate::console::PhysicsConsole;
+use crate::error::{ErrorKind, Result};
+use crate::history::PhysicsHistory;
+use crate::ids::{PhysicsEntityId, PhysicsIdMap};
+use crate::islands::PhysicsIslands;
+use crate::middleware::{RequestDropped, RequestMiddlewares, MAX_CREATES_PER_FRAME};
+use crate::plugin::{
+    CcdSpeedThreshold, ConnectionStateWrapper, DeltaResultEpsilon, ExtrapolateOmittedBodies,
+    MeshCache, PhysicsClientWrapper, PhysicsScale, PredictionDistance, RequestQueue, RequestResult,
+    RequestThread, Reregistering, ResultInterval, WrittenTransforms,
+};
+use crate::registration::{
+    self, BodyRegistration, ColliderRegistration, JointRegistration, RegistrationFailedPermanently,
+    RegistrationState,
+};
+use shared::scale::transform_to_iso;
+use shared::serializable::{SerializableRapierConfiguration, SerializableShape};
 use shared::*;
 
 pub type RigidBodyComponents<'a> = (
@@ -14,26 +38,507 @@ pub type RigidBodyComponents<'a> = (
     &'a RigidBody,
     Option<&'a GlobalTransform>,
     Option<&'a Velocity>,
+    Option<&'a Damping>,
+    Option<&'a GravityScale>,
+    Option<&'a LockedAxes>,
+    Option<&'a Ccd>,
     Option<&'a AdditionalMassProperties>,
+    Option<&'a UserMeta>,
 );
 
 pub type ColliderComponents<'a> = (
     Entity,
-    &'a Collider,
+    AnyOf<(&'a Collider, &'a ConvexHullPoints)>,
     Option<&'a Sensor>,
     Option<&'a ColliderMassProperties>,
     Option<&'a Friction>,
     Option<&'a Restitution>,
 );
 
-pub fn update_config(config: Res<RapierConfiguration>, mut request_queue: ResMut<RequestQueue>) {
-    if !config.is_changed() {
+pub type TransformHierarchy<'w, 's> = Query<'w, 's, (&'static Transform, Option<&'static Parent>)>;
+
+/// Computes the world transform of an entity by walking its `Transform` hierarchy.
+///
+/// `GlobalTransform` is only propagated in `PostUpdate`, so entities spawned earlier in
+/// the frame (e.g. by the scene spawner) still have a default one when the init systems
+/// run. Falls back to the `GlobalTransform` for entities without a `Transform`.
+fn world_transform(
+    entity: Entity,
+    global_transform: Option<&GlobalTransform>,
+    hierarchy: &TransformHierarchy,
+) -> Option<Transform> {
+    let Ok((transform, mut parent)) = hierarchy.get(entity) else {
+        return global_transform.map(|transform| transform.compute_transform());
+    };
+
+    let mut world = *transform;
+    while let Some((parent_transform, grandparent)) =
+        parent.and_then(|parent| hierarchy.get(parent.get()).ok())
+    {
+        world = parent_transform.mul_transform(world);
+        parent = grandparent;
+    }
+
+    Some(world)
+}
+
+/// Sends the config, and checks that the server took over the physics scale.
+pub fn update_config(
+    config: Res<RapierConfiguration>,
+    prediction_distance: Res<PredictionDistance>,
+    physics_scale: Res<PhysicsScale>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    if !config.is_changed() && !prediction_distance.is_changed() && !physics_scale.is_changed() {
         return;
     }
 
-    let req = Request::UpdateConfig(config.clone().into());
+    let mut config: SerializableRapierConfiguration = config.clone().into();
+    config.prediction_distance = prediction_distance.0;
+    config.physics_scale = Some(physics_scale.0);
+    let req = Request::UpdateConfig(config);
 
     request_queue.0.push(req);
+    request_queue.0.push(Request::QueryPhysicsScale);
+}
+
+pub fn update_result_interval(
+    interval: Res<ResultInterval>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    if !interval.is_changed() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetResultInterval(interval.0));
+}
+
+/// Turns CCD on for bodies faster than [`CcdSpeedThreshold`] and off again once
+/// they slowed down to half of it, so bodies near the threshold don't flicker.
+pub fn update_ccd_by_speed(
+    mut commands: Commands,
+    threshold: Res<CcdSpeedThreshold>,
+    mut bodies: Query<
+        (Entity, &Velocity, Option<&mut Ccd>),
+        (With<RapierRigidBodyHandle>, Changed<Velocity>),
+    >,
+) {
+    for (entity, velocity, ccd) in bodies.iter_mut() {
+        let speed = velocity.linvel.length();
+        match ccd {
+            Some(mut ccd) => {
+                if !ccd.enabled && speed > threshold.0 {
+                    ccd.enabled = true;
+                } else if ccd.enabled && speed < threshold.0 * 0.5 {
+                    ccd.enabled = false;
+                }
+            }
+            None if speed > threshold.0 => {
+                commands.entity(entity).insert(Ccd::enabled());
+            }
+            None => {}
+        }
+    }
+}
+
+/// Bodies are created with their `Ccd`, this only sends changes made afterwards.
+pub fn sync_ccd(
+    bodies: Query<(Entity, &Ccd), (With<RapierRigidBodyHandle>, Changed<Ccd>)>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let changes: Vec<_> = bodies
+        .iter()
+        .map(|(entity, ccd)| (ids.register(entity).0, ccd.enabled))
+        .collect();
+
+    if changes.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetCcd(changes));
+}
+
+/// Sends changed forces, and non-zero forces every step, mirroring how `ExternalForce`
+/// persists until it is changed.
+pub fn sync_external_forces(
+    bodies: Query<
+        (Entity, &ExternalForce, ChangeTrackers<ExternalForce>),
+        With<RapierRigidBodyHandle>,
+    >,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let forces: Vec<_> = bodies
+        .iter()
+        .filter(|(_, force, tracker)| {
+            tracker.is_changed() || force.force != Vect::ZERO || force.torque != Vect::ZERO
+        })
+        .map(|(entity, force, _)| (ids.register(entity).0, (*force).into()))
+        .collect();
+
+    if forces.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::ApplyForces(forces));
+}
+
+/// Sends damping changed after the body was created, e.g. from an inspector.
+pub fn sync_damping(
+    bodies: Query<(Entity, &Damping), (With<RapierRigidBodyHandle>, Changed<Damping>)>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let damping: Vec<_> = bodies
+        .iter()
+        .map(|(entity, damping)| (ids.register(entity).0, (*damping).into()))
+        .collect();
+
+    if damping.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetDamping(damping));
+}
+
+pub fn sync_gravity_scale(
+    bodies: Query<(Entity, &GravityScale), (With<RapierRigidBodyHandle>, Changed<GravityScale>)>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let gravity_scales: Vec<_> = bodies
+        .iter()
+        .map(|(entity, gravity_scale)| (ids.register(entity).0, gravity_scale.0))
+        .collect();
+
+    if gravity_scales.is_empty() {
+        return;
+    }
+
+    request_queue
+        .0
+        .push(Request::SetGravityScale(gravity_scales));
+}
+
+/// Sends locked axes changed after the body was created, e.g. unlocking rotation for a ragdoll.
+pub fn sync_locked_axes(
+    bodies: Query<(Entity, &LockedAxes), (With<RapierRigidBodyHandle>, Changed<LockedAxes>)>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let locked_axes: Vec<_> = bodies
+        .iter()
+        .map(|(entity, locked_axes)| (ids.register(entity).0, (*locked_axes).into()))
+        .collect();
+
+    if locked_axes.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetLockedAxes(locked_axes));
+}
+
+/// Teleports bodies whose `Transform` was changed by gameplay code.
+pub fn sync_body_positions(
+    bodies: Query<
+        (Entity, &Transform, Option<&GlobalTransform>),
+        (With<RapierRigidBodyHandle>, Changed<Transform>),
+    >,
+    hierarchy: TransformHierarchy,
+    written: Res<WrittenTransforms>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let positions: Vec<_> = bodies
+        .iter()
+        .filter(|(entity, transform, _)| {
+            written.0.get(entity) != Some(&(transform.translation, transform.rotation))
+        })
+        .filter_map(|(entity, _, global_transform)| {
+            let transform = world_transform(entity, global_transform, &hierarchy)?;
+            Some((ids.register(entity).0, transform_to_iso(&transform)))
+        })
+        .collect();
+
+    if positions.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetBodyPositions(positions));
+}
+
+/// Sends velocities set by gameplay code. Velocities the writeback just copied
+/// from the server are recognized by their [`ServerVelocity`] and not echoed back.
+///
+/// Bodies are created with their velocity as [`ServerVelocity`], a velocity set while
+/// waiting for the handle is sent once it arrives.
+pub fn sync_velocities(
+    bodies: Query<
+        (Entity, &Velocity, Option<&ServerVelocity>),
+        (
+            With<RapierRigidBodyHandle>,
+            Or<(Changed<Velocity>, Added<RapierRigidBodyHandle>)>,
+        ),
+    >,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let velocities: Vec<_> = bodies
+        .iter()
+        .filter(|(_, velocity, server)| server.map_or(true, |server| server.0 != **velocity))
+        .map(|(entity, velocity, _)| (ids.register(entity).0, (*velocity).into()))
+        .collect();
+
+    if velocities.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetVelocities(velocities));
+}
+
+/// Sends non-zero impulses and zeroes them, so each one is applied exactly once.
+pub fn sync_external_impulses(
+    mut bodies: Query<(Entity, &mut ExternalImpulse), With<RapierRigidBodyHandle>>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let mut impulses = vec![];
+    for (entity, mut impulse) in bodies.iter_mut() {
+        if impulse.impulse == Vect::ZERO && impulse.torque_impulse == Vect::ZERO {
+            continue;
+        }
+        impulses.push((ids.register(entity).0, (*impulse).into()));
+        *impulse = ExternalImpulse::default();
+    }
+
+    if impulses.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::ApplyImpulses(impulses));
+}
+
+/// Lets the server free the world right away instead of waiting for the connection to drop.
+pub fn say_goodbye(exit: EventReader<AppExit>, client: Res<PhysicsClientWrapper>) {
+    if exit.is_empty() {
+        return;
+    }
+
+    match client.0.lock().unwrap().goodbye() {
+        Ok(()) => info!("Disconnected from the server"),
+        Err(err) => error!("Failed to say goodbye to the server: {}", err),
+    }
+}
+
+pub fn sync_user_meta(
+    bodies: Query<(Entity, &UserMeta), (With<RapierRigidBodyHandle>, Changed<UserMeta>)>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let mut metas = vec![];
+    for (entity, meta) in bodies.iter() {
+        if meta.0.len() > MAX_USER_META_LEN {
+            error!(
+                "Metadata of {:?} is {} bytes, at most {} are allowed",
+                entity,
+                meta.0.len(),
+                MAX_USER_META_LEN
+            );
+            continue;
+        }
+        metas.push((ids.register(entity).0, Some(meta.0.clone())));
+    }
+
+    if metas.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::SetUserMeta(metas));
+}
+
+/// Mirrors the connection state of the request thread. After a reconnect that didn't
+/// resume the session, the server starts over with an empty world, so every body,
+/// collider and joint is registered again and the configuration is sent again.
+///
+/// State only set through requests (frozen bodies, cached meshes, ...) isn't restored.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_reconnect(
+    mut commands: Commands,
+    shared_state: Res<ConnectionStateWrapper>,
+    mut state: ResMut<ConnectionState>,
+    mut reregistering: ResMut<Reregistering>,
+    mut written: ResMut<WrittenTransforms>,
+    mut config: ResMut<RapierConfiguration>,
+    mut interval: ResMut<ResultInterval>,
+    bodies: Query<Entity, Or<(With<RapierRigidBodyHandle>, With<BodyRegistration>)>>,
+    colliders: Query<Entity, Or<(With<RapierColliderHandle>, With<ColliderRegistration>)>>,
+    joints: Query<Entity, Or<(With<RapierImpulseJointHandle>, With<JointRegistration>)>>,
+    mut session: Local<u32>,
+) {
+    reregistering.0.clear();
+
+    let current = *shared_state.0.lock().unwrap();
+    if *state != current {
+        *state = current;
+    }
+    match current {
+        ConnectionState::Connected { session: current } if current != *session => {
+            *session = current;
+        }
+        _ => return,
+    }
+
+    info!("Reconnected, registering all bodies, colliders and joints again");
+    for entity in &bodies {
+        commands
+            .entity(entity)
+            .remove::<(RapierRigidBodyHandle, BodyRegistration, ServerVelocity)>();
+        reregistering.0.insert(entity);
+    }
+    for entity in &colliders {
+        commands
+            .entity(entity)
+            .remove::<(RapierColliderHandle, ColliderRegistration)>();
+        reregistering.0.insert(entity);
+    }
+    for entity in &joints {
+        commands
+            .entity(entity)
+            .remove::<(RapierImpulseJointHandle, JointRegistration)>();
+    }
+    written.0.clear();
+    config.set_changed();
+    interval.set_changed();
+}
+
+/// Removes the bodies of despawned entities, or of entities that lost their handle.
+///
+/// Runs after `CoreStage::Update`, removals are only visible until the end of the frame.
+pub fn remove_bodies(
+    removed: RemovedComponents<RapierRigidBodyHandle>,
+    bodies: Query<(), With<RapierRigidBodyHandle>>,
+    reregistering: Res<Reregistering>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut written: ResMut<WrittenTransforms>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let mut removed_bodies = vec![];
+    for entity in removed.iter() {
+        // The handle may have been removed and inserted again in the same frame.
+        if bodies.contains(entity) || reregistering.0.contains(&entity) {
+            continue;
+        }
+        written.0.remove(&entity);
+        removed_bodies.push(ids.forget(entity).0);
+    }
+
+    if removed_bodies.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::RemoveBodies(removed_bodies));
+}
+
+/// Removes colliders whose component was removed or whose entity was despawned.
+///
+/// Colliders of despawned rigid bodies are left to [`remove_bodies`], the server
+/// removes them together with the body.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_colliders(
+    mut commands: Commands,
+    removed: RemovedComponents<RapierColliderHandle>,
+    removed_shapes: RemovedComponents<Collider>,
+    removed_bodies: RemovedComponents<RapierRigidBodyHandle>,
+    colliders: Query<(), With<RapierColliderHandle>>,
+    shapeless: Query<
+        Option<&RapierColliderHandle>,
+        (
+            Without<Collider>,
+            Without<ConvexHullPoints>,
+            Or<(With<RapierColliderHandle>, With<ColliderRegistration>)>,
+        ),
+    >,
+    reregistering: Res<Reregistering>,
+    entities: &Entities,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let mut removed_colliders = vec![];
+    for entity in removed.iter() {
+        if colliders.contains(entity) || reregistering.0.contains(&entity) {
+            continue;
+        }
+        let id = if entities.contains(entity) {
+            ids.register(entity)
+        } else {
+            if removed_bodies.iter().any(|body| body == entity) {
+                continue;
+            }
+            ids.forget(entity)
+        };
+        removed_colliders.push(id.0);
+    }
+
+    // The entity keeps its handle when only the `Collider` was removed.
+    for entity in removed_shapes.iter() {
+        let Ok(handle) = shapeless.get(entity) else {
+            continue;
+        };
+        commands
+            .entity(entity)
+            .remove::<(RapierColliderHandle, ColliderRegistration)>();
+        let id = ids.register(entity).0;
+        if handle.is_some() && !removed_colliders.contains(&id) {
+            removed_colliders.push(id);
+        }
+    }
+
+    if removed_colliders.is_empty() {
+        return;
+    }
+
+    request_queue
+        .0
+        .push(Request::RemoveColliders(removed_colliders));
+}
+
+/// Removes joints whose `ImpulseJoint` was removed. Joints whose `ImpulseJoint` changed
+/// are removed too and created again by [`init_joints`] with the new parent and data.
+///
+/// Joints of despawned entities go away on the server together with their body.
+pub fn remove_joints(
+    mut commands: Commands,
+    removed: RemovedComponents<ImpulseJoint>,
+    changed: Query<Entity, (Changed<ImpulseJoint>, With<RapierImpulseJointHandle>)>,
+    registered: Query<Option<&RapierImpulseJointHandle>, With<JointRegistration>>,
+    entities: &Entities,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let mut removed_joints = vec![];
+    for entity in removed
+        .iter()
+        .filter(|entity| entities.contains(*entity))
+        .chain(&changed)
+    {
+        let Ok(handle) = registered.get(entity) else {
+            continue;
+        };
+        commands
+            .entity(entity)
+            .remove::<(RapierImpulseJointHandle, JointRegistration)>();
+        let id = ids.register(entity).0;
+        if handle.is_some() && !removed_joints.contains(&id) {
+            removed_joints.push(id);
+        }
+    }
+
+    if removed_joints.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::RemoveJoints(removed_joints));
 }
 
 fn handle_update_config_response(resp: Result<Response>) {
@@ -47,23 +552,81 @@ fn handle_update_config_response(resp: Result<Response>) {
 }
 
 pub fn init_rigid_bodies(
-    context: Res<RapierContext>,
-    rigid_bodies: Query<RigidBodyComponents, Without<RapierRigidBodyHandle>>,
+    mut commands: Commands,
+    time: Res<Time>,
+    rigid_bodies: Query<
+        (RigidBodyComponents, Option<&BodyRegistration>),
+        Without<RapierRigidBodyHandle>,
+    >,
+    hierarchy: TransformHierarchy,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut failed: EventWriter<RegistrationFailedPermanently>,
     mut request_queue: ResMut<RequestQueue>,
 ) {
     let mut created_bodies = vec![];
 
-    let physics_scale = context.physics_scale();
+    let now = time.elapsed_seconds();
 
-    for (entity, rb, transform, velocity, additional_mass_properties) in rigid_bodies.iter() {
+    for (
+        (
+            entity,
+            rb,
+            transform,
+            velocity,
+            damping,
+            gravity_scale,
+            locked_axes,
+            ccd,
+            additional_mass_properties,
+            user_meta,
+        ),
+        registration,
+    ) in rigid_bodies.iter()
+    {
+        // Entities past the limit stay untouched until a later frame, instead of being
+        // marked pending and then cut from the request by `limit_creates`.
+        if created_bodies.len() >= MAX_CREATES_PER_FRAME {
+            break;
+        }
+
+        if registration.is_none() {
+            if let Some(user_meta) = user_meta.filter(|meta| meta.0.len() > MAX_USER_META_LEN) {
+                registration::fail::<BodyRegistration>(
+                    entity,
+                    ids.register(entity),
+                    format!(
+                        "metadata is {} bytes, at most {} are allowed",
+                        user_meta.0.len(),
+                        MAX_USER_META_LEN
+                    ),
+                    &mut commands,
+                    &mut failed,
+                );
+                continue;
+            }
+        }
+
+        let id = ids.register(entity);
+        if !registration::try_register(entity, id, registration, now, &mut commands, &mut failed) {
+            continue;
+        }
+
+        commands
+            .entity(entity)
+            .insert(ServerVelocity(velocity.copied().unwrap_or_default()));
         created_bodies.push(CreatedBody {
-            id: entity.to_bits(),
+            id: id.0,
             body: *rb,
-            transform: transform.map(|transform| {
-                shared::transform_to_iso(&transform.compute_transform(), physics_scale)
-            }),
+            transform: world_transform(entity, transform, &hierarchy)
+                .map(|transform| transform_to_iso(&transform)),
             additional_mass_properties: additional_mass_properties
                 .map(|mprops| mprops.clone().into()),
+            velocity: velocity.map(|velocity| (*velocity).into()),
+            damping: damping.map(|damping| (*damping).into()),
+            gravity_scale: gravity_scale.map(|gravity_scale| (*gravity_scale).into()),
+            locked_axes: locked_axes.map(|locked_axes| (*locked_axes).into()),
+            ccd: ccd.map(|ccd| (*ccd).into()),
+            user_meta: user_meta.map(|meta| meta.0.clone()),
         });
     }
 
@@ -74,36 +637,138 @@ pub fn init_rigid_bodies(
     request_queue.0.push(Request::CreateBodies(created_bodies));
 }
 
-fn handle_init_rigid_bodies_response(resp: Result<Response>, commands: &mut Commands) {
+fn handle_init_rigid_bodies_response(
+    resp: Result<Response>,
+    commands: &mut Commands,
+    ids: &PhysicsIdMap,
+) {
     if let Ok(Response::RigidBodyHandles(handles)) = resp {
-        for handle in handles {
-            commands
-                .entity(Entity::from_bits(handle.0))
-                .insert(RapierRigidBodyHandle(handle.1));
+        for (id, handle) in handles {
+            let id = PhysicsEntityId(id);
+            let Some(entity) = ids.entity(id) else {
+                warn!("Rigid body handle for unknown id {}", id);
+                continue;
+            };
+            if handle == RigidBodyHandle::invalid() {
+                warn!("Server failed to create rigid body {}", id);
+                registration::reject::<BodyRegistration>(
+                    entity,
+                    id,
+                    "server failed to create the rigid body".to_string(),
+                    commands,
+                );
+                continue;
+            }
+            commands.entity(entity).insert((
+                RapierRigidBodyHandle(handle),
+                BodyRegistration(RegistrationState::Registered),
+            ));
         }
+    } else if let Ok(Response::Error(err)) = resp {
+        error!("Failed to create rigid bodies: {}", err);
     }
 }
 
 pub fn init_colliders(
-    context: Res<RapierContext>,
-    colliders: Query<(ColliderComponents, Option<&GlobalTransform>), Without<RapierColliderHandle>>,
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<RapierConfiguration>,
+    colliders: Query<
+        (
+            ColliderComponents,
+            Option<&GlobalTransform>,
+            Option<&SensorOverlapCount>,
+            Option<&ColliderRegistration>,
+        ),
+        Without<RapierColliderHandle>,
+    >,
+    unregistered_bodies: Query<(), (With<RigidBody>, Without<RapierRigidBodyHandle>)>,
+    hierarchy: TransformHierarchy,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut failed: EventWriter<RegistrationFailedPermanently>,
+    mesh_cache: Res<MeshCache>,
     mut request_queue: ResMut<RequestQueue>,
 ) {
     let mut created_colliders = vec![];
+    // Colliders sent this frame, cached meshes are sent in their own requests.
+    let mut sent = 0;
+
+    let now = time.elapsed_seconds();
+
+    for (
+        (entity, shape, sensor, mprops, friction, restitution),
+        transform,
+        overlap_count,
+        registration,
+    ) in colliders.iter()
+    {
+        if sent >= MAX_CREATES_PER_FRAME {
+            break;
+        }
 
-    let physics_scale = context.physics_scale();
+        // A collider on a body that is still pending or waiting for a retry would be
+        // created without its parent, it waits until the body has a handle.
+        if unregistered_bodies.contains(entity) {
+            continue;
+        }
+
+        let id = ids.register(entity);
+        if !registration::try_register(entity, id, registration, now, &mut commands, &mut failed) {
+            continue;
+        }
+        sent += 1;
+
+        let transform = world_transform(entity, transform, &hierarchy);
+        let shape_scale = transform.map_or(Vec3::ONE, |transform| transform.scale);
+        let shape = match shape {
+            (Some(shape), _) => {
+                if !shared::is_scale_exact(shape, shape_scale) {
+                    warn!(
+                        "Collider {:?} has a non-uniform scale {} its shape can't represent, using a convex approximation",
+                        entity, shape_scale
+                    );
+                }
+                SerializableShape::Collider(shared::scale_collider(
+                    shape,
+                    shape_scale,
+                    config.scaled_shape_subdivision,
+                ))
+            }
+            (None, Some(hull)) => SerializableShape::ConvexHull(
+                hull.0.iter().map(|point| *point * shape_scale).collect(),
+            ),
+            (None, None) => unreachable!("AnyOf matches at least one component"),
+        };
+
+        let is_trimesh = matches!(
+            &shape,
+            SerializableShape::Collider(collider) if collider.raw.shape_type() == ShapeType::TriMesh
+        );
+        let plain = sensor.is_none()
+            && mprops.is_none()
+            && friction.is_none()
+            && restitution.is_none()
+            && overlap_count.is_none();
+        if mesh_cache.enabled && is_trimesh && plain {
+            let hash = shape.content_hash();
+            request_queue.0.push(Request::CreateColliderFromCachedMesh {
+                id: id.0,
+                hash,
+                transform: transform.as_ref().map(transform_to_iso),
+                fallback: mesh_cache.misses.contains(&hash).then_some(shape),
+            });
+            continue;
+        }
 
-    for ((entity, shape, sensor, mprops, friction, restitution), transform) in colliders.iter() {
         created_colliders.push(CreatedCollider {
-            id: entity.to_bits(),
-            shape: shape.clone(),
-            transform: transform.map(|transform| {
-                shared::transform_to_iso(&transform.compute_transform(), physics_scale)
-            }),
+            id: id.0,
+            shape,
+            transform: transform.as_ref().map(transform_to_iso),
             sensor: sensor.map(|sensor| sensor.clone().into()),
             mass_properties: mprops.map(|mprops| mprops.clone().into()),
             friction: friction.map(|friction| friction.clone().into()),
             restitution: restitution.map(|restitution| restitution.clone().into()),
+            report_overlaps: overlap_count.is_some(),
         });
     }
 
@@ -116,44 +781,230 @@ pub fn init_colliders(
         .push(Request::CreateColliders(created_colliders));
 }
 
-fn handle_init_colliders_response(resp: Result<Response>, commands: &mut Commands) {
+/// Creates joints once the bodies on both sides are registered. The joint goes away
+/// on the server together with either body.
+pub fn init_joints(
+    mut commands: Commands,
+    time: Res<Time>,
+    joints: Query<
+        (Entity, &ImpulseJoint, Option<&JointRegistration>),
+        Without<RapierImpulseJointHandle>,
+    >,
+    registered_bodies: Query<(), With<RapierRigidBodyHandle>>,
+    mut ids: ResMut<PhysicsIdMap>,
+    mut failed: EventWriter<RegistrationFailedPermanently>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let mut created_joints = vec![];
+    let now = time.elapsed_seconds();
+
+    for (entity, joint, registration) in joints.iter() {
+        if created_joints.len() >= MAX_CREATES_PER_FRAME {
+            break;
+        }
+
+        if !registered_bodies.contains(entity) || !registered_bodies.contains(joint.parent) {
+            continue;
+        }
+
+        let id = ids.register(entity);
+        if !registration::try_register(entity, id, registration, now, &mut commands, &mut failed) {
+            continue;
+        }
+
+        created_joints.push(CreatedJoint {
+            id: id.0,
+            parent: ids.register(joint.parent).0,
+            joint: joint.data.into(),
+        });
+    }
+
+    if created_joints.is_empty() {
+        return;
+    }
+
+    request_queue.0.push(Request::CreateJoints(created_joints));
+}
+
+fn handle_init_joints_response(
+    resp: Result<Response>,
+    commands: &mut Commands,
+    ids: &PhysicsIdMap,
+) {
+    if let Ok(Response::JointHandles(handles)) = resp {
+        for (id, handle) in handles {
+            let id = PhysicsEntityId(id);
+            let Some(entity) = ids.entity(id) else {
+                warn!("Joint handle for unknown id {}", id);
+                continue;
+            };
+            if handle == ImpulseJointHandle::invalid() {
+                warn!("Server failed to create joint {}", id);
+                registration::reject::<JointRegistration>(
+                    entity,
+                    id,
+                    "server failed to create the joint".to_string(),
+                    commands,
+                );
+                continue;
+            }
+            commands.entity(entity).insert((
+                RapierImpulseJointHandle(handle),
+                JointRegistration(RegistrationState::Registered),
+            ));
+        }
+    } else if let Ok(Response::Error(err)) = resp {
+        error!("Failed to create joints: {}", err);
+    }
+}
+
+fn handle_init_colliders_response(
+    resp: Result<Response>,
+    commands: &mut Commands,
+    ids: &PhysicsIdMap,
+) {
     if let Ok(Response::ColliderHandles(handles)) = resp {
-        for handle in handles {
-            commands
-                .entity(Entity::from_bits(handle.0))
-                .insert(RapierColliderHandle(handle.1));
+        for (id, handle) in handles {
+            let id = PhysicsEntityId(id);
+            let Some(entity) = ids.entity(id) else {
+                warn!("Collider handle for unknown id {}", id);
+                continue;
+            };
+            if handle == ColliderHandle::invalid() {
+                warn!("Server failed to create collider {}", id);
+                registration::reject::<ColliderRegistration>(
+                    entity,
+                    id,
+                    "server failed to create the collider".to_string(),
+                    commands,
+                );
+                continue;
+            }
+            commands.entity(entity).insert((
+                RapierColliderHandle(handle),
+                ColliderRegistration(RegistrationState::Registered),
+            ));
         }
+    } else if let Ok(Response::Error(err)) = resp {
+        error!("Failed to create colliders: {}", err);
     }
 }
 
-pub fn simulate_step(time: Res<Time>, mut request_queue: ResMut<RequestQueue>) {
-    request_queue
-        .0
-        .push(Request::SimulateStep(time.delta_seconds()));
+pub fn simulate_step(
+    time: Res<Time>,
+    catchup: Option<Res<CatchupConfig>>,
+    delta_epsilon: Option<Res<DeltaResultEpsilon>>,
+    mut stats: ResMut<SimulationTimeStats>,
+    mut dropped: EventWriter<DroppedSimulationTime>,
+    mut request_queue: ResMut<RequestQueue>,
+) {
+    let delta_time = match catchup {
+        Some(catchup) => catchup.apply(time.delta_seconds(), &mut stats, &mut dropped),
+        None => time.delta_seconds(),
+    };
+    let request = match delta_epsilon {
+        Some(epsilon) => Request::SimulateStepDelta {
+            delta_time,
+            epsilon: epsilon.0,
+        },
+        None => Request::SimulateStep(delta_time),
+    };
+    request_queue.0.push(request);
 }
 
 fn handle_simulate_step_response(
     resp: Result<Response>,
+    commands: &mut Commands,
     rigid_bodies: &mut Query<(RigidBodyWritebackComponents, &RapierRigidBodyHandle)>,
+    ids: &PhysicsIdMap,
+    extrapolation: Option<f32>,
+    written: &mut WrittenTransforms,
+    missing: &mut HashSet<Entity>,
+    mut history: Option<(&mut PhysicsHistory, f32)>,
 ) {
-    if let Ok(Response::SimulationResult(result)) = resp {
-        for ((entity, parent, transform, mut interpolation, mut velocity, mut sleeping), handle) in
-            rigid_bodies.iter_mut()
-        {
-            let (new_transform, new_velocity) = result.get(&handle.0).unwrap();
+    let (result, overlaps, partial) = match resp {
+        Ok(Response::SimulationResult(result, overlaps)) => (result, overlaps, false),
+        Ok(Response::SimulationDelta(result, overlaps)) => (result, overlaps, true),
+        _ => return,
+    };
+
+    for (id, count) in overlaps {
+        let entity = ids.entity(PhysicsEntityId(id));
+        if let Some(mut entity) = entity.and_then(|entity| commands.get_entity(entity)) {
+            entity.insert(SensorOverlapCount(count));
+        }
+    }
+
+    let index_of: HashMap<Entity, usize> = result
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (id, _, _))| Some((ids.entity(PhysicsEntityId(*id))?, index)))
+        .collect();
 
-            if let Some(mut transform) = transform {
+    for ((entity, parent, transform, mut interpolation, mut velocity, mut sleeping), _) in
+        rigid_bodies.iter_mut()
+    {
+        // The server may leave bodies out of the result, keep their last known state
+        // or move them along their last known velocity.
+        let Some(&index) = index_of.get(&entity) else {
+            // A delta leaves out bodies that didn't change.
+            if partial {
+                continue;
+            }
+            // Bodies stay missing for every step until the server knows them, warn once.
+            if missing.insert(entity) {
+                warn!("Body {:?} is missing from the simulation result", entity);
+            }
+            if let (Some(delta), Some(mut transform), Some(velocity)) =
+                (extrapolation, transform, velocity)
+            {
+                transform.translation += velocity.linvel * delta;
+                transform.rotation =
+                    Quat::from_scaled_axis(velocity.angvel * delta) * transform.rotation;
+                written
+                    .0
+                    .insert(entity, (transform.translation, transform.rotation));
+            }
+            continue;
+        };
+        missing.remove(&entity);
+        let (_, new_transform, new_velocity) = &result[index];
+
+        // Only states confirmed by the server go into the history, not extrapolated ones.
+        if let Some((history, now)) = &mut history {
+            history.record(entity, *now, *new_transform, Some(*new_velocity));
+        }
+
+        if let Some(mut transform) = transform {
+            // A transform changed since the last writeback is a teleport that hasn't
+            // reached the server yet, don't snap it back.
+            let last_written = written.0.get(&entity);
+            if !transform.is_changed()
+                || last_written == Some(&(transform.translation, transform.rotation))
+            {
                 transform.translation = new_transform.translation;
                 transform.rotation = new_transform.rotation;
+                written
+                    .0
+                    .insert(entity, (transform.translation, transform.rotation));
             }
+        }
 
-            if let Some(velocity) = &mut velocity {
+        if let Some(velocity) = &mut velocity {
+            // A velocity set since the last writeback hasn't reached the server yet,
+            // keep it so it can be sent.
+            if velocity.is_changed() {
+                commands
+                    .entity(entity)
+                    .insert(ServerVelocity(*new_velocity));
+            } else if **velocity != *new_velocity {
                 // NOTE: we write the new value only if there was an
                 //       actual change, in order to not trigger bevy’s
                 //       change tracking when the values didn’t change.
-                if **velocity != *new_velocity {
-                    **velocity = *new_velocity;
-                }
+                **velocity = *new_velocity;
+                commands
+                    .entity(entity)
+                    .insert(ServerVelocity(*new_velocity));
             }
         }
     }
@@ -161,67 +1012,138 @@ fn handle_simulate_step_response(
 
 pub fn process_requests(
     mut request_queue: ResMut<RequestQueue>,
+    middlewares: Res<RequestMiddlewares>,
+    mut dropped: EventWriter<RequestDropped>,
     client: Res<PhysicsClientWrapper>,
     result: Res<RequestResult>,
+    request_thread: Res<RequestThread>,
     rigid_bodies: Query<RigidBodyComponents>,
     mut frame_count: Local<u64>,
 ) {
+    // The queue is kept until the previous requests are done, so they stay in order.
+    if request_thread.busy.load(Ordering::Acquire) {
+        return;
+    }
+
+    middlewares.apply(&mut request_queue.0, &mut dropped);
+
+    let mut requests = mem::take(&mut *request_thread.unsent.lock().unwrap());
+    requests.append(&mut request_queue.0);
+
     let client = client.0.clone();
     let result = result.0.clone();
+    let busy = request_thread.busy.clone();
+    let unsent = request_thread.unsent.clone();
     let object_count = rigid_bodies.iter().count();
     *frame_count += 1;
     let frame_count = *frame_count;
 
-    #[cfg(feature = "bulk-requests")]
-    {
-        let req = Request::BulkRequest(request_queue.0.drain(..).collect());
+    busy.store(true, Ordering::Release);
 
-        thread::spawn(move || {
-            let span = tracing::debug_span!("process_requests", object_count, frame_count);
-            let _guard = span.enter();
-            let resp = client.lock().unwrap().send_request(req);
-            result.lock().unwrap().replace(resp);
-        });
-    }
+    #[cfg(feature = "bulk-requests")]
+    thread::spawn(move || {
+        let span = tracing::debug_span!("process_requests", object_count, frame_count);
+        let _guard = span.enter();
+        let mut client = client.lock().unwrap();
+        if !client.poll_reconnect() {
+            *unsent.lock().unwrap() = requests;
+        } else {
+            match client.send_request(Request::BulkRequest(requests)) {
+                Err(err) => match *err {
+                    ErrorKind::ConnectionLost {
+                        request: Request::BulkRequest(requests),
+                        ..
+                    } => *unsent.lock().unwrap() = requests,
+                    err => {
+                        result.lock().unwrap().replace(Err(err.into()));
+                    }
+                },
+                resp => {
+                    result.lock().unwrap().replace(resp);
+                }
+            }
+        }
+        busy.store(false, Ordering::Release);
+    });
     #[cfg(not(feature = "bulk-requests"))]
-    {
-        let request_queue = request_queue.0.drain(..).collect::<Vec<_>>();
-
-        thread::spawn(move || {
-            let span = tracing::debug_span!("process_requests", object_count, frame_count);
-            let _guard = span.enter();
-            let mut result = result.lock().unwrap();
-            for req in request_queue {
-                let resp = client.lock().unwrap().send_request(req);
-                result.push(resp);
+    thread::spawn(move || {
+        let span = tracing::debug_span!("process_requests", object_count, frame_count);
+        let _guard = span.enter();
+        let mut client = client.lock().unwrap();
+        let mut requests = requests.into_iter();
+        let mut lost = None;
+        while client.poll_reconnect() {
+            let Some(req) = requests.next() else {
+                break;
+            };
+            match client.send_request(req) {
+                Err(err) => match *err {
+                    ErrorKind::ConnectionLost { request, .. } => {
+                        lost = Some(request);
+                        break;
+                    }
+                    err => result.lock().unwrap().push(Err(err.into())),
+                },
+                resp => result.lock().unwrap().push(resp),
             }
-        });
-    }
+        }
+        let mut unsent = unsent.lock().unwrap();
+        unsent.extend(lost);
+        unsent.extend(requests);
+        busy.store(false, Ordering::Release);
+    });
 }
 
+/// Applies the responses of the request thread. Frames in which it hasn't answered yet
+/// are skipped.
+#[allow(clippy::too_many_arguments)]
 pub fn writeback(
     mut commands: Commands,
     mut rigid_bodies: Query<(RigidBodyWritebackComponents, &RapierRigidBodyHandle)>,
     result: Res<RequestResult>,
-    mut init: Local<bool>,
+    ids: Res<PhysicsIdMap>,
+    mut physics_islands: Option<ResMut<PhysicsIslands>>,
+    time: Res<Time>,
+    extrapolate: Res<ExtrapolateOmittedBodies>,
+    physics_scale: Res<PhysicsScale>,
+    mut mesh_cache: ResMut<MeshCache>,
+    mut written: ResMut<WrittenTransforms>,
+    mut console: ResMut<PhysicsConsole>,
+    mut missing: Local<HashSet<Entity>>,
+    mut history: Option<ResMut<PhysicsHistory>>,
 ) {
-    if !*init {
-        *init = true;
-        return;
-    }
+    let extrapolation = extrapolate.0.then(|| time.delta_seconds());
+    let now = time.elapsed_seconds();
 
     #[cfg(feature = "bulk-requests")]
     {
-        while result.0.lock().unwrap().is_none() {}
-        let resp = result.0.lock().unwrap().take().unwrap();
-        if let Err(err) = resp {
-            error!("Failed to send request: {}", err);
+        let Some(resp) = result.0.lock().unwrap().take() else {
             return;
-        }
+        };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(err) => {
+                error!("Failed to send request: {}", err);
+                return;
+            }
+        };
 
-        if let Response::BulkResponse(responses) = resp.unwrap() {
+        if let Response::BulkResponse(responses) = resp {
             for resp in responses {
-                handle_response(resp, &mut commands, &mut rigid_bodies);
+                handle_response(
+                    resp,
+                    &mut commands,
+                    &mut rigid_bodies,
+                    &ids,
+                    physics_islands.as_deref_mut(),
+                    extrapolation,
+                    physics_scale.0,
+                    &mut mesh_cache,
+                    &mut written,
+                    &mut console,
+                    &mut missing,
+                    history.as_deref_mut().map(|history| (history, now)),
+                );
             }
         } else {
             error!("Unexpected response");
@@ -229,11 +1151,24 @@ pub fn writeback(
     }
     #[cfg(not(feature = "bulk-requests"))]
     {
-        while result.0.lock().unwrap().is_empty() {}
-        while let Some(resp) = result.0.lock().unwrap().pop() {
+        let responses = mem::take(&mut *result.0.lock().unwrap());
+        for resp in responses {
             match resp {
                 Ok(resp) => {
-                    handle_response(resp, &mut commands, &mut rigid_bodies);
+                    handle_response(
+                        resp,
+                        &mut commands,
+                        &mut rigid_bodies,
+                        &ids,
+                        physics_islands.as_deref_mut(),
+                        extrapolation,
+                        physics_scale.0,
+                        &mut mesh_cache,
+                        &mut written,
+                        &mut console,
+                        &mut missing,
+                        history.as_deref_mut().map(|history| (history, now)),
+                    );
                 }
                 Err(err) => {
                     error!("Failed to send request: {}", err);
@@ -244,26 +1179,381 @@ pub fn writeback(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_response(
     resp: Response,
     mut commands: &mut Commands,
     mut rigid_bodies: &mut Query<(RigidBodyWritebackComponents, &RapierRigidBodyHandle)>,
+    ids: &PhysicsIdMap,
+    physics_islands: Option<&mut PhysicsIslands>,
+    extrapolation: Option<f32>,
+    physics_scale: Real,
+    mesh_cache: &mut MeshCache,
+    written: &mut WrittenTransforms,
+    console: &mut PhysicsConsole,
+    missing: &mut HashSet<Entity>,
+    history: Option<(&mut PhysicsHistory, f32)>,
 ) {
     match resp {
         Response::ConfigUpdated => {
             handle_update_config_response(Ok(resp));
         }
         Response::RigidBodyHandles(_) => {
-            handle_init_rigid_bodies_response(Ok(resp), &mut commands);
+            handle_init_rigid_bodies_response(Ok(resp), &mut commands, ids);
         }
         Response::ColliderHandles(_) => {
-            handle_init_colliders_response(Ok(resp), &mut commands);
+            handle_init_colliders_response(Ok(resp), &mut commands, ids);
+        }
+        Response::JointHandles(_) => {
+            handle_init_joints_response(Ok(resp), &mut commands, ids);
+        }
+        Response::SimulationResult(_, _) | Response::SimulationDelta(_, _) => {
+            handle_simulate_step_response(
+                Ok(resp),
+                &mut commands,
+                &mut rigid_bodies,
+                ids,
+                extrapolation,
+                written,
+                missing,
+                history,
+            );
+        }
+        Response::BodiesWoken => {
+            debug!("Bodies woken");
+        }
+        Response::Toi(toi) => {
+            debug!("Time of impact: {:?}", toi);
+        }
+        Response::ForcesCleared => {
+            debug!("Forces cleared");
+        }
+        Response::Grounded(grounded) => {
+            debug!("Grounded: {:?}", grounded);
+        }
+        Response::CollidersDetached(ids) => {
+            debug!("Colliders detached: {:?}", ids);
+        }
+        Response::SimTime(time) => {
+            debug!("Simulation time: {}", time);
+        }
+        Response::Islands(islands) => {
+            if let Some(physics_islands) = physics_islands {
+                physics_islands.0 = islands.into_iter().map(Into::into).collect();
+            }
+        }
+        Response::PointInSolid(id) => {
+            debug!("Point in solid: {:?}", id);
+        }
+        Response::ResultIntervalSet => {
+            info!("Result interval set");
+        }
+        Response::Stepped => {}
+        Response::CcdSet => {
+            debug!("CCD set");
+        }
+        Response::ForcesApplied => {
+            debug!("Forces applied");
+        }
+        Response::ImpulsesApplied => {
+            debug!("Impulses applied");
+        }
+        Response::VelocitiesSet => {
+            debug!("Velocities set");
+        }
+        Response::DampingSet => {
+            debug!("Damping set");
+        }
+        Response::GravityScaleSet => {
+            debug!("Gravity scale set");
+        }
+        Response::LockedAxesSet => {
+            debug!("Locked axes set");
+        }
+        Response::Diagnostics(diagnostics) => {
+            info!("Diagnostics: {:?}", diagnostics);
+            console.print(format!(
+                "{} bodies, {} colliders, {} broad-phase pairs, {} contact manifolds",
+                diagnostics.bodies,
+                diagnostics.colliders,
+                diagnostics.broad_phase_pairs,
+                diagnostics.contact_manifolds
+            ));
+        }
+        Response::RayHit(hit) => {
+            debug!("Ray hit: {:?}", hit);
+        }
+        Response::BodyPositionsSet => {
+            debug!("Body positions set");
+        }
+        Response::BodiesFrozen(ids) => {
+            debug!("Frozen state changed: {:?}", ids);
+        }
+        Response::UserMetaSet => {
+            debug!("User metadata set");
+        }
+        Response::UserMeta(metas) => {
+            debug!("User metadata: {:?}", metas);
+        }
+        Response::RadialImpulseApplied(ids) => {
+            debug!("Radial impulse applied to {:?}", ids);
+        }
+        Response::BodiesRemoved(ids) => {
+            debug!("Bodies removed: {:?}", ids);
+        }
+        Response::CollidersRemoved(ids) => {
+            debug!("Colliders removed: {:?}", ids);
+        }
+        Response::JointsRemoved(ids) => {
+            debug!("Joints removed: {:?}", ids);
         }
-        Response::SimulationResult(_) => {
-            handle_simulate_step_response(Ok(resp), &mut rigid_bodies);
+        Response::MeshCacheMiss { id, hash } => {
+            debug!("Mesh {} isn't cached on the server", hash);
+            mesh_cache.misses.insert(hash);
+            let id = PhysicsEntityId(id);
+            if let Some(entity) = ids.entity(id) {
+                registration::reject::<ColliderRegistration>(
+                    entity,
+                    id,
+                    "mesh isn't cached on the server".to_string(),
+                    commands,
+                );
+            }
+        }
+        Response::PhysicsScale(server_scale) => {
+            // Every position and velocity would be off by the ratio, don't carry on.
+            assert!(
+                server_scale == physics_scale,
+                "The server uses a physics scale of {}, but the client uses {}",
+                server_scale,
+                physics_scale
+            );
+            info!("Physics scale {} matches the server", server_scale);
+        }
+        Response::Error(err) => {
+            error!("Server error: {}", err);
         }
         _ => {
             error!("Unexpected response");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use bevy::ecs::entity::EntityMap;
+    use bevy::ecs::event::Events;
+    use bevy::ecs::system::System;
+    use url::Url;
+
+    use super::*;
+    use crate::client::PhysicsClient;
+    use crate::transport::Transport;
+
+    #[test]
+    fn scene_bodies_register_at_their_hierarchy_pose() {
+        let registry = AppTypeRegistry::default();
+        {
+            let mut registry = registry.write();
+            registry.register::<Transform>();
+            registry.register::<GlobalTransform>();
+            registry.register::<Parent>();
+            registry.register::<Children>();
+            registry.register::<RigidBody>();
+        }
+
+        let mut saved = World::new();
+        saved
+            .spawn(TransformBundle::from_transform(Transform::from_xyz(
+                10.0, 0.0, 0.0,
+            )))
+            .with_children(|parent| {
+                parent.spawn((
+                    RigidBody::Dynamic,
+                    TransformBundle::from_transform(Transform::from_xyz(0.0, 2.0, 0.0)),
+                ));
+            });
+        let scene = DynamicScene::from_world(&saved, &registry);
+
+        // Loading the scene leaves `GlobalTransform` unpropagated until `PostUpdate`.
+        let mut world = World::new();
+        world.insert_resource(registry);
+        scene
+            .write_to_world(&mut world, &mut EntityMap::default())
+            .unwrap();
+        world.init_resource::<Time>();
+        world.init_resource::<PhysicsIdMap>();
+        world.init_resource::<Events<RegistrationFailedPermanently>>();
+        world.init_resource::<RequestQueue>();
+
+        let mut system = IntoSystem::into_system(init_rigid_bodies);
+        system.initialize(&mut world);
+        system.run((), &mut world);
+
+        let requests = &world.resource::<RequestQueue>().0;
+        let [Request::CreateBodies(bodies)] = requests.as_slice() else {
+            panic!("unexpected requests {:?}", requests);
+        };
+        assert_eq!(bodies.len(), 1);
+        let translation = bodies[0].transform.unwrap().translation.vector;
+        assert_eq!(Vect::from(translation), Vect::new(10.0, 2.0, 0.0));
+    }
+
+    /// Resources `init_rigid_bodies` and `sync_velocities` need.
+    fn body_sync_world() -> World {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.init_resource::<PhysicsIdMap>();
+        world.init_resource::<Events<RegistrationFailedPermanently>>();
+        world.init_resource::<WrittenTransforms>();
+        world.init_resource::<RequestQueue>();
+        world
+    }
+
+    #[test]
+    fn velocity_set_before_the_handle_arrives_is_sent() {
+        let mut world = body_sync_world();
+        let launched = world
+            .spawn((
+                RigidBody::Dynamic,
+                Velocity::linear(Vect::X),
+                TransformBundle::default(),
+            ))
+            .id();
+        let resting = world
+            .spawn((
+                RigidBody::Dynamic,
+                Velocity::linear(Vect::X),
+                TransformBundle::default(),
+            ))
+            .id();
+
+        let mut init = IntoSystem::into_system(init_rigid_bodies);
+        init.initialize(&mut world);
+        init.run((), &mut world);
+        init.apply_buffers(&mut world);
+        let requests = mem::take(&mut world.resource_mut::<RequestQueue>().0);
+        assert!(
+            matches!(requests.as_slice(), [Request::CreateBodies(bodies)] if bodies.len() == 2)
+        );
+
+        let mut sync = IntoSystem::into_system(sync_velocities);
+        sync.initialize(&mut world);
+        sync.run((), &mut world);
+
+        // A frame later, the velocity changes while the handles are still on their way.
+        world.get_mut::<Velocity>(launched).unwrap().linvel = Vect::new(20.0, 5.0, 0.0);
+        sync.run((), &mut world);
+        assert!(world.resource::<RequestQueue>().0.is_empty());
+
+        for entity in [launched, resting] {
+            world
+                .entity_mut(entity)
+                .insert(RapierRigidBodyHandle(RigidBodyHandle::invalid()));
+        }
+        sync.run((), &mut world);
+
+        let id = world.resource_mut::<PhysicsIdMap>().register(launched);
+        let requests = &world.resource::<RequestQueue>().0;
+        let [Request::SetVelocities(velocities)] = requests.as_slice() else {
+            panic!("unexpected requests {:?}", requests);
+        };
+        assert_eq!(velocities.len(), 1);
+        assert_eq!(velocities[0].0, id.0);
+        assert_eq!(velocities[0].1.linvel, Vect::new(20.0, 5.0, 0.0));
+    }
+
+    /// A connection that dropped, sending fails like a reset socket.
+    struct DroppedTransport;
+
+    impl Transport for DroppedTransport {
+        fn send(&mut self, _bytes: Vec<u8>) -> Result<Vec<u8>> {
+            Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+        }
+
+        fn close(&mut self, _goodbye: Vec<u8>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn requests_lost_with_the_connection_are_sent_again() {
+        let url = Url::parse("ws://127.0.0.1:1/socket").unwrap();
+        let client = PhysicsClient::with_transport(DroppedTransport).with_endpoint(url);
+        let mut world = World::new();
+        world.insert_resource(PhysicsClientWrapper(Arc::new(Mutex::new(client))));
+        world.init_resource::<RequestMiddlewares>();
+        world.init_resource::<Events<RequestDropped>>();
+        world.init_resource::<RequestResult>();
+        world.init_resource::<RequestThread>();
+        world.insert_resource(RequestQueue(vec![
+            Request::WakeBodies(vec![1]),
+            Request::SimulateStep(1.0 / 60.0),
+        ]));
+
+        let mut system = IntoSystem::into_system(process_requests);
+        system.initialize(&mut world);
+        system.run((), &mut world);
+        let request_thread = world.resource::<RequestThread>();
+        while request_thread.busy.load(Ordering::Acquire) {
+            thread::yield_now();
+        }
+
+        // Both requests wait for the reconnect, in their order, and nothing was answered.
+        let unsent = request_thread.unsent.lock().unwrap();
+        let names: Vec<_> = unsent.iter().map(Request::name).collect();
+        assert_eq!(names, ["WakeBodies", "SimulateStep"]);
+        #[cfg(feature = "bulk-requests")]
+        assert!(world
+            .resource::<RequestResult>()
+            .0
+            .lock()
+            .unwrap()
+            .is_none());
+        #[cfg(not(feature = "bulk-requests"))]
+        assert!(world
+            .resource::<RequestResult>()
+            .0
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn ccd_enabled_by_speed_is_sent_before_the_step() {
+        let mut world = World::new();
+        world.insert_resource(CcdSpeedThreshold(10.0));
+        world.init_resource::<PhysicsIdMap>();
+        world.init_resource::<RequestQueue>();
+        let fast = world
+            .spawn((
+                RapierRigidBodyHandle(RigidBodyHandle::invalid()),
+                Velocity::linear(Vect::new(50.0, 0.0, 0.0)),
+            ))
+            .id();
+
+        // The stages of the plugin, the step is queued after `sync_ccd`.
+        let mut schedule = Schedule::default();
+        schedule
+            .add_stage(
+                "writeback",
+                SystemStage::parallel().with_system(update_ccd_by_speed),
+            )
+            .add_stage(
+                "sync_backend",
+                SystemStage::parallel().with_system(sync_ccd),
+            );
+        schedule.run(&mut world);
+
+        assert!(world.get::<Ccd>(fast).unwrap().enabled);
+        let id = world.resource_mut::<PhysicsIdMap>().register(fast);
+        let requests = &world.resource::<RequestQueue>().0;
+        let [Request::SetCcd(changes)] = requests.as_slice() else {
+            panic!("unexpected requests {:?}", requests);
+        };
+        assert_eq!(changes.as_slice(), [(id.0, true)]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use socket2::SockRef;
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+use crate::error::Result;
+
+/// Carries encoded requests to a physics backend and brings back the encoded responses.
+///
+/// Payloads are already serialized, compressed and checksummed by `PhysicsClient`.
+pub trait Transport: Send {
+    /// Sends one request and blocks until its response arrives.
+    fn send(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Sends the final `Goodbye` request and waits until the backend shut the connection down.
+    fn close(&mut self, goodbye: Vec<u8>) -> Result<()>;
+}
+
+/// Options applied to the TCP socket once the connection is established.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Disables Nagle's algorithm, so small requests go out immediately.
+    pub nodelay: bool,
+    /// `None` keeps the OS defaults.
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+/// How long `close` waits for the server to acknowledge the goodbye.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct WebSocketTransport(pub WebSocket<MaybeTlsStream<TcpStream>>);
+
+impl WebSocketTransport {
+    /// The TCP stream, or an error for a TLS stream, whose socket isn't reachable.
+    fn stream(&self) -> Result<&TcpStream> {
+        match self.0.get_ref() {
+            MaybeTlsStream::Plain(stream) => Ok(stream),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "socket options are only supported on plain TCP connections",
+            )
+            .into()),
+        }
+    }
+
+    pub fn configure(&self, options: SocketOptions) -> Result<()> {
+        let stream = self.stream()?;
+
+        stream.set_nodelay(options.nodelay)?;
+
+        let socket = SockRef::from(stream);
+        if let Some(size) = options.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = options.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        println!(
+            "Socket options: TCP_NODELAY: {}, send buffer: {} bytes, receive buffer: {} bytes",
+            stream.nodelay()?,
+            socket.send_buffer_size()?,
+            socket.recv_buffer_size()?
+        );
+        Ok(())
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        self.0.write_message(Message::Binary(bytes))?;
+        Ok(self.0.read_message()?.into_data())
+    }
+
+    fn close(&mut self, goodbye: Vec<u8>) -> Result<()> {
+        self.0.write_message(Message::Binary(goodbye))?;
+
+        // Runs when the client is dropped, a server that stopped answering can't hang the exit.
+        self.stream()?.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+
+        // The server answers with a close frame, which tungstenite acknowledges.
+        loop {
+            match self.0.read_message() {
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
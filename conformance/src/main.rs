@@ -0,0 +1,463 @@
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use bevy_rapier3d::prelude::*;
+use bevy_rapier3d::rapier::prelude::{ColliderHandle, Isometry, RigidBodyHandle};
+
+use bincode::{deserialize, serialize};
+use clap::{arg, command};
+#[cfg(feature = "compression")]
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use tungstenite::{connect, stream::MaybeTlsStream, Message, WebSocket};
+
+use shared::serializable::{SerializableGravityScale, SerializableShape, SerializableVelocity};
+use shared::*;
+
+const GROUND: u64 = 1;
+const BALL: u64 = 2;
+const DEGENERATE_HULL: u64 = 3;
+const OVERSIZED_META: u64 = 4;
+const FROZEN: u64 = 5;
+const FALLING: u64 = 6;
+const WALL: u64 = 7;
+const BULLET: u64 = 8;
+
+const STEP: f32 = 1.0 / 60.0;
+const STEPS: usize = 60;
+
+type CheckResult = Result<(), String>;
+
+/// Black-box protocol checks against a running server.
+///
+/// Checks run in order on a single connection and later checks rely on the
+/// scene the earlier ones built. Build with the same `compression` and
+/// `checksum` features as the server under test.
+struct Conformance {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    ball: RigidBodyHandle,
+}
+
+impl Conformance {
+    fn send(&mut self, request: Request) -> Result<Response, String> {
+        let payload = serialize(&request).map_err(|err| err.to_string())?;
+
+        #[cfg(feature = "compression")]
+        let payload = {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&payload).map_err(|err| err.to_string())?;
+            encoder.finish().map_err(|err| err.to_string())?
+        };
+
+        #[cfg(feature = "checksum")]
+        let payload = shared::checksum::append(payload);
+
+        self.socket
+            .write_message(Message::Binary(payload))
+            .map_err(|err| err.to_string())?;
+        let data = self
+            .socket
+            .read_message()
+            .map_err(|err| err.to_string())?
+            .into_data();
+
+        #[cfg(feature = "checksum")]
+        let data = shared::checksum::verify(data).ok_or("checksum mismatch")?;
+
+        #[cfg(feature = "compression")]
+        let data = {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(data.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(|err| err.to_string())?;
+            decompressed
+        };
+
+        deserialize(&data).map_err(|err| err.to_string())
+    }
+
+    fn update_config(&mut self) -> CheckResult {
+        match self.send(Request::UpdateConfig(RapierConfiguration::default().into()))? {
+            Response::ConfigUpdated => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn create_bodies(&mut self) -> CheckResult {
+        let bodies = vec![
+            body(GROUND, RigidBody::Fixed, 0.0),
+            body(BALL, RigidBody::Dynamic, 5.0),
+        ];
+        match self.send(Request::CreateBodies(bodies))? {
+            Response::RigidBodyHandles(handles) => {
+                let ids: Vec<_> = handles.iter().map(|(id, _)| *id).collect();
+                if ids != [GROUND, BALL] {
+                    return Err(format!("handles not in request order: {:?}", ids));
+                }
+                if handles
+                    .iter()
+                    .any(|(_, handle)| *handle == RigidBodyHandle::invalid())
+                {
+                    return Err("server returned an invalid handle".to_string());
+                }
+                self.ball = handles[1].1;
+                Ok(())
+            }
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn create_colliders(&mut self) -> CheckResult {
+        let colliders = vec![
+            collider(
+                GROUND,
+                SerializableShape::Collider(Collider::cuboid(10.0, 0.5, 10.0)),
+            ),
+            collider(BALL, SerializableShape::Collider(Collider::ball(0.5))),
+        ];
+        match self.send(Request::CreateColliders(colliders))? {
+            Response::ColliderHandles(handles) if handles.len() == 2 => {
+                if handles
+                    .iter()
+                    .any(|(_, handle)| *handle == ColliderHandle::invalid())
+                {
+                    return Err("server returned an invalid handle".to_string());
+                }
+                Ok(())
+            }
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn duplicate_body(&mut self) -> CheckResult {
+        // Sending the same create again returns the existing body.
+        match self.send(Request::CreateBodies(vec![body(
+            BALL,
+            RigidBody::Dynamic,
+            5.0,
+        )]))? {
+            Response::RigidBodyHandles(handles) if handles == [(BALL, self.ball)] => {}
+            other => return Err(unexpected(&other)),
+        }
+        // A different body under the same id only fails its own entry.
+        let bodies = vec![
+            body(BALL, RigidBody::Dynamic, 6.0),
+            body(GROUND, RigidBody::Fixed, 0.0),
+        ];
+        match self.send(Request::CreateBodies(bodies))? {
+            Response::RigidBodyHandles(handles)
+                if handles.len() == 2
+                    && handles[0] == (BALL, RigidBodyHandle::invalid())
+                    && handles[1].0 == GROUND
+                    && handles[1].1 != RigidBodyHandle::invalid() =>
+            {
+                Ok(())
+            }
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn oversized_user_meta(&mut self) -> CheckResult {
+        let mut body = body(OVERSIZED_META, RigidBody::Fixed, 0.0);
+        body.user_meta = Some(vec![0; MAX_USER_META_LEN + 1]);
+        match self.send(Request::CreateBodies(vec![body]))? {
+            Response::Error(_) => {}
+            other => return Err(unexpected(&other)),
+        }
+        match self.send(Request::SetUserMeta(vec![(
+            BALL,
+            Some(vec![0; MAX_USER_META_LEN + 1]),
+        )]))? {
+            Response::Error(_) => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn degenerate_hull(&mut self) -> CheckResult {
+        // Collinear points don't span a volume.
+        let points = vec![Vect::ZERO, Vect::X, Vect::X * 2.0];
+        let colliders = vec![collider(
+            DEGENERATE_HULL,
+            SerializableShape::ConvexHull(points),
+        )];
+        // Only the hull fails, with an invalid handle in its place.
+        match self.send(Request::CreateColliders(colliders))? {
+            Response::ColliderHandles(handles)
+                if handles == [(DEGENERATE_HULL, ColliderHandle::invalid())] =>
+            {
+                Ok(())
+            }
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn simulate(&mut self) -> CheckResult {
+        let mut last = None;
+        for _ in 0..STEPS {
+            last = Some(self.send(Request::SimulateStep(STEP))?);
+        }
+
+        match last {
+            Some(Response::SimulationResult(bodies, _)) => {
+                let ids: Vec<_> = bodies.iter().map(|(id, _, _)| *id).collect();
+                if ids != [GROUND, BALL] {
+                    return Err(format!("unexpected bodies in the result: {:?}", ids));
+                }
+                let ball = bodies[1].1.translation.y;
+                if !(0.9..5.0).contains(&ball) {
+                    return Err(format!(
+                        "ball should have fallen onto the ground, y = {}",
+                        ball
+                    ));
+                }
+                Ok(())
+            }
+            Some(other) => Err(unexpected(&other)),
+            None => unreachable!(),
+        }
+    }
+
+    fn sim_time(&mut self) -> CheckResult {
+        match self.send(Request::QuerySimTime)? {
+            Response::SimTime(time) if (time - STEP * STEPS as f32).abs() < 1e-3 => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn point_in_solid(&mut self) -> CheckResult {
+        match self.send(Request::PointInSolid(Vect::ZERO))? {
+            Response::PointInSolid(Some((GROUND, _))) => {}
+            other => return Err(unexpected(&other)),
+        }
+        match self.send(Request::PointInSolid(Vect::new(5.0, 10.0, 5.0)))? {
+            Response::PointInSolid(None) => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn bulk_request(&mut self) -> CheckResult {
+        let requests = vec![Request::QuerySimTime, Request::WakeBodies(vec![BALL])];
+        match self.send(Request::BulkRequest(requests))? {
+            Response::BulkResponse(responses) => match responses.as_slice() {
+                [Response::SimTime(_), Response::BodiesWoken] => Ok(()),
+                _ => Err(format!("unexpected responses: {:?}", responses)),
+            },
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn frozen_bodies(&mut self) -> CheckResult {
+        const HEIGHT: f32 = 8.0;
+
+        let mut falling = body(FALLING, RigidBody::Dynamic, HEIGHT);
+        falling.transform = Some(Isometry::translation(3.0, HEIGHT, 0.0));
+        let bodies = vec![body(FROZEN, RigidBody::Dynamic, HEIGHT), falling];
+        let colliders = [FROZEN, FALLING]
+            .map(|id| collider(id, SerializableShape::Collider(Collider::ball(0.5))))
+            .to_vec();
+        self.send(Request::CreateBodies(bodies))?;
+        self.send(Request::CreateColliders(colliders))?;
+
+        match self.send(Request::SetBodiesFrozen(vec![(FROZEN, true)]))? {
+            Response::BodiesFrozen(ids) if ids == [FROZEN] => {}
+            other => return Err(unexpected(&other)),
+        }
+        let (frozen, falling) = self.heights_after(10, FROZEN, FALLING)?;
+        if frozen != HEIGHT || falling >= HEIGHT {
+            return Err(format!(
+                "only the unfrozen body should move, y = {} and {}",
+                frozen, falling
+            ));
+        }
+
+        match self.send(Request::SetBodiesFrozen(vec![(FROZEN, false)]))? {
+            Response::BodiesFrozen(ids) if ids == [FROZEN] => {}
+            other => return Err(unexpected(&other)),
+        }
+        let (unfrozen, _) = self.heights_after(10, FROZEN, FALLING)?;
+        if unfrozen >= HEIGHT {
+            return Err(format!("unfrozen body should fall, y = {}", unfrozen));
+        }
+        Ok(())
+    }
+
+    /// A fast ball with CCD turned on through `SetCcd` must not pass through a thin wall.
+    fn ccd_tunneling(&mut self) -> CheckResult {
+        // Far away from the rest of the scene, without gravity.
+        const HEIGHT: f32 = 50.0;
+        const WALL_X: f32 = 10.0;
+
+        let mut wall = body(WALL, RigidBody::Fixed, HEIGHT);
+        wall.transform = Some(Isometry::translation(WALL_X, HEIGHT, 100.0));
+        let mut bullet = body(BULLET, RigidBody::Dynamic, HEIGHT);
+        bullet.transform = Some(Isometry::translation(0.0, HEIGHT, 100.0));
+        // About 8 units per step, the wall is skipped over without CCD.
+        bullet.velocity = Some(SerializableVelocity {
+            linvel: Vect::new(500.0, 0.0, 0.0),
+            angvel: Vect::ZERO,
+        });
+        bullet.gravity_scale = Some(SerializableGravityScale(0.0));
+        self.send(Request::CreateBodies(vec![wall, bullet]))?;
+        self.send(Request::CreateColliders(vec![
+            collider(
+                WALL,
+                SerializableShape::Collider(Collider::cuboid(0.05, 5.0, 5.0)),
+            ),
+            collider(BULLET, SerializableShape::Collider(Collider::ball(0.25))),
+        ]))?;
+
+        match self.send(Request::SetCcd(vec![(BULLET, true)]))? {
+            Response::CcdSet => {}
+            other => return Err(unexpected(&other)),
+        }
+
+        let mut last = None;
+        for _ in 0..5 {
+            last = Some(self.send(Request::SimulateStep(STEP))?);
+        }
+        let bodies = match last {
+            Some(Response::SimulationResult(bodies, _)) => bodies,
+            Some(other) => return Err(unexpected(&other)),
+            None => unreachable!(),
+        };
+        let x = bodies
+            .iter()
+            .find(|(id, _, _)| *id == BULLET)
+            .map(|(_, transform, _)| transform.translation.x)
+            .ok_or("bullet missing from the result")?;
+        if x >= WALL_X {
+            return Err(format!("the ball went through the wall, x = {}", x));
+        }
+        Ok(())
+    }
+
+    /// Steps `steps` times and returns the heights of two bodies.
+    fn heights_after(&mut self, steps: usize, a: u64, b: u64) -> Result<(f32, f32), String> {
+        let mut last = None;
+        for _ in 0..steps {
+            last = Some(self.send(Request::SimulateStep(STEP))?);
+        }
+
+        match last {
+            Some(Response::SimulationResult(bodies, _)) => {
+                let height = |id| {
+                    bodies
+                        .iter()
+                        .find(|(body, _, _)| *body == id)
+                        .map(|(_, transform, _)| transform.translation.y)
+                        .ok_or(format!("body {} missing from the result", id))
+                };
+                Ok((height(a)?, height(b)?))
+            }
+            Some(other) => Err(unexpected(&other)),
+            None => unreachable!(),
+        }
+    }
+
+    fn close(&mut self) -> CheckResult {
+        self.socket.close(None).map_err(|err| err.to_string())?;
+        // Drive the closing handshake until the server acknowledges it.
+        loop {
+            match self.socket.read_message() {
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+}
+
+fn body(id: u64, body: RigidBody, height: f32) -> CreatedBody {
+    CreatedBody {
+        id,
+        body,
+        transform: Some(Isometry::translation(0.0, height, 0.0)),
+        additional_mass_properties: None,
+        velocity: None,
+        damping: None,
+        gravity_scale: None,
+        locked_axes: None,
+        ccd: None,
+        user_meta: None,
+    }
+}
+
+fn collider(id: u64, shape: SerializableShape) -> CreatedCollider {
+    CreatedCollider {
+        id,
+        shape,
+        transform: None,
+        sensor: None,
+        mass_properties: None,
+        friction: None,
+        restitution: None,
+        report_overlaps: false,
+    }
+}
+
+fn unexpected(response: &Response) -> String {
+    format!("unexpected response <{}>: {:?}", response.name(), response)
+}
+
+fn run(name: &str, check: impl FnOnce() -> CheckResult) -> bool {
+    let start = Instant::now();
+    let result = check();
+    let elapsed = start.elapsed();
+    match &result {
+        Ok(()) => println!("PASS {:<20} {:>10.2?}", name, elapsed),
+        Err(err) => println!("FAIL {:<20} {:>10.2?}  {}", name, elapsed, err),
+    }
+    result.is_ok()
+}
+
+fn main() -> ExitCode {
+    let matches = command!()
+        .arg(arg!(<URL> "The server to check, e.g. ws://localhost:8080/socket"))
+        .get_matches();
+    let url = matches.get_one::<String>("URL").unwrap();
+
+    let start = Instant::now();
+    let mut conformance = match connect(url.as_str()) {
+        Ok((socket, _)) => Conformance {
+            socket,
+            ball: RigidBodyHandle::invalid(),
+        },
+        Err(err) => {
+            println!("FAIL {:<20} {:>10.2?}  {}", "connect", start.elapsed(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("PASS {:<20} {:>10.2?}", "connect", start.elapsed());
+
+    type Check = fn(&mut Conformance) -> CheckResult;
+    let checks: [(&str, Check); 13] = [
+        ("update_config", Conformance::update_config),
+        ("create_bodies", Conformance::create_bodies),
+        ("create_colliders", Conformance::create_colliders),
+        ("duplicate_body", Conformance::duplicate_body),
+        ("oversized_user_meta", Conformance::oversized_user_meta),
+        ("degenerate_hull", Conformance::degenerate_hull),
+        ("simulate", Conformance::simulate),
+        ("sim_time", Conformance::sim_time),
+        ("point_in_solid", Conformance::point_in_solid),
+        ("bulk_request", Conformance::bulk_request),
+        ("frozen_bodies", Conformance::frozen_bodies),
+        ("ccd_tunneling", Conformance::ccd_tunneling),
+        ("close", Conformance::close),
+    ];
+
+    let total = checks.len() + 1;
+    let passed = 1 + checks
+        .into_iter()
+        .filter(|(name, check)| run(name, || check(&mut conformance)))
+        .count();
+
+    println!("{}/{} checks passed", passed, total);
+    if passed == total {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bevy_rapier3d::prelude::*;
+use bevy_rapier3d::rapier::prelude::Isometry;
+
+use bincode::{deserialize, serialize};
+use clap::{arg, command};
+#[cfg(feature = "compression")]
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use tungstenite::{connect, stream::MaybeTlsStream, Message, WebSocket};
+
+use shared::serializable::SerializableShape;
+use shared::*;
+
+const GROUND: u64 = 1;
+const FIRST_BALL: u64 = 2;
+
+/// A load scenario, read from a TOML file. Missing keys take their defaults.
+///
+/// ```toml
+/// clients = 16
+/// duration = 60.0
+/// bodies = 200
+/// rate = 60.0
+/// jitter = 0.1
+///
+/// [mix]
+/// wake = 0.05
+/// point_in_solid = 0.2
+/// radial_impulse = 0.01
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Scenario {
+    /// Number of concurrent clients.
+    clients: usize,
+    /// Seconds every client keeps stepping.
+    duration: f32,
+    /// Dynamic bodies every client registers on top of its ground.
+    bodies: u64,
+    /// Steps per second per client.
+    rate: f32,
+    /// Random think time between steps, as a fraction of the step interval.
+    jitter: f32,
+    /// Seed of the request mix, every client offsets it by its index.
+    seed: u64,
+    mix: RequestMix,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            clients: 4,
+            duration: 10.0,
+            bodies: 100,
+            rate: 60.0,
+            jitter: 0.0,
+            seed: 0,
+            mix: RequestMix::default(),
+        }
+    }
+}
+
+/// Chance of sending each request after a step.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RequestMix {
+    wake: f64,
+    point_in_solid: f64,
+    radial_impulse: f64,
+}
+
+impl Scenario {
+    fn validate(&self) -> Result<(), String> {
+        if self.clients == 0 || self.rate <= 0.0 || self.duration < 0.0 {
+            return Err("clients and rate must be positive, duration can't be negative".into());
+        }
+        if !(0.0..1.0).contains(&self.jitter) {
+            return Err("jitter must be in [0, 1)".into());
+        }
+        let mix = [
+            self.mix.wake,
+            self.mix.point_in_solid,
+            self.mix.radial_impulse,
+        ];
+        if mix.iter().any(|chance| !(0.0..=1.0).contains(chance)) {
+            return Err("request mix chances must be in [0, 1]".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct RequestStats {
+    latencies: Vec<Duration>,
+    /// Requests the server answered with `Response::Error`.
+    errors: u64,
+}
+
+type ClientStats = BTreeMap<&'static str, RequestStats>;
+
+fn encode(request: &Request) -> Result<Vec<u8>, String> {
+    let payload = serialize(request).map_err(|err| err.to_string())?;
+
+    #[cfg(feature = "compression")]
+    let payload = {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload).map_err(|err| err.to_string())?;
+        encoder.finish().map_err(|err| err.to_string())?
+    };
+
+    #[cfg(feature = "checksum")]
+    let payload = shared::checksum::append(payload);
+
+    Ok(payload)
+}
+
+struct SyntheticClient {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    stats: ClientStats,
+}
+
+impl SyntheticClient {
+    fn send(&mut self, request: &Request) -> Result<Response, String> {
+        self.socket
+            .write_message(Message::Binary(encode(request)?))
+            .map_err(|err| err.to_string())?;
+        let data = self
+            .socket
+            .read_message()
+            .map_err(|err| err.to_string())?
+            .into_data();
+
+        #[cfg(feature = "checksum")]
+        let data = shared::checksum::verify(data).ok_or("checksum mismatch")?;
+
+        #[cfg(feature = "compression")]
+        let data = {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(data.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(|err| err.to_string())?;
+            decompressed
+        };
+
+        deserialize(&data).map_err(|err| err.to_string())
+    }
+
+    /// Sends the request and records its latency. Only connection failures are returned,
+    /// error responses are counted and the client carries on.
+    fn record(&mut self, request: Request) -> Result<(), String> {
+        let start = Instant::now();
+        let response = self.send(&request)?;
+        let elapsed = start.elapsed();
+
+        let stats = self.stats.entry(request.name()).or_default();
+        match response {
+            Response::Error(_) => stats.errors += 1,
+            _ => stats.latencies.push(elapsed),
+        }
+        Ok(())
+    }
+
+    fn goodbye(mut self) -> Result<ClientStats, String> {
+        self.socket
+            .write_message(Message::Binary(encode(&Request::Goodbye)?))
+            .map_err(|err| err.to_string())?;
+        loop {
+            match self.socket.read_message() {
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(self.stats),
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+}
+
+/// The ground and a grid of balls above it.
+fn scene(bodies: u64) -> (Vec<CreatedBody>, Vec<CreatedCollider>) {
+    let side = (bodies as f32).sqrt().ceil() as u64;
+    let ground = (GROUND, RigidBody::Fixed, Vect::ZERO);
+    let balls = (0..bodies).map(|i| {
+        let (x, z) = ((i % side) as f32, (i / side) as f32);
+        (
+            FIRST_BALL + i,
+            RigidBody::Dynamic,
+            Vect::new(x - side as f32 / 2.0, 5.0, z - side as f32 / 2.0),
+        )
+    });
+
+    std::iter::once(ground)
+        .chain(balls)
+        .map(|(id, body, position)| {
+            let shape = match body {
+                RigidBody::Fixed => Collider::cuboid(100.0, 0.5, 100.0),
+                _ => Collider::ball(0.4),
+            };
+            (
+                CreatedBody {
+                    id,
+                    body,
+                    transform: Some(Isometry::translation(position.x, position.y, position.z)),
+                    additional_mass_properties: None,
+                    velocity: None,
+                    damping: None,
+                    gravity_scale: None,
+                    locked_axes: None,
+                    ccd: None,
+                    user_meta: None,
+                },
+                CreatedCollider {
+                    id,
+                    shape: SerializableShape::Collider(shape),
+                    transform: None,
+                    sensor: None,
+                    mass_properties: None,
+                    friction: None,
+                    restitution: None,
+                    report_overlaps: false,
+                },
+            )
+        })
+        .unzip()
+}
+
+fn run_client(url: &str, scenario: &Scenario, index: usize) -> Result<ClientStats, String> {
+    let (socket, _) = connect(url).map_err(|err| err.to_string())?;
+    let mut client = SyntheticClient {
+        socket,
+        stats: BTreeMap::new(),
+    };
+    let mut rng = StdRng::seed_from_u64(scenario.seed.wrapping_add(index as u64));
+
+    let (bodies, colliders) = scene(scenario.bodies);
+    client.record(Request::UpdateConfig(RapierConfiguration::default().into()))?;
+    client.record(Request::CreateBodies(bodies))?;
+    client.record(Request::CreateColliders(colliders))?;
+
+    let interval = 1.0 / scenario.rate;
+    let mix = &scenario.mix;
+    let end = Instant::now() + Duration::from_secs_f32(scenario.duration);
+    while Instant::now() < end {
+        let tick = Instant::now();
+
+        client.record(Request::SimulateStep(interval))?;
+        if scenario.bodies > 0 && rng.gen_bool(mix.wake) {
+            let ball = FIRST_BALL + rng.gen_range(0..scenario.bodies);
+            client.record(Request::WakeBodies(vec![ball]))?;
+        }
+        if rng.gen_bool(mix.point_in_solid) {
+            let point = Vect::new(rng.gen_range(-10.0..10.0), 0.5, rng.gen_range(-10.0..10.0));
+            client.record(Request::PointInSolid(point))?;
+        }
+        if rng.gen_bool(mix.radial_impulse) {
+            let center = Vect::new(rng.gen_range(-10.0..10.0), 0.5, rng.gen_range(-10.0..10.0));
+            client.record(Request::RadialImpulse {
+                center,
+                radius: 3.0,
+                strength: 5.0,
+                falloff: true,
+            })?;
+        }
+
+        let jitter = rng.gen_range(-scenario.jitter..=scenario.jitter);
+        let think = Duration::from_secs_f32(interval * (1.0 + jitter));
+        if let Some(rest) = think.checked_sub(tick.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+
+    client.goodbye()
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> u128 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index].as_micros()
+}
+
+fn print_row(client: &str, name: &str, stats: &RequestStats) {
+    let mut latencies = stats.latencies.clone();
+    latencies.sort_unstable();
+    println!(
+        "{:<8} {:<16} {:>8} {:>7} {:>10} {:>10} {:>10} {:>10}",
+        client,
+        name,
+        latencies.len(),
+        stats.errors,
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 1.0),
+    );
+}
+
+fn main() -> ExitCode {
+    let matches = command!()
+        .arg(arg!(<URL> "The server to load, e.g. ws://localhost:8080/socket"))
+        .arg(arg!(<SCENARIO> "Path to the TOML scenario"))
+        .get_matches();
+    let url = matches.get_one::<String>("URL").unwrap();
+    let path = matches.get_one::<String>("SCENARIO").unwrap();
+
+    let scenario: Scenario = match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| toml::from_str(&text).map_err(|err| err.to_string()))
+    {
+        Ok(scenario) => scenario,
+        Err(err) => {
+            eprintln!("Can't read scenario {}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = scenario.validate() {
+        eprintln!("Invalid scenario {}: {}", path, err);
+        return ExitCode::FAILURE;
+    }
+
+    let results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..scenario.clients)
+            .map(|index| {
+                let scenario = &scenario;
+                scope.spawn(move || run_client(url, scenario, index))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    // One row per client and request type, then the totals. Latencies are in microseconds.
+    println!(
+        "{:<8} {:<16} {:>8} {:>7} {:>10} {:>10} {:>10} {:>10}",
+        "client", "request", "count", "errors", "p50_us", "p90_us", "p99_us", "max_us"
+    );
+    let mut total = ClientStats::new();
+    let mut failed = 0;
+    for (index, result) in results.iter().enumerate() {
+        match result {
+            Ok(stats) => {
+                for (name, stats) in stats {
+                    print_row(&index.to_string(), name, stats);
+                    let total = total.entry(*name).or_default();
+                    total.latencies.extend(&stats.latencies);
+                    total.errors += stats.errors;
+                }
+            }
+            Err(err) => {
+                eprintln!("Client {} failed: {}", index, err);
+                failed += 1;
+            }
+        }
+    }
+    for (name, stats) in &total {
+        print_row("total", name, stats);
+    }
+
+    if failed > 0 {
+        eprintln!("{}/{} clients failed", failed, scenario.clients);
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,87 @@
+//! Machine-readable world and connection lifecycle events.
+//!
+//! Every event is a single `logfmt` line on stdout starting with `event=<name>`,
+//! e.g. `event=client_detached peer=127.0.0.1:51234 reason=goodbye`.
+//! Event and field names are what log tooling matches on, don't rename them.
+
+use std::fmt::{Display, Write};
+
+pub const WORLD_CREATED: &str = "world_created";
+pub const WORLD_DROPPED: &str = "world_dropped";
+pub const CLIENT_ATTACHED: &str = "client_attached";
+/// Has a [`REASON`]: `goodbye`, `closed` or `error`.
+pub const CLIENT_DETACHED: &str = "client_detached";
+/// The client left without saying goodbye, its world is kept for a reconnect.
+pub const SESSION_DETACHED: &str = "session_detached";
+pub const SESSION_RESUMED: &str = "session_resumed";
+/// Nobody reconnected in time, followed by [`WORLD_DROPPED`] without a [`PEER`].
+pub const SESSION_EXPIRED: &str = "session_expired";
+/// Has the [`PEER`] that sent the request, a [`KIND`] naming the exceeded limit and
+/// the [`ID`] of the first rejected item.
+pub const QUOTA_REJECTED: &str = "quota_rejected";
+
+/// Address of the client the event is about.
+pub const PEER: &str = "peer";
+pub const REASON: &str = "reason";
+pub const SESSION: &str = "session";
+pub const KIND: &str = "kind";
+pub const ID: &str = "id";
+
+/// Lines emitted by the tests of this process, see [`emitted`].
+#[cfg(test)]
+static EMITTED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+pub fn emit(event: &str, fields: &[(&str, &dyn Display)]) {
+    let line = format_event(event, fields);
+    println!("{}", line);
+    #[cfg(test)]
+    EMITTED.lock().unwrap().push(line);
+}
+
+/// Every event emitted so far, parsed into its fields. Tests run concurrently,
+/// filter by a field that is unique to the test.
+#[cfg(test)]
+pub fn emitted() -> Vec<std::collections::HashMap<String, String>> {
+    EMITTED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|line| {
+            line.split(' ')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+fn format_event(event: &str, fields: &[(&str, &dyn Display)]) -> String {
+    let mut line = format!("event={}", event);
+    for (name, value) in fields {
+        let value = value.to_string();
+        // Quote values that would break the line apart, everything else stays bare.
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+            write!(line, " {}={:?}", name, value).unwrap();
+        } else {
+            write!(line, " {}={}", name, value).unwrap();
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_fields_in_order() {
+        let line = format_event(QUOTA_REJECTED, &[(KIND, &"max_bodies"), (ID, &7)]);
+        assert_eq!(line, "event=quota_rejected kind=max_bodies id=7");
+    }
+
+    #[test]
+    fn quotes_values_that_would_split_the_line() {
+        let line = format_event(CLIENT_DETACHED, &[(REASON, &"timed out"), (ID, &"")]);
+        assert_eq!(line, r#"event=client_detached reason="timed out" id="""#);
+    }
+}
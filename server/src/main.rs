@@ -1,21 +1,42 @@
 use bevy::prelude::*;
-use bevy_rapier3d::rapier::prelude::{ColliderBuilder, RigidBodyBuilder, RigidBodyHandle};
-use bevy_rapier3d::{prelude::*, utils};
+use bevy_rapier3d::prelude::*;
+use bevy_rapier3d::rapier::parry::{bounding_volume::BoundingVolume, query, shape::Shape};
+use bevy_rapier3d::rapier::prelude::{
+    Ball, ColliderBuilder, ColliderHandle, ImpulseJointHandle, IntegrationParameters, Isometry,
+    Point, QueryFilter, Ray, RigidBodyBuilder, RigidBodyHandle, RigidBodyType, Vector,
+};
 
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 use bincode::{deserialize, serialize};
 use clap::{arg, command, value_parser};
 use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
-use rand::{thread_rng, Rng};
-use tungstenite::{accept, Message};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use socket2::{Domain, Protocol, Socket, Type};
+use tungstenite::handshake::server::{Request as HandshakeRequest, Response as HandshakeResponse};
+use tungstenite::http::HeaderValue;
+use tungstenite::{accept_hdr, Message, WebSocket};
 
+use shared::scale::ScaleCtx;
+use shared::serializable::{
+    ContentHash, SerializableDamping, SerializableExternalForce, SerializableExternalImpulse,
+    SerializableLockedAxes, SerializableRapierConfiguration, SerializableShape,
+    SerializableVelocity,
+};
 use shared::*;
 
+mod lifecycle;
+mod mesh_cache;
+mod session;
+
+/// How far ahead, in seconds, `TimeOfImpact` looks for a collision.
+const TOI_HORIZON: Real = 10.0;
+
 #[derive(Debug, Clone, Copy)]
 enum SimulatedLatency {
     None,
@@ -23,6 +44,63 @@ enum SimulatedLatency {
     Random { min: u64, mean: u64 },
 }
 
+#[derive(Debug, Clone, Copy)]
+struct ServerConfig {
+    simulated_latency: SimulatedLatency,
+    max_bodies: Option<usize>,
+    /// Seeds the generator of every connection with `seed + connection index`.
+    /// The generator only samples the simulated latency, the rest of the server is
+    /// deterministic: Rapier uses no randomness and responses don't depend on hash order.
+    seed: Option<u64>,
+}
+
+/// State of a body before it was frozen, restored when it is unfrozen.
+struct FrozenBody {
+    body_type: RigidBodyType,
+    linvel: Vector<Real>,
+    angvel: Vector<Real>,
+}
+
+/// Simulation state owned by a single connection, or by a session between connections.
+#[derive(Default)]
+struct PhysicsWorld {
+    /// Keeps the default physics scale of 1, `scale` converts between the protocol and it.
+    context: RapierContext,
+    scale: ScaleCtx,
+    config: Option<RapierConfiguration>,
+    sim_to_render_time: SimulationToRenderTime,
+    /// Simulated seconds since the world was created.
+    sim_time: f32,
+    step_count: u64,
+    /// Results are sent every `result_interval` steps, 0 and 1 both mean every step.
+    result_interval: u32,
+    entity2body: HashMap<Entity, RigidBodyHandle>,
+    entity2collider: HashMap<Entity, ColliderHandle>,
+    entity2joint: HashMap<Entity, ImpulseJointHandle>,
+    /// Serialized create requests by id, used to answer repeated creates.
+    body_descriptions: HashMap<u64, Vec<u8>>,
+    collider_descriptions: HashMap<u64, Vec<u8>>,
+    joint_descriptions: HashMap<u64, Vec<u8>>,
+    user_meta: HashMap<u64, Vec<u8>>,
+    frozen_bodies: HashMap<u64, FrozenBody>,
+    /// Positions and sleep states last sent by `SimulateStepDelta`.
+    sent_positions: HashMap<RigidBodyHandle, (Isometry<Real>, bool)>,
+    /// Sensors whose overlap counts are reported with every step.
+    overlap_sensors: Vec<(u64, ColliderHandle)>,
+}
+
+/// How often detached sessions are checked for expiry.
+const SESSION_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for the client to acknowledge closing the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often an idle session connection checks whether another connection took it over.
+const SESSION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of connections currently being served.
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = command!()
         .arg(
@@ -47,6 +125,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .required(false)
             .requires("latency")
             .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(
+                --"max-bodies" <COUNT> "The maximum number of bodies in a single world"
+            )
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(
+                --backlog <BACKLOG> "The maximum number of pending connections"
+            )
+            .required(false)
+            .default_value("128")
+            .value_parser(value_parser!(i32).range(1..)),
+        )
+        .arg(
+            arg!(
+                --"session-timeout" <SECONDS> "How long the world of a dropped session is kept for a reconnect"
+            )
+            .required(false)
+            .default_value("60")
+            .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(
+                --seed <SEED> "The seed for the simulated latency of each connection, random if not specified"
+            )
+            .required(false)
+            .value_parser(value_parser!(u64)),
         );
 
     let matches = cmd.get_matches_mut();
@@ -69,17 +177,34 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ => unreachable!(),
     };
 
+    let server_config = ServerConfig {
+        simulated_latency,
+        max_bodies: matches.get_one::<usize>("max-bodies").copied(),
+        seed: matches.get_one::<u64>("seed").copied(),
+    };
+
+    let session_timeout = Duration::from_secs(*matches.get_one::<u64>("session-timeout").unwrap());
+    std::thread::spawn(move || loop {
+        sleep(SESSION_EXPIRY_INTERVAL);
+        session::expire(session_timeout);
+    });
+
     let port = matches.get_one::<u16>("port").unwrap();
-    let server = TcpListener::bind(format!("0.0.0.0:{}", port))?;
-    println!("Listening on port {}", port);
+    let backlog = matches.get_one::<i32>("backlog").unwrap();
+    let server = listen(*port, *backlog)?;
+    println!("Listening on port {} with a backlog of {}", port, backlog);
 
-    for stream in server.incoming() {
+    for (connection_index, stream) in server.incoming().enumerate() {
         match stream {
             Ok(stream) => {
+                let rng = connection_rng(server_config.seed, connection_index);
                 std::thread::spawn(move || {
-                    if let Err(e) = handle_connection(stream, simulated_latency) {
+                    ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = handle_connection(stream, server_config, rng) {
                         println!("Error: {}", e);
                     }
+                    let active = ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed) - 1;
+                    println!("{} active connections", active);
                 });
             }
             Err(e) => {
@@ -91,32 +216,195 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn listen(port: u16, backlog: i32) -> std::io::Result<TcpListener> {
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&address.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
 fn handle_connection(
     stream: TcpStream,
-    simulated_latency: SimulatedLatency,
+    server_config: ServerConfig,
+    mut rng: StdRng,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let peer_addr = stream.peer_addr()?;
 
-    let mut websocket = accept(stream)?;
+    // Requests and responses are small and sent one at a time,
+    // so don't let Nagle's algorithm hold them back.
+    stream.set_nodelay(true)?;
+
+    let mut attached = None;
+    let websocket = accept_hdr(
+        stream,
+        |request: &HandshakeRequest, mut response: HandshakeResponse| {
+            let Some(id) = session::session_id(request)? else {
+                return Ok(response);
+            };
+            let attachment = session::attach(id)?;
+            response.headers_mut().insert(
+                SESSION_RESUMED_HEADER,
+                HeaderValue::from_static(if attachment.resumed { "true" } else { "false" }),
+            );
+            attached = Some((id, attachment));
+            Ok(response)
+        },
+    )
+    .map_err(|err| err.to_string());
+    let mut websocket = match websocket {
+        Ok(websocket) => websocket,
+        Err(err) => {
+            // The handshake failed after attaching, give the world back.
+            if let Some((id, attachment)) = attached {
+                session::detach(id, attachment.world);
+            }
+            return Err(err.into());
+        }
+    };
+
+    println!(
+        "Connection from {} (TCP_NODELAY: {})",
+        peer_addr,
+        websocket.get_ref().nodelay()?
+    );
+    lifecycle::emit(lifecycle::CLIENT_ATTACHED, &[(lifecycle::PEER, &peer_addr)]);
+
+    let (mut world, session, taken_over) = match attached {
+        Some((id, attachment)) => {
+            if attachment.resumed {
+                println!("{} resumed session {}", peer_addr, id);
+                lifecycle::emit(
+                    lifecycle::SESSION_RESUMED,
+                    &[(lifecycle::PEER, &peer_addr), (lifecycle::SESSION, &id)],
+                );
+            } else {
+                lifecycle::emit(lifecycle::WORLD_CREATED, &[(lifecycle::PEER, &peer_addr)]);
+            }
+            // Wake up regularly to notice when another connection takes the session over.
+            websocket
+                .get_ref()
+                .set_read_timeout(Some(SESSION_POLL_INTERVAL))?;
+            (attachment.world, Some(id), Some(attachment.taken_over))
+        }
+        None => {
+            lifecycle::emit(lifecycle::WORLD_CREATED, &[(lifecycle::PEER, &peer_addr)]);
+            (PhysicsWorld::default(), None, None)
+        }
+    };
+
+    let result = serve(
+        &mut websocket,
+        peer_addr,
+        &server_config,
+        &mut world,
+        &mut rng,
+        taken_over.as_deref(),
+    );
+
+    match session {
+        // Without a goodbye the client may come back for its world.
+        Some(id) if !matches!(result, Ok(DetachReason::Goodbye)) => {
+            session::detach(id, world);
+            lifecycle::emit(
+                lifecycle::SESSION_DETACHED,
+                &[(lifecycle::PEER, &peer_addr), (lifecycle::SESSION, &id)],
+            );
+        }
+        session => {
+            if let Some(id) = session {
+                session::end(id);
+            }
+            drop(world);
+            lifecycle::emit(lifecycle::WORLD_DROPPED, &[(lifecycle::PEER, &peer_addr)]);
+        }
+    }
+
+    let result = match result {
+        Ok(DetachReason::Goodbye) => close(&mut websocket).map(|()| DetachReason::Goodbye),
+        result => result,
+    };
+
+    let reason = match &result {
+        Ok(reason) => reason.as_str(),
+        Err(_) => "error",
+    };
+    lifecycle::emit(
+        lifecycle::CLIENT_DETACHED,
+        &[(lifecycle::PEER, &peer_addr), (lifecycle::REASON, &reason)],
+    );
+
+    result.map(|_| ())
+}
+
+/// Closes the connection from our side and waits for the client to acknowledge it.
+fn close(websocket: &mut WebSocket<TcpStream>) -> Result<(), Box<dyn std::error::Error>> {
+    websocket.get_ref().set_read_timeout(Some(CLOSE_TIMEOUT))?;
+    websocket.close(None)?;
+    loop {
+        match websocket.read_message() {
+            Ok(_) => continue,
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
 
-    println!("Connection from {}", peer_addr);
+#[derive(Debug, Clone, Copy)]
+enum DetachReason {
+    /// The client sent `Request::Goodbye`.
+    Goodbye,
+    /// The client sent a close frame.
+    Closed,
+    /// Another connection attached to the session.
+    TakenOver,
+}
 
-    let mut context = RapierContext::default();
-    let mut config: Option<RapierConfiguration> = None;
-    let mut sim_to_render_time = SimulationToRenderTime::default();
-    let mut entity2body = HashMap::new();
+impl DetachReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            DetachReason::Goodbye => "goodbye",
+            DetachReason::Closed => "closed",
+            DetachReason::TakenOver => "taken_over",
+        }
+    }
+}
 
+/// Answers requests until the client leaves.
+fn serve(
+    websocket: &mut WebSocket<TcpStream>,
+    peer_addr: SocketAddr,
+    server_config: &ServerConfig,
+    world: &mut PhysicsWorld,
+    rng: &mut StdRng,
+    taken_over: Option<&AtomicBool>,
+) -> Result<DetachReason, Box<dyn std::error::Error>> {
     // dummy physics hooks
     #[allow(clippy::let_unit_value)]
     let physics_hooks = ();
 
     loop {
         println!("Waiting for message...");
-        let msg = websocket.read_message()?;
+        let msg = match websocket.read_message() {
+            Ok(msg) => msg,
+            Err(tungstenite::Error::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                if taken_over.map_or(false, |taken_over| taken_over.load(Ordering::Relaxed)) {
+                    println!("{} was taken over by another connection", peer_addr);
+                    return Ok(DetachReason::TakenOver);
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
         println!("Received message of length {:?}", msg.len());
         if msg.is_binary() {
             let msg_data = msg.into_data();
 
+            #[cfg(feature = "checksum")]
+            let msg_data = shared::checksum::verify(msg_data).ok_or("Checksum mismatch")?;
+
             let req = {
                 #[cfg(feature = "compression")]
                 {
@@ -132,35 +420,37 @@ fn handle_connection(
                 }
             };
 
-            let response = handle_request(
-                req,
-                &mut context,
-                &mut config,
-                &mut sim_to_render_time,
-                &mut entity2body,
-                physics_hooks,
-            );
+            if let Request::Goodbye = req {
+                println!("{} said goodbye, dropping its world", peer_addr);
+                return Ok(DetachReason::Goodbye);
+            }
 
-            simulate_latency(simulated_latency);
+            let response = handle_request(req, peer_addr, server_config, world, physics_hooks);
+
+            simulate_latency(server_config.simulated_latency, rng);
 
             let serialized = serialize(&response)?;
-            let msg = {
+            let payload = {
                 #[cfg(feature = "compression")]
                 {
                     let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
                     encoder.write_all(&serialized)?;
-                    let compressed = encoder.finish()?;
-                    Message::binary(compressed)
+                    encoder.finish()?
                 }
                 #[cfg(not(feature = "compression"))]
                 {
-                    Message::binary(serialized)
+                    serialized
                 }
             };
+
+            #[cfg(feature = "checksum")]
+            let payload = shared::checksum::append(payload);
+
+            let msg = Message::binary(payload);
             websocket.write_message(msg)?;
         } else if msg.is_close() {
             println!("Closing connection with {}", peer_addr);
-            return Ok(());
+            return Ok(DetachReason::Closed);
         } else {
             return Err(format!("Unexpected message: {:?}", msg).into());
         }
@@ -169,85 +459,431 @@ fn handle_connection(
 
 fn handle_request(
     req: Request,
-    mut context: &mut RapierContext,
-    mut config: &mut Option<RapierConfiguration>,
-    mut sim_to_render_time: &mut SimulationToRenderTime,
-    mut entity2body: &mut HashMap<Entity, RigidBodyHandle>,
+    peer_addr: SocketAddr,
+    server_config: &ServerConfig,
+    world: &mut PhysicsWorld,
     physics_hooks: (),
 ) -> Response {
     match req {
         Request::BulkRequest(reqs) => {
-            let mut responses = vec![];
-            for req in reqs {
-                responses.push(handle_request(
+            // Config changes are applied before anything else in the batch, so
+            // bodies are never created or stepped with a stale configuration.
+            // Responses keep the order of the requests.
+            let mut responses: Vec<Option<Response>> = reqs.iter().map(|_| None).collect();
+            let (configs, others): (Vec<_>, Vec<_>) = reqs
+                .into_iter()
+                .enumerate()
+                .partition(|(_, req)| matches!(req, Request::UpdateConfig(_)));
+
+            for (index, req) in configs.into_iter().chain(others) {
+                responses[index] = Some(handle_request(
                     req,
-                    &mut context,
-                    &mut config,
-                    &mut sim_to_render_time,
-                    &mut entity2body,
+                    peer_addr,
+                    server_config,
+                    world,
                     physics_hooks,
                 ));
             }
-            Response::BulkResponse(responses)
+            Response::BulkResponse(responses.into_iter().flatten().collect())
         }
-        Request::UpdateConfig(new_config) => update_config(new_config.into(), &mut config),
-        Request::CreateBodies(bodies) => create_bodies(bodies, &mut context, &mut entity2body),
-        Request::CreateColliders(colliders) => {
-            create_colliders(colliders, &mut context, &entity2body)
-        }
-        Request::SimulateStep(delta_time) => simulate_step(
-            &mut context,
-            config.unwrap().gravity,
-            config.unwrap().timestep_mode,
-            physics_hooks,
+        Request::UpdateConfig(new_config) => update_config(
+            new_config,
+            &mut world.config,
+            &mut world.scale,
+            &mut world.context,
+        ),
+        Request::CreateBodies(bodies) => create_bodies(
+            bodies,
+            peer_addr,
+            server_config.max_bodies,
+            world.scale,
+            &mut world.context,
+            &mut world.entity2body,
+            &mut world.body_descriptions,
+            &mut world.user_meta,
+        ),
+        Request::CreateColliders(colliders) => create_colliders(
+            colliders,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+            &mut world.entity2collider,
+            &mut world.collider_descriptions,
+            &mut world.overlap_sensors,
+        ),
+        Request::CreateJoints(joints) => create_joints(
+            joints,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+            &mut world.entity2joint,
+            &mut world.joint_descriptions,
+        ),
+        Request::SimulateStep(delta_time) => step(delta_time, None, world, physics_hooks),
+        Request::SimulateStepDelta {
             delta_time,
-            &mut sim_to_render_time,
+            epsilon,
+        } => step(delta_time, Some(epsilon), world, physics_hooks),
+        Request::WakeBodies(ids) => wake_bodies(ids, &mut world.context, &world.entity2body),
+        Request::TimeOfImpact(id1, id2) => {
+            time_of_impact(id1, id2, &world.context, &world.entity2body)
+        }
+        Request::ClearForces(ids) => clear_forces(ids, &mut world.context, &world.entity2body),
+        Request::GroundedCheck(checks) => grounded_check(
+            checks,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+            &world.user_meta,
+        ),
+        Request::DetachColliders(ids) => {
+            detach_colliders(ids, &mut world.context, &world.entity2collider)
+        }
+        Request::QuerySimTime => Response::SimTime(world.sim_time),
+        Request::GetIslands => get_islands(&world.context),
+        Request::PointInSolid(point) => {
+            point_in_solid(point, world.scale, &mut world.context, &world.user_meta)
+        }
+        Request::SetCcd(changes) => set_ccd(changes, &mut world.context, &world.entity2body),
+        Request::SetUserMeta(metas) => {
+            set_user_meta(metas, &mut world.user_meta, &world.entity2body)
+        }
+        Request::GetUserMeta(ids) => get_user_meta(ids, &world.user_meta),
+        Request::RadialImpulse {
+            center,
+            radius,
+            strength,
+            falloff,
+        } => radial_impulse(
+            center,
+            radius,
+            strength,
+            falloff,
+            world.scale,
+            &mut world.context,
+        ),
+        Request::RemoveBodies(ids) => {
+            for id in &ids {
+                world.frozen_bodies.remove(id);
+            }
+            let response = remove_bodies(
+                ids,
+                &mut world.context,
+                &mut world.entity2body,
+                &mut world.entity2collider,
+                &mut world.body_descriptions,
+                &mut world.collider_descriptions,
+                &mut world.user_meta,
+                &mut world.overlap_sensors,
+            );
+            // Joints attached to the removed bodies went with them.
+            let joints = &world.context.impulse_joints;
+            let joint_descriptions = &mut world.joint_descriptions;
+            world.entity2joint.retain(|entity, handle| {
+                let exists = joints.get(*handle).is_some();
+                if !exists {
+                    joint_descriptions.remove(&entity.to_bits());
+                }
+                exists
+            });
+            response
+        }
+        Request::RemoveColliders(ids) => remove_colliders(
+            ids,
+            &mut world.context,
+            &mut world.entity2collider,
+            &mut world.collider_descriptions,
+            &mut world.overlap_sensors,
+        ),
+        Request::RemoveJoints(ids) => remove_joints(
+            ids,
+            &mut world.context,
+            &mut world.entity2joint,
+            &mut world.joint_descriptions,
+        ),
+        Request::CreateColliderFromCachedMesh {
+            id,
+            hash,
+            transform,
+            fallback,
+        } => create_collider_from_cached_mesh(id, hash, transform, fallback, world),
+        Request::ApplyForces(forces) => {
+            apply_forces(forces, world.scale, &mut world.context, &world.entity2body)
+        }
+        Request::SetBodiesFrozen(changes) => set_bodies_frozen(
+            changes,
+            &mut world.context,
+            &world.entity2body,
+            &mut world.frozen_bodies,
+        ),
+        Request::ApplyImpulses(impulses) => apply_impulses(
+            impulses,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+        ),
+        Request::SetVelocities(velocities) => set_velocities(
+            velocities,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+        ),
+        Request::SetDamping(damping) => {
+            set_damping(damping, &mut world.context, &world.entity2body)
+        }
+        Request::SetGravityScale(gravity_scales) => {
+            set_gravity_scale(gravity_scales, &mut world.context, &world.entity2body)
+        }
+        Request::SetLockedAxes(locked_axes) => {
+            set_locked_axes(locked_axes, &mut world.context, &world.entity2body)
+        }
+        Request::GetDiagnostics => get_diagnostics(&world.context),
+        Request::CastRay {
+            origin,
+            dir,
+            max_toi,
+            solid,
+        } => cast_ray(
+            origin,
+            dir,
+            max_toi,
+            solid,
+            world.scale,
+            &mut world.context,
+            &world.user_meta,
+        ),
+        Request::SetBodyPositions(positions) => set_body_positions(
+            positions,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+        ),
+        Request::QueryPhysicsScale => {
+            println!("Querying physics scale");
+            Response::PhysicsScale(world.scale.physics_scale)
+        }
+        Request::Goodbye => Response::Error("Goodbye must be sent on its own".to_string()),
+        Request::SetResultInterval(interval) => {
+            println!("Setting result interval to {}", interval);
+            world.result_interval = interval;
+            Response::ResultIntervalSet
+        }
+    }
+}
+
+/// Steps the world and builds the result, with `epsilon` only of the bodies that
+/// are awake or moved since they were last sent.
+fn step(
+    delta_time: f32,
+    epsilon: Option<Real>,
+    world: &mut PhysicsWorld,
+    physics_hooks: (),
+) -> Response {
+    // Without a config, step by exactly the requested dt.
+    let config = world.config.unwrap_or_else(|| RapierConfiguration {
+        timestep_mode: TimestepMode::Fixed {
+            dt: delta_time,
+            substeps: 1,
+        },
+        ..default()
+    });
+    simulate_step(
+        &mut world.context,
+        world.scale.to_physics_vec(config.gravity),
+        config.timestep_mode,
+        config.physics_pipeline_active,
+        physics_hooks,
+        delta_time,
+        &mut world.sim_to_render_time,
+        &mut world.sim_time,
+    );
+
+    // Every step is simulated, but only every `result_interval`-th one
+    // sends the state of the bodies back.
+    world.step_count += 1;
+    if world.step_count % u64::from(world.result_interval.max(1)) != 0 {
+        return Response::Stepped;
+    }
+
+    match epsilon {
+        Some(epsilon) => simulation_delta(
+            world.scale,
+            &world.context,
+            &world.overlap_sensors,
+            epsilon,
+            &mut world.sent_positions,
         ),
+        None => simulation_result(world.scale, &world.context, &world.overlap_sensors),
     }
 }
 
-fn simulate_latency(simulated_latency: SimulatedLatency) {
+/// Every connection gets its own generator so that its behavior only
+/// depends on the seed and the order in which it connected.
+fn connection_rng(seed: Option<u64>, connection_index: usize) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(connection_index as u64)),
+        None => StdRng::from_entropy(),
+    }
+}
+
+fn sample_latency(simulated_latency: SimulatedLatency, rng: &mut StdRng) -> Option<Duration> {
     let latency = match simulated_latency {
-        SimulatedLatency::None => return,
+        SimulatedLatency::None => return None,
         SimulatedLatency::Fixed(latency) => latency,
         SimulatedLatency::Random { min, mean } => {
-            let mut rng = thread_rng();
             let expovariate = -rng.gen::<f64>().ln() * (mean - min) as f64;
             (min as f64 + expovariate) as u64
         }
     };
+    Some(Duration::from_millis(latency))
+}
 
-    let latency = Duration::from_millis(latency);
+fn simulate_latency(simulated_latency: SimulatedLatency, rng: &mut StdRng) {
+    let Some(latency) = sample_latency(simulated_latency, rng) else {
+        return;
+    };
     println!("Simulated Latency: {:?}", latency);
     sleep(latency);
 }
 
 fn update_config(
-    new_config: RapierConfiguration,
+    new_config: SerializableRapierConfiguration,
     config: &mut Option<RapierConfiguration>,
+    scale: &mut ScaleCtx,
+    context: &mut RapierContext,
 ) -> Response {
-    *config = Some(new_config);
+    if let Some(physics_scale) = new_config.physics_scale {
+        if !(physics_scale.is_finite() && physics_scale > 0.0) {
+            return Response::Error(format!("Invalid physics scale {}", physics_scale));
+        }
+        // Existing bodies and shapes were converted with the old scale.
+        if physics_scale != scale.physics_scale
+            && (!context.bodies.is_empty() || !context.colliders.is_empty())
+        {
+            return Response::Error(format!(
+                "Physics scale can't change from {} to {} once the world has bodies or colliders",
+                scale.physics_scale, physics_scale
+            ));
+        }
+        *scale = ScaleCtx::new(physics_scale);
+    }
+
+    let default_prediction_distance = IntegrationParameters::default().prediction_distance;
+    context.integration_parameters.prediction_distance = new_config
+        .prediction_distance
+        .map(|distance| scale.to_physics_len(distance))
+        .unwrap_or(default_prediction_distance);
+
+    *config = Some(new_config.into());
     Response::ConfigUpdated
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_bodies(
     bodies: Vec<CreatedBody>,
+    peer_addr: SocketAddr,
+    max_bodies: Option<usize>,
+    scale: ScaleCtx,
     context: &mut RapierContext,
     entity2body: &mut HashMap<Entity, RigidBodyHandle>,
+    body_descriptions: &mut HashMap<u64, Vec<u8>>,
+    user_meta: &mut HashMap<u64, Vec<u8>>,
 ) -> Response {
     println!("Creating bodies");
+
+    if let Some(body) = bodies.iter().find(|body| {
+        body.user_meta
+            .as_ref()
+            .map_or(false, |meta| meta.len() > MAX_USER_META_LEN)
+    }) {
+        return Response::Error(format!(
+            "Metadata of body {} is larger than {} bytes",
+            body.id, MAX_USER_META_LEN
+        ));
+    }
+
+    // Creates are idempotent, so a batch whose response got lost can be sent again.
+    let descriptions: Vec<_> = bodies.iter().map(|body| serialize(body).unwrap()).collect();
+
+    if let Some(max_bodies) = max_bodies {
+        let new_bodies = bodies
+            .iter()
+            .filter(|body| !body_descriptions.contains_key(&body.id))
+            .count();
+        if context.bodies.len() + new_bodies > max_bodies {
+            if let Some(body) = bodies
+                .iter()
+                .find(|body| !body_descriptions.contains_key(&body.id))
+            {
+                lifecycle::emit(
+                    lifecycle::QUOTA_REJECTED,
+                    &[
+                        (lifecycle::PEER, &peer_addr),
+                        (lifecycle::KIND, &"max_bodies"),
+                        (lifecycle::ID, &body.id),
+                    ],
+                );
+            }
+            return Response::Error(format!(
+                "Body limit reached: {} bodies exist, {} requested, at most {} allowed",
+                context.bodies.len(),
+                new_bodies,
+                max_bodies
+            ));
+        }
+    }
+
     let mut rbs = vec![];
-    for body in bodies {
+    for (body, description) in bodies.into_iter().zip(descriptions) {
+        if let Some(handle) = entity2body.get(&Entity::from_bits(body.id)) {
+            // A different body under the same id only fails its own entry.
+            if matches!(body_descriptions.get(&body.id), Some(existing) if *existing != description)
+            {
+                println!(
+                    "DuplicateId: body {} already exists with a different description",
+                    body.id
+                );
+                rbs.push((body.id, RigidBodyHandle::invalid()));
+                continue;
+            }
+            println!("Body {} already exists", body.id);
+            rbs.push((body.id, *handle));
+            continue;
+        }
+
         let mut builder = RigidBodyBuilder::new(body.body.into());
 
         if let Some(transform) = body.transform {
-            builder = builder.position(transform);
+            builder = builder.position(scale.to_physics_iso(&transform));
+        }
+
+        if let Some(velocity) = body.velocity {
+            let (linvel, angvel) = scale.to_physics_velocity(velocity.into());
+            builder = builder.linvel(linvel).angvel(angvel);
+        }
+
+        if let Some(damping) = body.damping {
+            builder = builder
+                .linear_damping(damping.linear_damping)
+                .angular_damping(damping.angular_damping);
+        }
+
+        if let Some(gravity_scale) = body.gravity_scale {
+            builder = builder.gravity_scale(gravity_scale.0);
+        }
+
+        if let Some(locked_axes) = body.locked_axes {
+            builder = builder.locked_axes(locked_axes.into());
+        }
+
+        if let Some(ccd) = body.ccd {
+            builder = builder.ccd_enabled(ccd.enabled);
         }
 
         if let Some(mprops) = body.additional_mass_properties {
             builder = match mprops.into() {
                 AdditionalMassProperties::MassProperties(mprops) => {
-                    builder.additional_mass_properties(mprops.into_rapier(context.physics_scale()))
+                    builder.additional_mass_properties(scale.to_physics_mass_properties(mprops))
                 }
                 AdditionalMassProperties::Mass(mass) => builder.additional_mass(mass),
             };
@@ -258,6 +894,10 @@ fn create_bodies(
         let handle = context.bodies.insert(builder);
 
         entity2body.insert(Entity::from_bits(body.id), handle);
+        body_descriptions.insert(body.id, description);
+        if let Some(meta) = body.user_meta {
+            user_meta.insert(body.id, meta);
+        }
 
         rbs.push((body.id, handle));
     }
@@ -266,24 +906,63 @@ fn create_bodies(
 
 fn create_colliders(
     colliders: Vec<CreatedCollider>,
+    scale: ScaleCtx,
     context: &mut RapierContext,
     entity2body: &HashMap<Entity, RigidBodyHandle>,
+    entity2collider: &mut HashMap<Entity, ColliderHandle>,
+    collider_descriptions: &mut HashMap<u64, Vec<u8>>,
+    overlap_sensors: &mut Vec<(u64, ColliderHandle)>,
 ) -> Response {
     println!("Creating colliders");
+
+    let descriptions: Vec<_> = colliders
+        .iter()
+        .map(|collider| serialize(collider).unwrap())
+        .collect();
+
     let mut cols = vec![];
-    for collider in colliders {
-        let mut builder = ColliderBuilder::new(collider.shape.raw);
+    for (collider, description) in colliders.into_iter().zip(descriptions) {
+        if let Some(handle) = entity2collider.get(&Entity::from_bits(collider.id)) {
+            if matches!(collider_descriptions.get(&collider.id), Some(existing) if *existing != description)
+            {
+                println!(
+                    "DuplicateId: collider {} already exists with a different description",
+                    collider.id
+                );
+                cols.push((collider.id, ColliderHandle::invalid()));
+                continue;
+            }
+            println!("Collider {} already exists", collider.id);
+            cols.push((collider.id, *handle));
+            continue;
+        }
+
+        // A degenerate hull only fails its own collider, the rest of the batch is created.
+        let Some(shape) = collider.shape.clone().into_collider() else {
+            println!(
+                "Points of collider {} don't form a convex hull",
+                collider.id
+            );
+            cols.push((collider.id, ColliderHandle::invalid()));
+            continue;
+        };
+
+        let mut builder = ColliderBuilder::new(scale.to_physics_collider(&shape).raw);
 
         if let Some(mprops) = collider.mass_properties {
             builder = match mprops.into() {
                 ColliderMassProperties::Density(density) => builder.density(density),
                 ColliderMassProperties::Mass(mass) => builder.mass(mass),
                 ColliderMassProperties::MassProperties(mprops) => {
-                    builder.mass_properties(mprops.into_rapier(context.physics_scale()))
+                    builder.mass_properties(scale.to_physics_mass_properties(mprops))
                 }
             };
         }
 
+        if collider.sensor.is_some() {
+            builder = builder.sensor(true);
+        }
+
         if let Some(friction) = collider.friction {
             builder = builder
                 .friction(friction.coefficient)
@@ -298,67 +977,1870 @@ fn create_colliders(
 
         let body_entity = Entity::from_bits(collider.id);
         let body_handle = entity2body.get(&body_entity).copied();
-        let child_transform = Transform::default();
 
         builder = builder.user_data(collider.id.into());
 
         let handle = if let Some(body_handle) = body_handle {
-            builder = builder.position(transform_to_iso(&child_transform, context.physics_scale()));
+            builder = builder.position(Isometry::identity());
             context
                 .colliders
                 .insert_with_parent(builder, body_handle, &mut context.bodies)
         } else {
             let transform = collider.transform.unwrap_or_default();
-            builder = builder.position(transform);
+            builder = builder.position(scale.to_physics_iso(&transform));
             context.colliders.insert(builder)
         };
 
-        // entity2collider.insert(Entity::from_bits(collider.id), handle);
+        entity2collider.insert(Entity::from_bits(collider.id), handle);
+        collider_descriptions.insert(collider.id, description);
+
+        if collider.report_overlaps && collider.sensor.is_some() {
+            overlap_sensors.push((collider.id, handle));
+        }
 
         cols.push((collider.id, handle));
     }
     Response::ColliderHandles(cols)
 }
 
-fn simulate_step(
+fn create_joints(
+    joints: Vec<CreatedJoint>,
+    scale: ScaleCtx,
     context: &mut RapierContext,
-    gravity: Vect,
-    timestep_mode: TimestepMode,
-    physics_hooks: (),
-    delta_time: f32,
-    sim_to_render_time: &mut SimulationToRenderTime,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+    entity2joint: &mut HashMap<Entity, ImpulseJointHandle>,
+    joint_descriptions: &mut HashMap<u64, Vec<u8>>,
 ) -> Response {
-    println!("Simulating step");
+    println!("Creating joints");
+    let mut handles = vec![];
+    for joint in joints {
+        let description = serialize(&joint).unwrap();
+        if let Some(handle) = entity2joint.get(&Entity::from_bits(joint.id)) {
+            if matches!(joint_descriptions.get(&joint.id), Some(existing) if *existing != description)
+            {
+                println!(
+                    "DuplicateId: joint {} already exists with a different parent or data",
+                    joint.id
+                );
+                handles.push((joint.id, ImpulseJointHandle::invalid()));
+                continue;
+            }
+            println!("Joint {} already exists", joint.id);
+            handles.push((joint.id, *handle));
+            continue;
+        }
 
-    // Hack to get delta time into rapier
-    let now = Instant::now();
-    let then = now - Duration::from_secs_f32(delta_time);
-    let mut time = Time::new(then);
-    time.update_with_instant(then);
-    time.update_with_instant(now);
+        let parent = entity2body.get(&Entity::from_bits(joint.parent));
+        let child = entity2body.get(&Entity::from_bits(joint.id));
+        let (Some(parent), Some(child)) = (parent, child) else {
+            println!("Bodies of joint {} don't exist", joint.id);
+            handles.push((joint.id, ImpulseJointHandle::invalid()));
+            continue;
+        };
+
+        let data = scale.to_physics_joint(joint.joint.into());
+        let handle = context.impulse_joints.insert(*parent, *child, data, true);
+        entity2joint.insert(Entity::from_bits(joint.id), handle);
+        joint_descriptions.insert(joint.id, description);
+        handles.push((joint.id, handle));
+    }
+    Response::JointHandles(handles)
+}
+
+fn remove_joints(
+    ids: Vec<u64>,
+    context: &mut RapierContext,
+    entity2joint: &mut HashMap<Entity, ImpulseJointHandle>,
+    joint_descriptions: &mut HashMap<u64, Vec<u8>>,
+) -> Response {
+    println!("Removing joints");
+    let mut removed = vec![];
+    for id in ids {
+        joint_descriptions.remove(&id);
+        // Joints removed together with one of their bodies are already gone.
+        let Some(handle) = entity2joint.remove(&Entity::from_bits(id)) else {
+            continue;
+        };
+        if context.impulse_joints.remove(handle, true).is_some() {
+            removed.push(id);
+        }
+    }
+    Response::JointsRemoved(removed)
+}
+
+fn create_collider_from_cached_mesh(
+    id: u64,
+    hash: ContentHash,
+    transform: Option<Isometry<Real>>,
+    fallback: Option<SerializableShape>,
+    world: &mut PhysicsWorld,
+) -> Response {
+    println!("Creating collider {} from cached mesh {}", id, hash);
+
+    let cached = mesh_cache::global().get(&hash);
+    let (shape, upload) = match (cached, fallback) {
+        (Some(shape), _) => (shape, false),
+        (None, Some(fallback)) => {
+            if fallback.content_hash() != hash {
+                return Response::Error(format!(
+                    "Fallback shape of collider {} doesn't match hash {}",
+                    id, hash
+                ));
+            }
+            (fallback, true)
+        }
+        (None, None) => return Response::MeshCacheMiss { id, hash },
+    };
 
-    context.step_simulation(
-        gravity,
-        timestep_mode,
-        None,
-        &physics_hooks,
-        &time,
-        sim_to_render_time,
-        None,
+    let collider = CreatedCollider {
+        id,
+        shape: shape.clone(),
+        transform,
+        sensor: None,
+        mass_properties: None,
+        friction: None,
+        restitution: None,
+        report_overlaps: false,
+    };
+    let response = create_colliders(
+        vec![collider],
+        world.scale,
+        &mut world.context,
+        &world.entity2body,
+        &mut world.entity2collider,
+        &mut world.collider_descriptions,
+        &mut world.overlap_sensors,
     );
 
-    let scale = context.physics_scale();
+    // Only shapes the server could build are worth keeping.
+    // Keyed by the hash computed here, not the one the client sent.
+    if upload && matches!(response, Response::ColliderHandles(_)) {
+        mesh_cache::global().insert(shape);
+    }
+    response
+}
 
-    let mut results = HashMap::new();
+fn remove_bodies(
+    ids: Vec<u64>,
+    context: &mut RapierContext,
+    entity2body: &mut HashMap<Entity, RigidBodyHandle>,
+    entity2collider: &mut HashMap<Entity, ColliderHandle>,
+    body_descriptions: &mut HashMap<u64, Vec<u8>>,
+    collider_descriptions: &mut HashMap<u64, Vec<u8>>,
+    user_meta: &mut HashMap<u64, Vec<u8>>,
+    overlap_sensors: &mut Vec<(u64, ColliderHandle)>,
+) -> Response {
+    println!("Removing bodies");
+    let mut removed = vec![];
+    for id in ids {
+        // Forget the id even if the body is already gone, so a recycled
+        // entity can be registered again.
+        body_descriptions.remove(&id);
+        user_meta.remove(&id);
+        let Some(handle) = entity2body.remove(&Entity::from_bits(id)) else {
+            continue;
+        };
 
-    for (handle, rb) in context.bodies.iter() {
-        let transform = utils::iso_to_transform(rb.position(), scale);
-        let velocity = Velocity {
-            linvel: (rb.linvel() * scale).into(),
-            angvel: (*rb.angvel()).into(),
+        // The attached colliders go with the body, forget them as well.
+        if let Some(rb) = context.bodies.get(handle) {
+            for collider in rb.colliders() {
+                let collider_id = context.colliders[*collider].user_data as u64;
+                entity2collider.remove(&Entity::from_bits(collider_id));
+                collider_descriptions.remove(&collider_id);
+                overlap_sensors.retain(|(_, sensor)| sensor != collider);
+            }
+        }
+
+        context.bodies.remove(
+            handle,
+            &mut context.islands,
+            &mut context.colliders,
+            &mut context.impulse_joints,
+            &mut context.multibody_joints,
+            true,
+        );
+        removed.push(id);
+    }
+    Response::BodiesRemoved(removed)
+}
+
+fn remove_colliders(
+    ids: Vec<u64>,
+    context: &mut RapierContext,
+    entity2collider: &mut HashMap<Entity, ColliderHandle>,
+    collider_descriptions: &mut HashMap<u64, Vec<u8>>,
+    overlap_sensors: &mut Vec<(u64, ColliderHandle)>,
+) -> Response {
+    println!("Removing colliders");
+    let mut removed = vec![];
+    for id in ids {
+        collider_descriptions.remove(&id);
+        // Colliders removed together with their body are already gone.
+        let Some(handle) = entity2collider.remove(&Entity::from_bits(id)) else {
+            continue;
+        };
+        overlap_sensors.retain(|(_, sensor)| *sensor != handle);
+
+        if context
+            .colliders
+            .remove(handle, &mut context.islands, &mut context.bodies, true)
+            .is_some()
+        {
+            removed.push(id);
+        }
+    }
+    Response::CollidersRemoved(removed)
+}
+
+fn wake_bodies(
+    ids: Vec<u64>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Waking bodies");
+    for id in ids {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.wake_up(true);
+        }
+    }
+    Response::BodiesWoken
+}
+
+fn set_user_meta(
+    metas: Vec<(u64, Option<Vec<u8>>)>,
+    user_meta: &mut HashMap<u64, Vec<u8>>,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting user metadata");
+    if let Some((id, _)) = metas.iter().find(|(_, meta)| {
+        meta.as_ref()
+            .map_or(false, |meta| meta.len() > MAX_USER_META_LEN)
+    }) {
+        return Response::Error(format!(
+            "Metadata of body {} is larger than {} bytes",
+            id, MAX_USER_META_LEN
+        ));
+    }
+
+    for (id, meta) in metas {
+        if !entity2body.contains_key(&Entity::from_bits(id)) {
+            continue;
+        }
+        match meta {
+            Some(meta) => user_meta.insert(id, meta),
+            None => user_meta.remove(&id),
         };
+    }
+    Response::UserMetaSet
+}
+
+fn get_user_meta(ids: Vec<u64>, user_meta: &HashMap<u64, Vec<u8>>) -> Response {
+    println!("Getting user metadata");
+    let metas = ids
+        .into_iter()
+        .map(|id| (id, user_meta.get(&id).cloned()))
+        .collect();
+    Response::UserMeta(metas)
+}
 
-        results.insert(handle, (transform, velocity));
+fn set_ccd(
+    changes: Vec<(u64, bool)>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting CCD");
+    for (id, enabled) in changes {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.enable_ccd(enabled);
+        }
+    }
+    Response::CcdSet
+}
+
+fn set_bodies_frozen(
+    changes: Vec<(u64, bool)>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+    frozen_bodies: &mut HashMap<u64, FrozenBody>,
+) -> Response {
+    println!("Setting frozen bodies");
+    let mut changed = vec![];
+    for (id, frozen) in changes {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) else {
+            continue;
+        };
+
+        if frozen {
+            if frozen_bodies.contains_key(&id) {
+                continue;
+            }
+            // Fixed bodies lose their velocity, keep it to resume where they left off.
+            frozen_bodies.insert(
+                id,
+                FrozenBody {
+                    body_type: rb.body_type(),
+                    linvel: *rb.linvel(),
+                    angvel: *rb.angvel(),
+                },
+            );
+            rb.set_body_type(RigidBodyType::Fixed, true);
+        } else {
+            let Some(state) = frozen_bodies.remove(&id) else {
+                continue;
+            };
+            rb.set_body_type(state.body_type, true);
+            rb.set_linvel(state.linvel, true);
+            rb.set_angvel(state.angvel, true);
+        }
+        changed.push(id);
+    }
+    Response::BodiesFrozen(changed)
+}
+
+fn apply_forces(
+    forces: Vec<(u64, SerializableExternalForce)>,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Applying forces");
+    for (id, force) in forces {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.reset_forces(true);
+            rb.reset_torques(true);
+            rb.add_force(scale.to_physics_force(force.force), true);
+            rb.add_torque(scale.to_physics_torque(force.torque), true);
+        }
+    }
+    Response::ForcesApplied
+}
+
+fn apply_impulses(
+    impulses: Vec<(u64, SerializableExternalImpulse)>,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Applying impulses");
+    for (id, impulse) in impulses {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.apply_impulse(scale.to_physics_force(impulse.impulse), true);
+            rb.apply_torque_impulse(scale.to_physics_torque(impulse.torque_impulse), true);
+        }
+    }
+    Response::ImpulsesApplied
+}
+
+fn set_velocities(
+    velocities: Vec<(u64, SerializableVelocity)>,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting velocities");
+    for (id, velocity) in velocities {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            let (linvel, angvel) = scale.to_physics_velocity(velocity.into());
+            rb.set_linvel(linvel, true);
+            rb.set_angvel(angvel, true);
+        }
+    }
+    Response::VelocitiesSet
+}
+
+fn set_damping(
+    damping: Vec<(u64, SerializableDamping)>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting damping");
+    for (id, damping) in damping {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.set_linear_damping(damping.linear_damping);
+            rb.set_angular_damping(damping.angular_damping);
+        }
+    }
+    Response::DampingSet
+}
+
+fn set_gravity_scale(
+    gravity_scales: Vec<(u64, f32)>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting gravity scales");
+    for (id, gravity_scale) in gravity_scales {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            // Wakes the body, a sleeping pickup would otherwise stay put.
+            rb.set_gravity_scale(gravity_scale, true);
+        }
+    }
+    Response::GravityScaleSet
+}
+
+fn set_locked_axes(
+    locked_axes: Vec<(u64, SerializableLockedAxes)>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting locked axes");
+    for (id, locked_axes) in locked_axes {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.set_locked_axes(locked_axes.into(), true);
+        }
+    }
+    Response::LockedAxesSet
+}
+
+fn set_body_positions(
+    positions: Vec<(u64, Isometry<Real>)>,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Setting body positions");
+    for (id, position) in positions {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            let position = scale.to_physics_iso(&position);
+            if rb.is_kinematic() {
+                rb.set_next_kinematic_position(position);
+            } else {
+                rb.set_position(position, true);
+            }
+        }
+    }
+    Response::BodyPositionsSet
+}
+
+fn clear_forces(
+    ids: Vec<u64>,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Clearing forces");
+    for id in ids {
+        let handle = entity2body.get(&Entity::from_bits(id));
+        if let Some(rb) = handle.and_then(|handle| context.bodies.get_mut(*handle)) {
+            rb.reset_forces(true);
+            rb.reset_torques(true);
+        }
+    }
+    Response::ForcesCleared
+}
+
+fn detach_colliders(
+    ids: Vec<u64>,
+    context: &mut RapierContext,
+    entity2collider: &HashMap<Entity, ColliderHandle>,
+) -> Response {
+    println!("Detaching colliders");
+    let mut detached = vec![];
+    for id in ids {
+        let Some(&handle) = entity2collider.get(&Entity::from_bits(id)) else {
+            continue;
+        };
+        let Some(parent) = context
+            .colliders
+            .get(handle)
+            .and_then(|collider| collider.parent())
+        else {
+            continue;
+        };
+
+        // Without a parent the collider stays at its current world position and
+        // keeps its handle. The former parent recomputes its mass on the next step.
+        context
+            .colliders
+            .set_parent(handle, None, &mut context.bodies);
+        if let Some(rb) = context.bodies.get_mut(parent) {
+            rb.wake_up(true);
+        }
+
+        detached.push(id);
+    }
+    Response::CollidersDetached(detached)
+}
+
+fn get_diagnostics(context: &RapierContext) -> Response {
+    println!("Getting diagnostics");
+    // The narrow phase keeps one pair per overlapping AABB pair found by the broad phase.
+    let narrow_phase = &context.narrow_phase;
+    let broad_phase_pairs =
+        narrow_phase.contact_pairs().count() + narrow_phase.intersection_pairs().count();
+    let contact_manifolds = narrow_phase
+        .contact_pairs()
+        .map(|pair| pair.manifolds.len())
+        .sum::<usize>();
+
+    Response::Diagnostics(Diagnostics {
+        bodies: context.bodies.len() as u32,
+        colliders: context.colliders.len() as u32,
+        broad_phase_pairs: broad_phase_pairs as u32,
+        contact_manifolds: contact_manifolds as u32,
+    })
+}
+
+/// Groups the active dynamic bodies into islands by following their active contacts
+/// and joints.
+fn get_islands(context: &RapierContext) -> Response {
+    println!("Getting islands");
+    let active = context.islands.active_dynamic_bodies();
+    let index: HashMap<RigidBodyHandle, usize> = active
+        .iter()
+        .enumerate()
+        .map(|(i, handle)| (*handle, i))
+        .collect();
+
+    fn find(roots: &mut [usize], mut i: usize) -> usize {
+        while roots[i] != i {
+            roots[i] = roots[roots[i]];
+            i = roots[i];
+        }
+        i
+    }
+
+    let mut roots: Vec<usize> = (0..active.len()).collect();
+    let parent_index = |handle: ColliderHandle| {
+        context
+            .colliders
+            .get(handle)
+            .and_then(|collider| collider.parent())
+            .and_then(|parent| index.get(&parent).copied())
+    };
+    for pair in context.narrow_phase.contact_pairs() {
+        if !pair.has_any_active_contact {
+            continue;
+        }
+        // Contacts with fixed or sleeping bodies don't join islands.
+        if let (Some(i1), Some(i2)) = (parent_index(pair.collider1), parent_index(pair.collider2)) {
+            let (root1, root2) = (find(&mut roots, i1), find(&mut roots, i2));
+            roots[root1] = root2;
+        }
+    }
+    for (_, joint) in context.impulse_joints.iter() {
+        if let (Some(i1), Some(i2)) = (index.get(&joint.body1), index.get(&joint.body2)) {
+            let (root1, root2) = (find(&mut roots, *i1), find(&mut roots, *i2));
+            roots[root1] = root2;
+        }
+    }
+
+    let mut islands: HashMap<usize, IslandInfo> = HashMap::new();
+    for (i, handle) in active.iter().enumerate() {
+        let root = find(&mut roots, i);
+        let rb = &context.bodies[*handle];
+        let island = islands.entry(root).or_insert_with(|| IslandInfo {
+            bodies: vec![],
+            kinetic_energy: 0.0,
+        });
+        island.bodies.push(rb.user_data as u64);
+        island.kinetic_energy += rb.kinetic_energy();
+    }
+
+    // The active set and the map iterate in no particular order, sort by body id.
+    let mut islands: Vec<IslandInfo> = islands.into_values().collect();
+    for island in &mut islands {
+        island.bodies.sort_unstable();
+    }
+    islands.sort_unstable_by_key(|island| island.bodies[0]);
+    Response::Islands(islands)
+}
+
+fn update_query_pipeline(context: &mut RapierContext) {
+    context
+        .query_pipeline
+        .update(&context.bodies, &context.colliders);
+}
+
+fn radial_impulse(
+    center: Vect,
+    radius: f32,
+    strength: f32,
+    falloff: bool,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+) -> Response {
+    println!("Applying radial impulse");
+    update_query_pipeline(context);
+
+    let center: Point<Real> = scale.to_physics_vec(center).into();
+    let radius = scale.to_physics_len(radius);
+
+    let mut bodies = HashSet::new();
+    let filter = QueryFilter::only_dynamic().exclude_sensors();
+    context.query_pipeline.intersections_with_shape(
+        &context.bodies,
+        &context.colliders,
+        &Isometry::translation(center.x, center.y, center.z),
+        &Ball::new(radius),
+        filter,
+        |handle| {
+            if let Some(parent) = context.colliders[handle].parent() {
+                bodies.insert(parent);
+            }
+            true
+        },
+    );
+
+    let mut applied = vec![];
+    for handle in bodies {
+        let rb = &mut context.bodies[handle];
+        let offset = *rb.center_of_mass() - center;
+        let distance = offset.norm();
+        // A body right at the center has no outward direction, push it up.
+        let direction = if distance > Real::EPSILON {
+            offset / distance
+        } else {
+            Vector::y()
+        };
+        let magnitude = if falloff {
+            strength * (1.0 - distance / radius).max(0.0)
+        } else {
+            strength
+        };
+        rb.apply_impulse(
+            scale.to_physics_force(Vect::from(direction) * magnitude),
+            true,
+        );
+        applied.push(rb.user_data as u64);
+    }
+    // The set iterates in hash order, keep the response stable.
+    applied.sort_unstable();
+    Response::RadialImpulseApplied(applied)
+}
+
+/// Metadata of the body the collider is attached to.
+fn hit_user_meta(
+    collider: ColliderHandle,
+    context: &RapierContext,
+    user_meta: &HashMap<u64, Vec<u8>>,
+) -> Option<Vec<u8>> {
+    let body = context.colliders.get(collider)?.parent()?;
+    let id = context.bodies.get(body)?.user_data as u64;
+    user_meta.get(&id).cloned()
+}
+
+fn point_in_solid(
+    point: Vect,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    user_meta: &HashMap<u64, Vec<u8>>,
+) -> Response {
+    println!("Checking point in solid");
+    update_query_pipeline(context);
+
+    let point = scale.to_physics_vec(point).into();
+    let filter = QueryFilter::default().exclude_sensors();
+
+    // With `solid` set, a point inside a shape projects onto itself and is
+    // flagged as inside, otherwise it projects onto the closest surface.
+    let collider = context
+        .query_pipeline
+        .project_point(&context.bodies, &context.colliders, &point, true, filter)
+        .filter(|(_, projection)| projection.is_inside)
+        .map(|(handle, _)| {
+            (
+                context.colliders[handle].user_data as u64,
+                hit_user_meta(handle, context, user_meta),
+            )
+        });
+
+    Response::PointInSolid(collider)
+}
+
+fn cast_ray(
+    origin: Vect,
+    dir: Vect,
+    max_toi: f32,
+    solid: bool,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    user_meta: &HashMap<u64, Vec<u8>>,
+) -> Response {
+    println!("Casting ray");
+    update_query_pipeline(context);
+
+    // Scaling the direction as well keeps the time of impact the same in both spaces.
+    let ray = Ray::new(
+        scale.to_physics_vec(origin).into(),
+        scale.to_physics_vec(dir).into(),
+    );
+    let filter = QueryFilter::default().exclude_sensors();
+
+    let hit = context
+        .query_pipeline
+        .cast_ray_and_get_normal(
+            &context.bodies,
+            &context.colliders,
+            &ray,
+            max_toi,
+            solid,
+            filter,
+        )
+        .map(|(handle, intersection)| {
+            (
+                context.colliders[handle].user_data as u64,
+                intersection.toi,
+                intersection.normal.into(),
+                hit_user_meta(handle, context, user_meta),
+            )
+        });
+
+    Response::RayHit(hit)
+}
+
+fn grounded_check(
+    checks: Vec<(u64, Vect, f32)>,
+    scale: ScaleCtx,
+    context: &mut RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+    user_meta: &HashMap<u64, Vec<u8>>,
+) -> Response {
+    println!("Checking grounded bodies");
+    update_query_pipeline(context);
+
+    let results = checks
+        .into_iter()
+        .map(|(id, down, distance)| {
+            let ground = entity2body.get(&Entity::from_bits(id)).and_then(|handle| {
+                let aabb = context
+                    .bodies
+                    .get(*handle)?
+                    .colliders()
+                    .iter()
+                    .filter_map(|collider| context.colliders.get(*collider))
+                    .map(|collider| collider.compute_aabb())
+                    .reduce(|aabb, other| aabb.merged(&other))?;
+
+                // Start at the center of the AABB and extend the ray past its
+                // support point in the down direction by the requested distance.
+                let dir: Vector<Real> = down.normalize_or_zero().into();
+                let half_extents = aabb.half_extents();
+                let support = dir.abs().dot(&half_extents);
+                let ray = query::Ray::new(aabb.center(), dir);
+                let filter = QueryFilter::default()
+                    .exclude_sensors()
+                    .exclude_rigid_body(*handle);
+
+                context.query_pipeline.cast_ray(
+                    &context.bodies,
+                    &context.colliders,
+                    &ray,
+                    support + scale.to_physics_len(distance),
+                    true,
+                    filter,
+                )
+            });
+            match ground {
+                Some((collider, _)) => (id, true, hit_user_meta(collider, context, user_meta)),
+                None => (id, false, None),
+            }
+        })
+        .collect();
+
+    Response::Grounded(results)
+}
+
+fn body_motion<'a>(
+    id: u64,
+    context: &'a RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Option<(&'a Isometry<Real>, Vector<Real>, &'a dyn Shape)> {
+    let handle = entity2body.get(&Entity::from_bits(id))?;
+    let rb = context.bodies.get(*handle)?;
+    let collider = context.colliders.get(*rb.colliders().first()?)?;
+    Some((collider.position(), *rb.linvel(), collider.shape()))
+}
+
+fn time_of_impact(
+    id1: u64,
+    id2: u64,
+    context: &RapierContext,
+    entity2body: &HashMap<Entity, RigidBodyHandle>,
+) -> Response {
+    println!("Computing time of impact");
+    let toi = body_motion(id1, context, entity2body)
+        .zip(body_motion(id2, context, entity2body))
+        .and_then(|((pos1, vel1, shape1), (pos2, vel2, shape2))| {
+            query::time_of_impact(pos1, &vel1, shape1, pos2, &vel2, shape2, TOI_HORIZON, true)
+                .ok()
+                .flatten()
+        })
+        .map(|toi| toi.toi);
+    Response::Toi(toi)
+}
+
+fn simulate_step(
+    context: &mut RapierContext,
+    gravity: Vect,
+    timestep_mode: TimestepMode,
+    physics_pipeline_active: bool,
+    physics_hooks: (),
+    delta_time: f32,
+    sim_to_render_time: &mut SimulationToRenderTime,
+    sim_time: &mut f32,
+) {
+    println!("Simulating step");
+
+    // Hack to get delta time into rapier
+    let now = Instant::now();
+    let then = now - Duration::from_secs_f32(delta_time);
+    let mut time = Time::new(then);
+    time.update_with_instant(then);
+    time.update_with_instant(now);
+
+    // A paused pipeline still reports the current state of the bodies.
+    if physics_pipeline_active {
+        let accumulated = sim_to_render_time.diff;
+        context.step_simulation(
+            gravity,
+            timestep_mode,
+            None,
+            &physics_hooks,
+            &time,
+            sim_to_render_time,
+            None,
+        );
+        *sim_time += simulated_time(timestep_mode, delta_time, accumulated, sim_to_render_time);
+    }
+}
+
+/// Time the substeps of `step_simulation` covered, which is not the requested delta
+/// when the step is fixed, capped or interpolated.
+fn simulated_time(
+    timestep_mode: TimestepMode,
+    delta_time: f32,
+    accumulated: f32,
+    sim_to_render_time: &SimulationToRenderTime,
+) -> f32 {
+    match timestep_mode {
+        TimestepMode::Fixed { dt, .. } => dt,
+        TimestepMode::Variable {
+            max_dt, time_scale, ..
+        } => (delta_time * time_scale).min(max_dt),
+        TimestepMode::Interpolated { dt, time_scale, .. } => {
+            // Every step takes `dt` off the accumulated time.
+            let steps = ((accumulated + delta_time - sim_to_render_time.diff) / dt).round();
+            steps * dt * time_scale
+        }
+    }
+}
+
+fn simulation_result(
+    scale: ScaleCtx,
+    context: &RapierContext,
+    overlap_sensors: &[(u64, ColliderHandle)],
+) -> Response {
+    let (results, overlaps) = body_states(scale, context, overlap_sensors, |_, _| true);
+    Response::SimulationResult(results, overlaps)
+}
+
+fn simulation_delta(
+    scale: ScaleCtx,
+    context: &RapierContext,
+    overlap_sensors: &[(u64, ColliderHandle)],
+    epsilon: Real,
+    sent_positions: &mut HashMap<RigidBodyHandle, (Isometry<Real>, bool)>,
+) -> Response {
+    sent_positions.retain(|handle, _| context.bodies.contains(*handle));
+
+    let (results, overlaps) = body_states(scale, context, overlap_sensors, |handle, rb| {
+        let position = rb.position();
+        let sleeping = rb.is_sleeping();
+        // A body that fell asleep is sent once more, so the client sees it stop.
+        let changed = sent_positions
+            .get(&handle)
+            .map_or(true, |(sent, sent_sleeping)| {
+                let distance = (position.translation.vector - sent.translation.vector).norm();
+                scale.to_render_len(distance) > epsilon
+                    || position.rotation.angle_to(&sent.rotation) > epsilon
+                    || sleeping != *sent_sleeping
+            });
+        if sleeping && !changed {
+            return false;
+        }
+        sent_positions.insert(handle, (*position, sleeping));
+        true
+    });
+    Response::SimulationDelta(results, overlaps)
+}
+
+/// States of the bodies `include` accepts and the overlap counts of the sensors, sorted by id.
+fn body_states(
+    scale: ScaleCtx,
+    context: &RapierContext,
+    overlap_sensors: &[(u64, ColliderHandle)],
+    mut include: impl FnMut(RigidBodyHandle, &bevy_rapier3d::rapier::dynamics::RigidBody) -> bool,
+) -> (Vec<(u64, Transform, Velocity)>, Vec<(u64, u32)>) {
+    let mut results = vec![];
+
+    for (handle, rb) in context.bodies.iter() {
+        if !include(handle, rb) {
+            continue;
+        }
+        let transform = scale.to_render_transform(rb.position());
+        let velocity = scale.to_render_velocity(*rb.linvel(), *rb.angvel());
+
+        results.push((rb.user_data as u64, transform, velocity));
+    }
+
+    // The body set iterates in arena order, which depends on the removal history,
+    // so sort by id to keep the response stable.
+    results.sort_unstable_by_key(|(id, _, _)| *id);
+
+    let mut overlaps: Vec<_> = overlap_sensors
+        .iter()
+        .map(|(id, handle)| {
+            let count = context
+                .narrow_phase
+                .intersections_with(*handle)
+                .filter(|(_, _, intersecting)| *intersecting)
+                .count();
+            (*id, count as u32)
+        })
+        .collect();
+    overlaps.sort_unstable_by_key(|(id, _)| *id);
+
+    (results, overlaps)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::scale::transform_to_iso;
+    use shared::serializable::SerializableGenericJoint;
+
+    use super::*;
+
+    fn collider(id: u64, shape: SerializableShape) -> CreatedCollider {
+        CreatedCollider {
+            id,
+            shape,
+            transform: None,
+            sensor: None,
+            mass_properties: None,
+            friction: None,
+            restitution: None,
+            report_overlaps: false,
+        }
+    }
+
+    /// A dynamic body at the origin.
+    fn body(id: u64) -> CreatedBody {
+        CreatedBody {
+            id,
+            body: RigidBody::Dynamic,
+            transform: None,
+            additional_mass_properties: None,
+            velocity: None,
+            damping: None,
+            gravity_scale: None,
+            locked_axes: None,
+            ccd: None,
+            user_meta: None,
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    /// No latency, no body limit and no seed.
+    fn config() -> ServerConfig {
+        ServerConfig {
+            simulated_latency: SimulatedLatency::None,
+            max_bodies: None,
+            seed: None,
+        }
+    }
+
+    fn create(colliders: Vec<CreatedCollider>, world: &mut PhysicsWorld) -> Response {
+        create_colliders(
+            colliders,
+            world.scale,
+            &mut world.context,
+            &world.entity2body,
+            &mut world.entity2collider,
+            &mut world.collider_descriptions,
+            &mut world.overlap_sensors,
+        )
+    }
+
+    #[test]
+    fn degenerate_hull_only_fails_its_own_collider() {
+        let cube = (0..8)
+            .map(|i| Vect::new((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32))
+            .collect();
+        let coplanar = vec![Vect::ZERO, Vect::X, Vect::Z, Vect::new(1.0, 0.0, 1.0)];
+
+        let mut world = PhysicsWorld::default();
+        let response = create(
+            vec![
+                collider(1, SerializableShape::ConvexHull(cube)),
+                collider(2, SerializableShape::ConvexHull(coplanar)),
+            ],
+            &mut world,
+        );
+
+        let Response::ColliderHandles(handles) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(handles.len(), 2);
+        assert_eq!(handles[0].0, 1);
+        assert!(world.context.colliders.get(handles[0].1).is_some());
+        assert_eq!(handles[1], (2, ColliderHandle::invalid()));
+    }
+
+    #[test]
+    fn delta_includes_the_step_a_body_falls_asleep() {
+        let mut world = PhysicsWorld::default();
+        let handle = world
+            .context
+            .bodies
+            .insert(RigidBodyBuilder::dynamic().user_data(7));
+        let delta = |world: &mut PhysicsWorld| {
+            let response = simulation_delta(
+                world.scale,
+                &world.context,
+                &world.overlap_sensors,
+                1e-3,
+                &mut world.sent_positions,
+            );
+            let Response::SimulationDelta(results, _) = response else {
+                panic!("unexpected response {:?}", response);
+            };
+            results.iter().map(|(id, _, _)| *id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(delta(&mut world), [7]);
+        world.context.bodies[handle].sleep();
+        assert_eq!(delta(&mut world), [7]);
+        assert!(delta(&mut world).is_empty());
+    }
+
+    #[test]
+    fn sim_time_counts_simulated_substeps() {
+        let mut context = RapierContext::default();
+        let mut sim_to_render_time = SimulationToRenderTime::default();
+        let mut sim_time = 0.0;
+        let fixed = TimestepMode::Fixed {
+            dt: 1.0 / 60.0,
+            substeps: 4,
+        };
+
+        // A fixed step simulates `dt`, however long the frame took.
+        simulate_step(
+            &mut context,
+            Vect::ZERO,
+            fixed,
+            true,
+            (),
+            0.1,
+            &mut sim_to_render_time,
+            &mut sim_time,
+        );
+        assert!((sim_time - 1.0 / 60.0).abs() < 1e-6);
+
+        let interpolated = TimestepMode::Interpolated {
+            dt: 0.01,
+            time_scale: 1.0,
+            substeps: 1,
+        };
+        sim_time = 0.0;
+        simulate_step(
+            &mut context,
+            Vect::ZERO,
+            interpolated,
+            true,
+            (),
+            0.035,
+            &mut sim_to_render_time,
+            &mut sim_time,
+        );
+        assert!((sim_time - 0.04).abs() < 1e-4);
+    }
+
+    #[test]
+    fn duplicate_id_only_fails_its_own_entry() {
+        let body = |id, height| CreatedBody {
+            transform: Some(Isometry::translation(0.0, height, 0.0)),
+            ..body(id)
+        };
+        let mut world = PhysicsWorld::default();
+        let create_batch = |bodies, world: &mut PhysicsWorld| {
+            create_bodies(
+                bodies,
+                peer(),
+                None,
+                world.scale,
+                &mut world.context,
+                &mut world.entity2body,
+                &mut world.body_descriptions,
+                &mut world.user_meta,
+            )
+        };
+
+        let Response::RigidBodyHandles(created) = create_batch(vec![body(1, 1.0)], &mut world)
+        else {
+            panic!("bodies weren't created");
+        };
+        let response = create_batch(vec![body(1, 2.0), body(2, 1.0)], &mut world);
+        let Response::RigidBodyHandles(handles) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(handles[0], (1, RigidBodyHandle::invalid()));
+        assert_eq!(handles[1].0, 2);
+        assert!(world.context.bodies.get(handles[1].1).is_some());
+        assert_eq!(world.entity2body[&Entity::from_bits(1)], created[0].1);
+
+        let ball = SerializableShape::Collider(Collider::ball(0.5));
+        let cuboid = SerializableShape::Collider(Collider::cuboid(0.5, 0.5, 0.5));
+        create(vec![collider(1, ball.clone())], &mut world);
+        let response = create(vec![collider(1, cuboid), collider(2, ball)], &mut world);
+        let Response::ColliderHandles(handles) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(handles[0], (1, ColliderHandle::invalid()));
+        assert!(world.context.colliders.get(handles[1].1).is_some());
+    }
+
+    #[test]
+    fn changed_joint_gets_an_invalid_handle() {
+        let mut world = PhysicsWorld::default();
+        for id in [1, 2] {
+            let handle = world.context.bodies.insert(RigidBodyBuilder::dynamic());
+            world.entity2body.insert(Entity::from_bits(id), handle);
+        }
+        let joint = |anchor| CreatedJoint {
+            id: 2,
+            parent: 1,
+            joint: SerializableGenericJoint(
+                FixedJointBuilder::new()
+                    .local_anchor1(anchor)
+                    .build()
+                    .into(),
+            ),
+        };
+        let create = |joint, world: &mut PhysicsWorld| {
+            let response = create_joints(
+                vec![joint],
+                world.scale,
+                &mut world.context,
+                &world.entity2body,
+                &mut world.entity2joint,
+                &mut world.joint_descriptions,
+            );
+            let Response::JointHandles(handles) = response else {
+                panic!("unexpected response {:?}", response);
+            };
+            handles[0].1
+        };
+
+        let handle = create(joint(Vect::X), &mut world);
+        assert_ne!(handle, ImpulseJointHandle::invalid());
+        assert_eq!(create(joint(Vect::X), &mut world), handle);
+        assert_eq!(
+            create(joint(Vect::Y), &mut world),
+            ImpulseJointHandle::invalid()
+        );
+        assert_eq!(world.context.impulse_joints.len(), 1);
+    }
+
+    #[test]
+    fn radial_impulse_falls_off_with_distance() {
+        let mut context = RapierContext::default();
+        for (id, x) in [(2, 3.0), (1, 1.0)] {
+            let handle = context.bodies.insert(
+                RigidBodyBuilder::dynamic()
+                    .translation(Vector::new(x, 0.0, 0.0))
+                    .user_data(id),
+            );
+            context.colliders.insert_with_parent(
+                ColliderBuilder::ball(0.5),
+                handle,
+                &mut context.bodies,
+            );
+        }
+
+        let response = radial_impulse(
+            Vect::ZERO,
+            5.0,
+            10.0,
+            true,
+            ScaleCtx::default(),
+            &mut context,
+        );
+        let Response::RadialImpulseApplied(applied) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(applied, [1, 2]);
+
+        let speed = |id| {
+            let (_, rb) = context
+                .bodies
+                .iter()
+                .find(|(_, rb)| rb.user_data == id)
+                .unwrap();
+            rb.linvel().norm()
+        };
+        assert!(speed(1) > speed(2));
+        assert!(speed(2) > 0.0);
+    }
+
+    #[test]
+    fn joints_join_islands() {
+        let mut context = RapierContext::default();
+        let mut bodies = vec![];
+        for (id, x) in [(3, 0.0), (1, 10.0), (2, 20.0)] {
+            bodies.push(
+                context.bodies.insert(
+                    RigidBodyBuilder::dynamic()
+                        .translation(Vector::new(x, 0.0, 0.0))
+                        .user_data(id),
+                ),
+            );
+        }
+        context.impulse_joints.insert(
+            bodies[0],
+            bodies[2],
+            GenericJoint::from(FixedJointBuilder::new()).raw,
+            true,
+        );
+        context.step_simulation(
+            Vect::ZERO,
+            TimestepMode::Fixed {
+                dt: 1.0 / 60.0,
+                substeps: 1,
+            },
+            None,
+            &(),
+            &Time::default(),
+            &mut SimulationToRenderTime::default(),
+            None,
+        );
+
+        let response = get_islands(&context);
+        let Response::Islands(islands) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        let bodies: Vec<_> = islands.iter().map(|island| island.bodies.clone()).collect();
+        assert_eq!(bodies, [vec![1], vec![2, 3]]);
+    }
+
+    #[test]
+    fn query_hits_carry_user_meta() {
+        let mut world = PhysicsWorld::default();
+        let body = world
+            .context
+            .bodies
+            .insert(RigidBodyBuilder::fixed().user_data(1));
+        world.context.colliders.insert_with_parent(
+            ColliderBuilder::cuboid(5.0, 0.5, 5.0).user_data(2),
+            body,
+            &mut world.context.bodies,
+        );
+        world.user_meta.insert(1, b"metal".to_vec());
+
+        let response = cast_ray(
+            Vect::Y * 5.0,
+            Vect::NEG_Y,
+            10.0,
+            true,
+            world.scale,
+            &mut world.context,
+            &world.user_meta,
+        );
+        let Response::RayHit(Some((collider, _, _, meta))) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(collider, 2);
+        assert_eq!(meta.as_deref(), Some(&b"metal"[..]));
+
+        let response = point_in_solid(
+            Vect::ZERO,
+            world.scale,
+            &mut world.context,
+            &world.user_meta,
+        );
+        let Response::PointInSolid(Some((2, Some(meta)))) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(meta, b"metal");
+    }
+
+    #[test]
+    fn removing_bodies_frees_capacity() {
+        let mut world = PhysicsWorld::default();
+        let create_batch = |ids: &[u64], world: &mut PhysicsWorld| {
+            create_bodies(
+                ids.iter().copied().map(body).collect(),
+                peer(),
+                Some(2),
+                world.scale,
+                &mut world.context,
+                &mut world.entity2body,
+                &mut world.body_descriptions,
+                &mut world.user_meta,
+            )
+        };
+
+        assert!(matches!(
+            create_batch(&[1, 2], &mut world),
+            Response::RigidBodyHandles(_)
+        ));
+        assert!(matches!(create_batch(&[3], &mut world), Response::Error(_)));
+        // Sending bodies that already exist again doesn't count against the cap.
+        assert!(matches!(
+            create_batch(&[1], &mut world),
+            Response::RigidBodyHandles(_)
+        ));
+
+        remove_bodies(
+            vec![1],
+            &mut world.context,
+            &mut world.entity2body,
+            &mut world.entity2collider,
+            &mut world.body_descriptions,
+            &mut world.collider_descriptions,
+            &mut world.user_meta,
+            &mut world.overlap_sensors,
+        );
+        assert!(matches!(
+            create_batch(&[3], &mut world),
+            Response::RigidBodyHandles(_)
+        ));
+        assert_eq!(world.context.bodies.len(), 2);
+    }
+
+    #[test]
+    fn identical_worlds_encode_identical_steps() {
+        let config = config();
+        let body = |id: u64, body_type, height| CreatedBody {
+            body: body_type,
+            transform: Some(Isometry::translation(id as f32 * 0.3, height, 0.0)),
+            ..body(id)
+        };
+        let mut bodies = vec![body(1000, RigidBody::Fixed, 0.0)];
+        let mut colliders = vec![collider(
+            1000,
+            SerializableShape::Collider(Collider::cuboid(50.0, 0.5, 50.0)),
+        )];
+        // Ids spread over the hash space, so hash order and id order differ.
+        for id in (1..40).map(|i| i * 7919) {
+            bodies.push(body(id, RigidBody::Dynamic, 1.0 + (id % 5) as f32));
+            colliders.push(collider(
+                id,
+                SerializableShape::Collider(Collider::ball(0.25)),
+            ));
+        }
+
+        let run = || {
+            let mut world = PhysicsWorld::default();
+            let setup = Request::BulkRequest(vec![
+                Request::CreateBodies(bodies.clone()),
+                Request::CreateColliders(colliders.clone()),
+            ]);
+            handle_request(setup, peer(), &config, &mut world, ());
+            (0..30)
+                .map(|_| {
+                    let response = handle_request(
+                        Request::SimulateStep(1.0 / 60.0),
+                        peer(),
+                        &config,
+                        &mut world,
+                        (),
+                    );
+                    assert!(matches!(response, Response::SimulationResult(..)));
+                    serialize(&response).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn bulk_config_scales_bodies_created_before_it() {
+        let falling = CreatedBody {
+            transform: Some(Isometry::translation(0.0, 50.0, 0.0)),
+            ..body(1)
+        };
+        let mut rapier_config: SerializableRapierConfiguration = RapierConfiguration {
+            gravity: Vect::ZERO,
+            ..default()
+        }
+        .into();
+        rapier_config.physics_scale = Some(100.0);
+
+        // The config comes last, but the body and its collider are created and
+        // stepped with its scale and gravity.
+        let mut world = PhysicsWorld::default();
+        let response = handle_request(
+            Request::BulkRequest(vec![
+                Request::CreateBodies(vec![falling]),
+                Request::CreateColliders(vec![collider(
+                    1,
+                    SerializableShape::Collider(Collider::ball(5.0)),
+                )]),
+                Request::SimulateStep(1.0 / 60.0),
+                Request::UpdateConfig(rapier_config),
+            ]),
+            peer(),
+            &config(),
+            &mut world,
+            (),
+        );
+
+        let Response::BulkResponse(responses) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert!(matches!(responses[0], Response::RigidBodyHandles(_)));
+        assert!(matches!(responses[3], Response::ConfigUpdated));
+        let Response::SimulationResult(results, _) = &responses[2] else {
+            panic!("unexpected response {:?}", responses[2]);
+        };
+        assert_eq!(results[0].1.translation, Vect::new(0.0, 50.0, 0.0));
+        assert_eq!(results[0].2.linvel, Vect::ZERO);
+
+        let handle = world.entity2body[&Entity::from_bits(1)];
+        assert_eq!(world.context.bodies[handle].translation().y, 0.5);
+        let collider = world.entity2collider[&Entity::from_bits(1)];
+        let ball = world.context.colliders[collider].shape().as_ball().unwrap();
+        assert!((ball.radius - 0.05).abs() < 1e-6);
+    }
+
+    #[test]
+    fn creation_responses_follow_request_order() {
+        let mut world = PhysicsWorld::default();
+        let response = create_bodies(
+            vec![body(3), body(1), body(2)],
+            peer(),
+            None,
+            world.scale,
+            &mut world.context,
+            &mut world.entity2body,
+            &mut world.body_descriptions,
+            &mut world.user_meta,
+        );
+        let Response::RigidBodyHandles(handles) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        let ids: Vec<_> = handles.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [3, 1, 2]);
+
+        // Parented, parentless and failed colliders keep their place.
+        let ball = || SerializableShape::Collider(Collider::ball(0.5));
+        let flat = vec![Vect::ZERO, Vect::X, Vect::Z, Vect::new(1.0, 0.0, 1.0)];
+        let response = create(
+            vec![
+                collider(1, ball()),
+                collider(10, ball()),
+                collider(3, SerializableShape::ConvexHull(flat)),
+                collider(2, ball()),
+            ],
+            &mut world,
+        );
+        let Response::ColliderHandles(handles) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        let ids: Vec<_> = handles.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [1, 10, 3, 2]);
+        assert_eq!(handles[2].1, ColliderHandle::invalid());
+        for (id, handle) in [handles[0], handles[1], handles[3]] {
+            assert!(world.context.colliders.get(handle).is_some(), "{}", id);
+        }
+        assert!(world.context.colliders[handles[1].1].parent().is_none());
+    }
+
+    /// Sends plain bincode, so it only runs without compression and checksums.
+    #[cfg(not(any(feature = "compression", feature = "checksum")))]
+    #[test]
+    fn lifecycle_events_have_their_fields() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let config = ServerConfig {
+                max_bodies: Some(0),
+                ..config()
+            };
+            handle_connection(stream, config, StdRng::seed_from_u64(0)).unwrap();
+        });
+
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}", address)).unwrap();
+        let tungstenite::stream::MaybeTlsStream::Plain(stream) = socket.get_ref() else {
+            panic!("not a plain TCP stream");
+        };
+        let peer = stream.local_addr().unwrap().to_string();
+
+        for request in [Request::CreateBodies(vec![body(1)]), Request::Goodbye] {
+            socket
+                .write_message(Message::binary(serialize(&request).unwrap()))
+                .unwrap();
+        }
+        let response: Response = deserialize(&socket.read_message().unwrap().into_data()).unwrap();
+        assert!(matches!(response, Response::Error(_)));
+        while socket.read_message().is_ok() {}
+        server.join().unwrap();
+
+        let events: Vec<_> = lifecycle::emitted()
+            .into_iter()
+            .filter(|fields| fields.get(lifecycle::PEER) == Some(&peer))
+            .collect();
+        let required: [(&str, &[&str]); 4] = [
+            (lifecycle::CLIENT_ATTACHED, &[]),
+            (lifecycle::WORLD_CREATED, &[]),
+            (lifecycle::QUOTA_REJECTED, &[lifecycle::KIND, lifecycle::ID]),
+            (lifecycle::WORLD_DROPPED, &[]),
+        ];
+        for (event, fields) in required {
+            let emitted = events
+                .iter()
+                .find(|emitted| emitted["event"] == event)
+                .unwrap_or_else(|| panic!("{} wasn't emitted", event));
+            for field in fields {
+                assert!(emitted.contains_key(*field), "{} has no {}", event, field);
+            }
+        }
+        let detached = events
+            .iter()
+            .find(|emitted| emitted["event"] == lifecycle::CLIENT_DETACHED)
+            .unwrap();
+        assert_eq!(detached[lifecycle::REASON], "goodbye");
+    }
+
+    #[test]
+    fn step_with_only_a_dt() {
+        let config = config();
+        let mut world = PhysicsWorld::default();
+        let falling = CreatedBody {
+            transform: Some(Isometry::translation(0.0, 10.0, 0.0)),
+            ..body(1)
+        };
+        handle_request(
+            Request::CreateBodies(vec![falling]),
+            peer(),
+            &config,
+            &mut world,
+            (),
+        );
+        // A body without colliders has no mass, and gravity doesn't move it.
+        create(
+            vec![collider(
+                1,
+                SerializableShape::Collider(Collider::ball(0.5)),
+            )],
+            &mut world,
+        );
+
+        // Without a config, the step is exactly the dt with the default gravity.
+        let response = handle_request(Request::SimulateStep(0.1), peer(), &config, &mut world, ());
+        let Response::SimulationResult(results, _) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        let gravity = RapierConfiguration::default().gravity.y;
+        assert!((results[0].2.linvel.y - gravity * 0.1).abs() < 1e-4);
+        assert!((results[0].1.translation.y - (10.0 + gravity * 0.01)).abs() < 1e-4);
+        assert!((world.sim_time - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bodies_read_back_like_the_local_writeback() {
+        let physics_scale = 100.0;
+        let timestep_mode = TimestepMode::Fixed {
+            dt: 1.0 / 60.0,
+            substeps: 1,
+        };
+        let start = Transform::from_xyz(0.0, 50.0, 0.0);
+        let velocity = Velocity {
+            linvel: Vect::new(30.0, 0.0, 0.0),
+            angvel: Vect::new(0.0, 2.0, 0.0),
+        };
+
+        // bevy_rapier simulating and writing back the body itself.
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(TransformPlugin)
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<Mesh>()
+            .add_asset::<Scene>()
+            .insert_resource(RapierConfiguration {
+                timestep_mode,
+                ..default()
+            })
+            .add_plugin(
+                RapierPhysicsPlugin::<NoUserData>::default().with_physics_scale(physics_scale),
+            );
+        let entity = app
+            .world
+            .spawn((
+                RigidBody::Dynamic,
+                Collider::ball(5.0),
+                velocity,
+                // The body is created before transforms are propagated.
+                TransformBundle::from_transform(start),
+            ))
+            .insert(GlobalTransform::from(start))
+            .id();
+        for _ in 0..30 {
+            app.update();
+        }
+
+        let mut rapier_config: SerializableRapierConfiguration = RapierConfiguration {
+            timestep_mode,
+            ..default()
+        }
+        .into();
+        rapier_config.physics_scale = Some(physics_scale);
+        let moving = CreatedBody {
+            transform: Some(transform_to_iso(&start)),
+            velocity: Some(velocity.into()),
+            ..body(1)
+        };
+        let mut world = PhysicsWorld::default();
+        handle_request(
+            Request::BulkRequest(vec![
+                Request::UpdateConfig(rapier_config),
+                Request::CreateBodies(vec![moving]),
+                Request::CreateColliders(vec![collider(
+                    1,
+                    SerializableShape::Collider(Collider::ball(5.0)),
+                )]),
+            ]),
+            peer(),
+            &config(),
+            &mut world,
+            (),
+        );
+        let mut results = vec![];
+        for _ in 0..30 {
+            let response = handle_request(
+                Request::SimulateStep(1.0 / 60.0),
+                peer(),
+                &config(),
+                &mut world,
+                (),
+            );
+            let Response::SimulationResult(step_results, _) = response else {
+                panic!("unexpected response {:?}", response);
+            };
+            results = step_results;
+        }
+
+        let local_transform = app.world.get::<Transform>(entity).unwrap();
+        let local_velocity = app.world.get::<Velocity>(entity).unwrap();
+        let (_, transform, velocity) = results[0];
+        // The body fell and spun, and moved 15 units sideways.
+        assert!(local_transform.translation.y < 49.0);
+        assert!((local_transform.translation.x - 15.0).abs() < 1e-3);
+        assert!((transform.translation - local_transform.translation).length() < 1e-3);
+        assert!(transform.rotation.angle_between(local_transform.rotation) < 1e-4);
+        assert!((velocity.linvel - local_velocity.linvel).length() < 1e-3);
+        assert!((velocity.angvel - local_velocity.angvel).length() < 1e-4);
+    }
+
+    #[test]
+    fn seed_reproduces_the_latency_of_each_connection() {
+        let latency = SimulatedLatency::Random { min: 10, mean: 100 };
+        let samples = |seed, connection_index| {
+            let mut rng = connection_rng(seed, connection_index);
+            (0..32)
+                .map(|_| sample_latency(latency, &mut rng).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(samples(Some(42), 0), samples(Some(42), 0));
+        assert_ne!(samples(Some(42), 0), samples(Some(42), 1));
+        assert!(samples(Some(42), 0)
+            .iter()
+            .all(|latency| *latency >= Duration::from_millis(10)));
+    }
+
+    type BodyStates = Vec<(u64, Transform, Velocity)>;
+
+    /// Sends a request and its response through the wire format, like the client would.
+    fn send(req: Request, config: &ServerConfig, world: &mut PhysicsWorld) -> Response {
+        let req = deserialize(&serialize(&req).unwrap()).unwrap();
+        let response = handle_request(req, peer(), config, world, ());
+        deserialize(&serialize(&response).unwrap()).unwrap()
+    }
+
+    /// A ball thrown up and pushed sideways, a pendulum and a floor far below, all in
+    /// render space. Returns the body states of every step and the answers to a ray
+    /// cast and a point query against the floor.
+    fn run_canonical_scene(physics_scale: Real) -> (Vec<BodyStates>, Response) {
+        let config = config();
+        let mut rapier_config: SerializableRapierConfiguration =
+            RapierConfiguration::default().into();
+        rapier_config.physics_scale = Some(physics_scale);
+
+        let ball = CreatedBody {
+            transform: Some(Isometry::translation(0.0, 5.0, 0.0)),
+            velocity: Some(SerializableVelocity {
+                linvel: Vect::new(1.0, 3.0, 0.0),
+                angvel: Vect::new(0.0, 1.0, 0.0),
+            }),
+            ..body(1)
+        };
+        let pivot = CreatedBody {
+            body: RigidBody::Fixed,
+            transform: Some(Isometry::translation(4.0, 5.0, 0.0)),
+            ..body(2)
+        };
+        let bob = CreatedBody {
+            transform: Some(Isometry::translation(6.0, 5.0, 0.0)),
+            ..body(3)
+        };
+        let with_mass = |id, radius, mass| CreatedCollider {
+            mass_properties: Some(ColliderMassProperties::Mass(mass).into()),
+            ..collider(id, SerializableShape::Collider(Collider::ball(radius)))
+        };
+        let floor = CreatedCollider {
+            transform: Some(Isometry::translation(0.0, -20.0, 0.0)),
+            ..collider(
+                10,
+                SerializableShape::Collider(Collider::cuboid(20.0, 0.5, 20.0)),
+            )
+        };
+        let arm = CreatedJoint {
+            id: 3,
+            parent: 2,
+            joint: SerializableGenericJoint(
+                SphericalJointBuilder::new()
+                    .local_anchor2(Vect::new(-2.0, 0.0, 0.0))
+                    .build()
+                    .into(),
+            ),
+        };
+
+        let mut world = PhysicsWorld::default();
+        let setup = send(
+            Request::BulkRequest(vec![
+                Request::UpdateConfig(rapier_config),
+                Request::CreateBodies(vec![ball, pivot, bob]),
+                Request::CreateColliders(vec![
+                    with_mass(1, 0.5, 2.0),
+                    with_mass(3, 0.25, 1.0),
+                    floor,
+                ]),
+                Request::CreateJoints(vec![arm]),
+                Request::ApplyImpulses(vec![(
+                    1,
+                    SerializableExternalImpulse {
+                        impulse: Vect::new(0.0, 4.0, 0.0),
+                        torque_impulse: Vect::ZERO,
+                    },
+                )]),
+                Request::ApplyForces(vec![(
+                    1,
+                    SerializableExternalForce {
+                        force: Vect::new(2.0, 0.0, 0.0),
+                        torque: Vect::ZERO,
+                    },
+                )]),
+            ]),
+            &config,
+            &mut world,
+        );
+        let Response::BulkResponse(responses) = setup else {
+            panic!("unexpected response {:?}", setup);
+        };
+        assert!(
+            !responses
+                .iter()
+                .any(|response| matches!(response, Response::Error(_))),
+            "{:?}",
+            responses
+        );
+
+        let steps = (0..30)
+            .map(
+                |_| match send(Request::SimulateStep(1.0 / 60.0), &config, &mut world) {
+                    Response::SimulationResult(results, _) => results,
+                    response => panic!("unexpected response {:?}", response),
+                },
+            )
+            .collect();
+        let queries = send(
+            Request::BulkRequest(vec![
+                Request::CastRay {
+                    origin: Vect::ZERO,
+                    dir: Vect::NEG_Y,
+                    max_toi: 100.0,
+                    solid: true,
+                },
+                Request::PointInSolid(Vect::new(15.0, -20.2, 0.0)),
+            ]),
+            &config,
+            &mut world,
+        );
+        (steps, queries)
+    }
+
+    #[test]
+    fn scenes_match_at_every_physics_scale() {
+        let (expected_steps, expected_queries) = run_canonical_scene(1.0);
+
+        // The pendulum swung and the ball rose and moved sideways.
+        let last = expected_steps.last().unwrap();
+        assert!(last[0].1.translation.x > 0.5 && last[0].1.translation.y > 6.0);
+        assert!(last[2].1.translation.y < 4.5);
+
+        let close = |a: Vect, b: Vect| (a - b).length() <= 1e-3 * b.length().max(1.0);
+        for physics_scale in [0.5, 100.0] {
+            let (steps, queries) = run_canonical_scene(physics_scale);
+            for (step, expected) in steps.iter().zip(&expected_steps) {
+                assert_eq!(step.len(), expected.len());
+                for (
+                    (id, transform, velocity),
+                    (expected_id, expected_transform, expected_velocity),
+                ) in step.iter().zip(expected)
+                {
+                    assert_eq!(id, expected_id);
+                    assert!(
+                        close(transform.translation, expected_transform.translation),
+                        "body {} at scale {}: {} != {}",
+                        id,
+                        physics_scale,
+                        transform.translation,
+                        expected_transform.translation
+                    );
+                    assert!(
+                        transform
+                            .rotation
+                            .angle_between(expected_transform.rotation)
+                            < 1e-3
+                    );
+                    assert!(close(velocity.linvel, expected_velocity.linvel));
+                    assert!(close(velocity.angvel, expected_velocity.angvel));
+                }
+            }
+
+            let (Response::BulkResponse(queries), Response::BulkResponse(expected_queries)) =
+                (&queries, &expected_queries)
+            else {
+                panic!("unexpected responses {:?} {:?}", queries, expected_queries);
+            };
+            let [Response::RayHit(Some((collider, toi, normal, _))), Response::PointInSolid(Some((solid, _)))] =
+                queries.as_slice()
+            else {
+                panic!("unexpected responses {:?}", queries);
+            };
+            let [Response::RayHit(Some((_, expected_toi, _, _))), _] = expected_queries.as_slice()
+            else {
+                panic!("unexpected responses {:?}", expected_queries);
+            };
+            assert_eq!((*collider, *solid), (10, 10));
+            assert!((toi - expected_toi).abs() < 1e-3 && (toi - 19.5).abs() < 1e-3);
+            assert!(close(*normal, Vect::Y));
+        }
     }
-    Response::SimulationResult(results)
 }
@@ -0,0 +1,127 @@
+//! Shapes uploaded with `CreateColliderFromCachedMesh`, shared by all connections so
+//! reconnecting clients don't have to send their level geometry again.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use shared::serializable::{ContentHash, SerializableShape};
+
+/// Serialized size of the shapes kept before the least recently used ones are dropped.
+pub const CAPACITY: usize = 256 * 1024 * 1024;
+
+struct Entry {
+    shape: SerializableShape,
+    size: usize,
+    last_used: u64,
+}
+
+pub struct MeshCache {
+    entries: BTreeMap<ContentHash, Entry>,
+    capacity: usize,
+    size: usize,
+    uses: u64,
+}
+
+static MESH_CACHE: Mutex<MeshCache> = Mutex::new(MeshCache::new(CAPACITY));
+
+/// The cache only holds finished inserts, a connection that panicked can't leave it
+/// half-updated, so a poisoned lock is still usable.
+pub fn global() -> MutexGuard<'static, MeshCache> {
+    MESH_CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl MeshCache {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            capacity,
+            size: 0,
+            uses: 0,
+        }
+    }
+
+    pub fn get(&mut self, hash: &ContentHash) -> Option<SerializableShape> {
+        self.uses += 1;
+        let entry = self.entries.get_mut(hash)?;
+        entry.last_used = self.uses;
+        Some(entry.shape.clone())
+    }
+
+    /// Caches the shape under the hash computed here, and returns it.
+    /// Shapes larger than the whole cache aren't kept.
+    pub fn insert(&mut self, shape: SerializableShape) -> ContentHash {
+        let hash = shape.content_hash();
+        let size = bincode::serialized_size(&shape).unwrap() as usize;
+        if size > self.capacity || self.entries.contains_key(&hash) {
+            return hash;
+        }
+
+        while self.size + size > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(hash, _)| *hash)
+                .expect("the cache holds the shapes its size counts");
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.size -= entry.size;
+        }
+
+        self.uses += 1;
+        self.size += size;
+        self.entries.insert(
+            hash,
+            Entry {
+                shape,
+                size,
+                last_used: self.uses,
+            },
+        );
+        hash
+    }
+
+    /// Serialized size of the cached shapes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_rapier3d::prelude::Vect;
+
+    use super::*;
+
+    /// Shapes with different seeds have the same size.
+    fn shape(seed: f32) -> SerializableShape {
+        SerializableShape::ConvexHull((0..4).map(|i| Vect::splat(seed + i as f32)).collect())
+    }
+
+    fn size() -> usize {
+        bincode::serialized_size(&shape(0.0)).unwrap() as usize
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = MeshCache::new(size() * 2);
+        let first = cache.insert(shape(1.0));
+        let second = cache.insert(shape(2.0));
+
+        // `first` was used last, so making room drops `second`.
+        assert!(cache.get(&first).is_some());
+        let third = cache.insert(shape(3.0));
+
+        assert_eq!(cache.size(), size() * 2);
+        assert!(cache.get(&first).is_some());
+        assert!(cache.get(&second).is_none());
+        assert!(cache.get(&third).is_some());
+    }
+
+    #[test]
+    fn keeps_nothing_larger_than_the_cache() {
+        let mut cache = MeshCache::new(size() - 1);
+        let hash = cache.insert(shape(1.0));
+        assert!(cache.get(&hash).is_none());
+        assert_eq!(cache.size(), 0);
+    }
+}
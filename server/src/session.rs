@@ -0,0 +1,183 @@
+//! Worlds that outlive their connection, so a client that lost its connection can
+//! reconnect and continue where it left off.
+//!
+//! A client opts in by connecting with `?session=<uuid>`. When the connection drops
+//! without a `Goodbye`, the world is kept until the session timeout and handed to the
+//! next connection with the same id.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bevy::utils::Uuid;
+use tungstenite::handshake::server::{ErrorResponse, Request};
+use tungstenite::http::{Response, StatusCode};
+
+use crate::{lifecycle, PhysicsWorld};
+
+enum Session {
+    /// A connection is using the world. Setting `taken_over` tells it to give the
+    /// world back, its connection may be half-open and never see another message.
+    Attached { taken_over: Arc<AtomicBool> },
+    /// Waiting for the client to reconnect since `since`.
+    Detached { world: PhysicsWorld, since: Instant },
+}
+
+static SESSIONS: Mutex<BTreeMap<Uuid, Session>> = Mutex::new(BTreeMap::new());
+
+fn error_response(status: StatusCode, message: String) -> ErrorResponse {
+    Response::builder()
+        .status(status)
+        .body(Some(message))
+        .unwrap()
+}
+
+/// Reads the session id from the query of the handshake request.
+pub fn session_id(request: &Request) -> Result<Option<Uuid>, ErrorResponse> {
+    let Some(query) = request.uri().query() else {
+        return Ok(None);
+    };
+    let Some(value) = query.split('&').find_map(|pair| {
+        pair.strip_prefix(shared::SESSION_QUERY_PARAM)?
+            .strip_prefix('=')
+    }) else {
+        return Ok(None);
+    };
+    Uuid::parse_str(value).map(Some).map_err(|err| {
+        error_response(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid session id: {}", err),
+        )
+    })
+}
+
+/// A world handed to a connection.
+pub struct Attachment {
+    pub world: PhysicsWorld,
+    pub resumed: bool,
+    /// Set when another connection wants the session, the connection should detach.
+    pub taken_over: Arc<AtomicBool>,
+}
+
+/// Hands the world of the session to a new connection.
+///
+/// A session that is still attached is taken from its connection, which detaches
+/// within the session poll interval. Until then this fails and the client retries.
+pub fn attach(id: Uuid) -> Result<Attachment, ErrorResponse> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let taken_over = Arc::new(AtomicBool::new(false));
+    let attached = Session::Attached {
+        taken_over: taken_over.clone(),
+    };
+    match sessions.insert(id, attached) {
+        Some(Session::Attached { taken_over }) => {
+            taken_over.store(true, Ordering::Relaxed);
+            sessions.insert(id, Session::Attached { taken_over });
+            Err(error_response(
+                StatusCode::CONFLICT,
+                format!("Session {} is being taken over from another connection", id),
+            ))
+        }
+        Some(Session::Detached { mut world, .. }) => {
+            // The client may have missed the last responses, send it full results again.
+            world.sent_positions.clear();
+            Ok(Attachment {
+                world,
+                resumed: true,
+                taken_over,
+            })
+        }
+        None => Ok(Attachment {
+            world: PhysicsWorld::default(),
+            resumed: false,
+            taken_over,
+        }),
+    }
+}
+
+/// Keeps the world until the client reconnects or the session expires.
+pub fn detach(id: Uuid, world: PhysicsWorld) {
+    let session = Session::Detached {
+        world,
+        since: Instant::now(),
+    };
+    SESSIONS.lock().unwrap().insert(id, session);
+}
+
+/// Forgets the session, the caller drops the world.
+pub fn end(id: Uuid) {
+    SESSIONS.lock().unwrap().remove(&id);
+}
+
+/// Drops the worlds of sessions detached for longer than `timeout`.
+pub fn expire(timeout: Duration) {
+    let mut expired = Vec::new();
+    {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let ids: Vec<Uuid> = sessions
+            .iter()
+            .filter(|(_, session)| {
+                matches!(session, Session::Detached { since, .. } if since.elapsed() >= timeout)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            if let Some(session) = sessions.remove(&id) {
+                expired.push((id, session));
+            }
+        }
+    }
+
+    // Dropping a large world takes a while, don't keep other connections waiting.
+    for (id, session) in expired {
+        drop(session);
+        lifecycle::emit(lifecycle::SESSION_EXPIRED, &[(lifecycle::SESSION, &id)]);
+        lifecycle::emit(lifecycle::WORLD_DROPPED, &[(lifecycle::SESSION, &id)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `expire` affects every session, the tests share `SESSIONS`.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn attached_session_is_taken_over() {
+        let _serial = SERIAL.lock().unwrap();
+        let id = Uuid::new_v4();
+        let first = attach(id).unwrap();
+        assert!(!first.resumed);
+
+        let err = attach(id).err().unwrap();
+        assert_eq!(err.status(), StatusCode::CONFLICT);
+        assert!(first.taken_over.load(Ordering::Relaxed));
+
+        detach(id, first.world);
+        let second = attach(id).unwrap();
+        assert!(second.resumed);
+        assert!(!second.taken_over.load(Ordering::Relaxed));
+        end(id);
+    }
+
+    #[test]
+    fn expired_sessions_start_over() {
+        let _serial = SERIAL.lock().unwrap();
+        let id = Uuid::new_v4();
+        let attachment = attach(id).unwrap();
+        detach(id, attachment.world);
+
+        expire(Duration::ZERO);
+        let session = id.to_string();
+        for event in [lifecycle::SESSION_EXPIRED, lifecycle::WORLD_DROPPED] {
+            assert!(lifecycle::emitted().iter().any(|fields| {
+                fields["event"] == event && fields.get(lifecycle::SESSION) == Some(&session)
+            }));
+        }
+
+        assert!(!attach(id).unwrap().resumed);
+        end(id);
+    }
+}
@@ -0,0 +1,41 @@
+/// Appends a little-endian CRC32 of `data` to it.
+pub fn append(mut data: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32fast::hash(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/// Strips the CRC32 trailer added by [`append`], returning `None` if it doesn't match.
+pub fn verify(mut data: Vec<u8>) -> Option<Vec<u8>> {
+    let payload_len = data.len().checked_sub(4)?;
+    let checksum = u32::from_le_bytes(data[payload_len..].try_into().ok()?);
+    data.truncate(payload_len);
+
+    (crc32fast::hash(&data) == checksum).then_some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intact_message_verifies() {
+        let data = b"SimulateStep".to_vec();
+        assert_eq!(verify(append(data.clone())), Some(data));
+    }
+
+    #[test]
+    fn flipped_byte_is_detected() {
+        let framed = append(b"SimulateStep".to_vec());
+        for i in 0..framed.len() {
+            let mut corrupted = framed.clone();
+            corrupted[i] ^= 0x01;
+            assert_eq!(verify(corrupted), None, "flipped byte {} went unnoticed", i);
+        }
+    }
+
+    #[test]
+    fn frame_shorter_than_the_trailer_is_rejected() {
+        assert_eq!(verify(vec![1, 2, 3]), None);
+    }
+}
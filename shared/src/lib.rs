@@ -1,42 +1,168 @@
-use std::collections::HashMap;
-
 use bevy::prelude::*;
 use bevy_rapier3d::{
     prelude::*,
-    rapier::prelude::{ColliderHandle, Isometry, RigidBodyHandle},
+    rapier::parry::shape::ShapeType,
+    rapier::prelude::{ColliderHandle, ImpulseJointHandle, Isometry, RigidBodyHandle},
 };
 
 use serde::{Deserialize, Serialize};
 
+pub mod checksum;
+pub mod scale;
 pub mod serializable;
 use serializable::*;
 
+/// Maximum size of the metadata attached to a body, in bytes.
+pub const MAX_USER_META_LEN: usize = 64;
+
+/// Query parameter of the handshake that names the session to resume, e.g. `?session=<uuid>`.
+pub const SESSION_QUERY_PARAM: &str = "session";
+/// Handshake response header, `true` if the connection got the world of its previous
+/// connection back and `false` if it starts with an empty one.
+pub const SESSION_RESUMED_HEADER: &str = "x-session-resumed";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreatedBody {
     pub id: u64,
     pub body: RigidBody,
     pub transform: Option<Isometry<Real>>,
     pub additional_mass_properties: Option<SerializableAdditionalMassProperties>,
+    pub velocity: Option<SerializableVelocity>,
+    pub damping: Option<SerializableDamping>,
+    pub gravity_scale: Option<SerializableGravityScale>,
+    pub locked_axes: Option<SerializableLockedAxes>,
+    pub ccd: Option<SerializableCcd>,
+    /// Opaque gameplay data the server keeps with the body, at most [`MAX_USER_META_LEN`] bytes.
+    pub user_meta: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreatedCollider {
     pub id: u64,
-    pub shape: Collider,
+    pub shape: SerializableShape,
     pub transform: Option<Isometry<Real>>,
     pub sensor: Option<SerializableSensor>,
     pub mass_properties: Option<SerializableColliderMassProperties>,
     pub friction: Option<SerializableFriction>,
     pub restitution: Option<SerializableRestitution>,
+    /// Report the number of colliders overlapping this sensor after every step.
+    pub report_overlaps: bool,
+}
+
+/// An impulse joint between the body `id` and the body `parent`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedJoint {
+    /// The entity with the `ImpulseJoint`, also the body on the child side of the joint.
+    pub id: u64,
+    pub parent: u64,
+    pub joint: SerializableGenericJoint,
+}
+
+/// An island of active dynamic bodies connected by contacts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IslandInfo {
+    pub bodies: Vec<u64>,
+    pub kinetic_energy: Real,
+}
+
+/// Size of the world and counters that explain the cost of a step. The pair and
+/// manifold counts are zero before the first step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostics {
+    pub bodies: u32,
+    pub colliders: u32,
+    /// Collider pairs whose AABBs overlap, i.e. the pairs the narrow phase has to check.
+    pub broad_phase_pairs: u32,
+    /// Contact manifolds computed by the narrow phase.
+    pub contact_manifolds: u32,
 }
 
+/// Positions, lengths, velocities, forces and shapes are in render space, the server
+/// converts them with the physics scale of its world, see [`scale::ScaleCtx`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     BulkRequest(Vec<Request>),
     UpdateConfig(SerializableRapierConfiguration),
     CreateBodies(Vec<CreatedBody>),
     CreateColliders(Vec<CreatedCollider>),
+    CreateJoints(Vec<CreatedJoint>),
     SimulateStep(f32),
+    WakeBodies(Vec<u64>),
+    TimeOfImpact(u64, u64),
+    ClearForces(Vec<u64>),
+    /// Body id, down direction and distance to check below the body's AABB.
+    GroundedCheck(Vec<(u64, Vect, f32)>),
+    DetachColliders(Vec<u64>),
+    /// Simulated time since the world was created, see [`Response::SimTime`].
+    QuerySimTime,
+    /// Debug request, membership lists can be large.
+    GetIslands,
+    /// World point to test against solid (non-sensor) colliders.
+    PointInSolid(Vect),
+    /// Only return a [`Response::SimulationResult`] every N steps, the steps
+    /// in between are answered with [`Response::Stepped`].
+    SetResultInterval(u32),
+    /// Enables or disables continuous collision detection per body.
+    SetCcd(Vec<(u64, bool)>),
+    /// Drops the world of this connection, the server closes the connection afterwards.
+    Goodbye,
+    /// Replaces the metadata of bodies, `None` clears it.
+    SetUserMeta(Vec<(u64, Option<Vec<u8>>)>),
+    GetUserMeta(Vec<u64>),
+    /// Pushes every dynamic body within `radius` of `center` away from it.
+    /// With `falloff`, the impulse fades linearly to zero at `radius`.
+    RadialImpulse {
+        center: Vect,
+        radius: f32,
+        strength: f32,
+        falloff: bool,
+    },
+    /// Removes bodies together with their attached colliders and joints.
+    RemoveBodies(Vec<u64>),
+    QueryPhysicsScale,
+    /// Removes colliders and wakes up the bodies they were attached to.
+    RemoveColliders(Vec<u64>),
+    /// Removes joints and wakes up the bodies they connected.
+    RemoveJoints(Vec<u64>),
+    /// Creates a plain collider from a shape the server cached under its
+    /// [`SerializableShape::content_hash`], e.g. level geometry sent by an earlier connection.
+    /// On a cache miss the `fallback` is cached and used, without one the server answers
+    /// with [`Response::MeshCacheMiss`] and the client sends the request again with the shape.
+    CreateColliderFromCachedMesh {
+        id: u64,
+        hash: ContentHash,
+        transform: Option<Isometry<Real>>,
+        fallback: Option<SerializableShape>,
+    },
+    /// Replaces the persistent forces and torques of bodies, like `ExternalForce` does locally.
+    ApplyForces(Vec<(u64, SerializableExternalForce)>),
+    /// Turns bodies into fixed ones and back, restoring their previous type and velocity.
+    /// Unlike pausing the pipeline, the rest of the world keeps simulating.
+    SetBodiesFrozen(Vec<(u64, bool)>),
+    /// One-shot impulses, applied once and waking the bodies up.
+    ApplyImpulses(Vec<(u64, SerializableExternalImpulse)>),
+    SetVelocities(Vec<(u64, SerializableVelocity)>),
+    SetDamping(Vec<(u64, SerializableDamping)>),
+    SetGravityScale(Vec<(u64, f32)>),
+    SetLockedAxes(Vec<(u64, SerializableLockedAxes)>),
+    GetDiagnostics,
+    /// Casts a ray against solid (non-sensor) colliders. The hit lies at `origin + dir * toi`,
+    /// with `solid` a ray starting inside a shape hits it at `toi` 0.
+    CastRay {
+        origin: Vect,
+        dir: Vect,
+        max_toi: f32,
+        solid: bool,
+    },
+    /// Teleports bodies, kinematic ones move there during the next step.
+    SetBodyPositions(Vec<(u64, Isometry<Real>)>),
+    /// Like `SimulateStep`, but answered with a [`Response::SimulationDelta`] that leaves out
+    /// sleeping bodies that moved less than `epsilon` since they were last sent. The epsilon
+    /// applies to the translation in world units and to the rotation in radians.
+    SimulateStepDelta {
+        delta_time: f32,
+        epsilon: Real,
+    },
 }
 
 impl Request {
@@ -46,7 +172,38 @@ impl Request {
             Self::UpdateConfig(_) => "UpdateConfig",
             Self::CreateBodies(_) => "CreateBodies",
             Self::CreateColliders(_) => "CreateColliders",
+            Self::CreateJoints(_) => "CreateJoints",
             Self::SimulateStep(_) => "SimulateStep",
+            Self::WakeBodies(_) => "WakeBodies",
+            Self::TimeOfImpact(_, _) => "TimeOfImpact",
+            Self::ClearForces(_) => "ClearForces",
+            Self::GroundedCheck(_) => "GroundedCheck",
+            Self::DetachColliders(_) => "DetachColliders",
+            Self::QuerySimTime => "QuerySimTime",
+            Self::GetIslands => "GetIslands",
+            Self::PointInSolid(_) => "PointInSolid",
+            Self::SetResultInterval(_) => "SetResultInterval",
+            Self::SetCcd(_) => "SetCcd",
+            Self::SetUserMeta(_) => "SetUserMeta",
+            Self::GetUserMeta(_) => "GetUserMeta",
+            Self::RadialImpulse { .. } => "RadialImpulse",
+            Self::RemoveBodies(_) => "RemoveBodies",
+            Self::QueryPhysicsScale => "QueryPhysicsScale",
+            Self::RemoveColliders(_) => "RemoveColliders",
+            Self::RemoveJoints(_) => "RemoveJoints",
+            Self::CreateColliderFromCachedMesh { .. } => "CreateColliderFromCachedMesh",
+            Self::ApplyForces(_) => "ApplyForces",
+            Self::SetBodiesFrozen(_) => "SetBodiesFrozen",
+            Self::ApplyImpulses(_) => "ApplyImpulses",
+            Self::SetVelocities(_) => "SetVelocities",
+            Self::SetDamping(_) => "SetDamping",
+            Self::SetGravityScale(_) => "SetGravityScale",
+            Self::SetLockedAxes(_) => "SetLockedAxes",
+            Self::GetDiagnostics => "GetDiagnostics",
+            Self::CastRay { .. } => "CastRay",
+            Self::SetBodyPositions(_) => "SetBodyPositions",
+            Self::SimulateStepDelta { .. } => "SimulateStepDelta",
+            Self::Goodbye => "Goodbye",
         }
     }
 }
@@ -54,27 +211,179 @@ impl Request {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
     BulkResponse(Vec<Response>),
+    Error(String),
     ConfigUpdated,
+    /// One entry per created body, in the order of the `CreateBodies` request.
+    /// Bodies that could not be created get `RigidBodyHandle::invalid()`.
     RigidBodyHandles(Vec<(u64, RigidBodyHandle)>),
+    /// One entry per created collider, in the order of the `CreateColliders` request.
+    /// Colliders that could not be created get `ColliderHandle::invalid()`.
     ColliderHandles(Vec<(u64, ColliderHandle)>),
-    SimulationResult(HashMap<RigidBodyHandle, (Transform, Velocity)>),
+    /// One entry per created joint, in the order of the `CreateJoints` request.
+    /// Joints whose bodies don't exist get `ImpulseJointHandle::invalid()`.
+    JointHandles(Vec<(u64, ImpulseJointHandle)>),
+    /// State of every body after the step and the overlap count of every sensor
+    /// created with `report_overlaps`, both sorted by id so that identical worlds
+    /// produce byte-identical responses.
+    SimulationResult(Vec<(u64, Transform, Velocity)>, Vec<(u64, u32)>),
+    BodiesWoken,
+    Toi(Option<f32>),
+    ForcesCleared,
+    /// Whether each body is grounded, with the metadata of the body it stands on.
+    Grounded(Vec<(u64, bool, Option<Vec<u8>>)>),
+    CollidersDetached(Vec<u64>),
+    /// Sum of the step deltas, in seconds. Paused steps don't count.
+    SimTime(f32),
+    Islands(Vec<IslandInfo>),
+    /// Id of a collider containing the point and the metadata of its body, `None` in free space.
+    PointInSolid(Option<(u64, Option<Vec<u8>>)>),
+    ResultIntervalSet,
+    /// A step was simulated but its results were coalesced into a later step.
+    Stepped,
+    CcdSet,
+    UserMetaSet,
+    UserMeta(Vec<(u64, Option<Vec<u8>>)>),
+    /// Bodies that received an impulse.
+    RadialImpulseApplied(Vec<u64>),
+    /// Ids of the bodies that existed and were removed.
+    BodiesRemoved(Vec<u64>),
+    PhysicsScale(Real),
+    /// Ids of the colliders that existed and were removed.
+    CollidersRemoved(Vec<u64>),
+    /// Ids of the joints that existed and were removed.
+    JointsRemoved(Vec<u64>),
+    /// The server has no shape with this hash, send it as the fallback.
+    MeshCacheMiss {
+        id: u64,
+        hash: ContentHash,
+    },
+    ForcesApplied,
+    /// Ids of the bodies whose frozen state changed.
+    BodiesFrozen(Vec<u64>),
+    ImpulsesApplied,
+    VelocitiesSet,
+    DampingSet,
+    GravityScaleSet,
+    LockedAxesSet,
+    Diagnostics(Diagnostics),
+    /// Id of the hit collider, the time of impact, the surface normal at the hit and the
+    /// metadata of the body the collider is attached to.
+    RayHit(Option<(u64, f32, Vect, Option<Vec<u8>>)>),
+    BodyPositionsSet,
+    /// A [`Response::SimulationResult`] of only the bodies that changed, bodies left out
+    /// are where they were last sent.
+    SimulationDelta(Vec<(u64, Transform, Velocity)>, Vec<(u64, u32)>),
 }
 
 impl Response {
     pub fn name(&self) -> &'static str {
         match self {
             Self::BulkResponse(_) => "BulkResponse",
+            Self::Error(_) => "Error",
             Self::ConfigUpdated => "ConfigUpdated",
             Self::RigidBodyHandles(_) => "RigidBodyHandles",
             Self::ColliderHandles(_) => "ColliderHandles",
-            Self::SimulationResult(_) => "SimulationResult",
+            Self::JointHandles(_) => "JointHandles",
+            Self::SimulationResult(_, _) => "SimulationResult",
+            Self::BodiesWoken => "BodiesWoken",
+            Self::Toi(_) => "Toi",
+            Self::ForcesCleared => "ForcesCleared",
+            Self::Grounded(_) => "Grounded",
+            Self::CollidersDetached(_) => "CollidersDetached",
+            Self::SimTime(_) => "SimTime",
+            Self::Islands(_) => "Islands",
+            Self::PointInSolid(_) => "PointInSolid",
+            Self::ResultIntervalSet => "ResultIntervalSet",
+            Self::Stepped => "Stepped",
+            Self::CcdSet => "CcdSet",
+            Self::UserMetaSet => "UserMetaSet",
+            Self::UserMeta(_) => "UserMeta",
+            Self::RadialImpulseApplied(_) => "RadialImpulseApplied",
+            Self::BodiesRemoved(_) => "BodiesRemoved",
+            Self::PhysicsScale(_) => "PhysicsScale",
+            Self::CollidersRemoved(_) => "CollidersRemoved",
+            Self::JointsRemoved(_) => "JointsRemoved",
+            Self::MeshCacheMiss { .. } => "MeshCacheMiss",
+            Self::ForcesApplied => "ForcesApplied",
+            Self::BodiesFrozen(_) => "BodiesFrozen",
+            Self::ImpulsesApplied => "ImpulsesApplied",
+            Self::VelocitiesSet => "VelocitiesSet",
+            Self::DampingSet => "DampingSet",
+            Self::GravityScaleSet => "GravityScaleSet",
+            Self::LockedAxesSet => "LockedAxesSet",
+            Self::Diagnostics(_) => "Diagnostics",
+            Self::RayHit(_) => "RayHit",
+            Self::BodyPositionsSet => "BodyPositionsSet",
+            Self::SimulationDelta(_, _) => "SimulationDelta",
         }
     }
 }
 
-pub fn transform_to_iso(transform: &Transform, physics_scale: Real) -> Isometry<Real> {
-    Isometry::from_parts(
-        (transform.translation / physics_scale).into(),
-        transform.rotation.into(),
-    )
+/// Applies a scale to the collider shape, e.g. the one of the transform
+/// [`scale::transform_to_iso`] drops. The server divides it by the physics scale.
+///
+/// Shapes that can't be scaled exactly are approximated by a convex hull built with
+/// `num_subdivisions` subdivisions, see [`is_scale_exact`].
+pub fn scale_collider(collider: &Collider, scale: Vect, num_subdivisions: u32) -> Collider {
+    let mut scaled = collider.clone();
+    scaled.set_scale(scale, num_subdivisions);
+    scaled
+}
+
+/// Whether scaling the collider by `scale` keeps its shape type.
+///
+/// Balls, capsules, cylinders, cones and rounded shapes only support uniform scale.
+pub fn is_scale_exact(collider: &Collider, scale: Vect) -> bool {
+    let uniform = (scale.x - scale.y).abs() <= f32::EPSILON * scale.x.abs().max(1.0)
+        && (scale.y - scale.z).abs() <= f32::EPSILON * scale.y.abs().max(1.0);
+
+    uniform
+        || !matches!(
+            collider.raw.shape_type(),
+            ShapeType::Ball
+                | ShapeType::Capsule
+                | ShapeType::Cylinder
+                | ShapeType::Cone
+                | ShapeType::RoundCuboid
+                | ShapeType::RoundTriangle
+                | ShapeType::RoundCylinder
+                | ShapeType::RoundCone
+                | ShapeType::RoundConvexPolyhedron
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_rapier3d::rapier::parry::{query, shape::Ball};
+
+    use super::*;
+
+    /// Whether a small ball just inside x = 1 touches the collider at the origin.
+    fn reaches_x_one(collider: &Collider) -> bool {
+        query::intersection_test(
+            &Isometry::identity(),
+            &*collider.raw,
+            &Isometry::translation(1.05, 0.0, 0.0),
+            &Ball::new(0.1),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn scaled_cuboid_collides_at_the_larger_size() {
+        let cuboid = Collider::cuboid(0.5, 0.5, 0.5);
+        assert!(!reaches_x_one(&cuboid));
+
+        let scaled = scale_collider(&cuboid, Vect::splat(2.0), 10);
+        assert!(reaches_x_one(&scaled));
+        assert_eq!(scaled.raw.shape_type(), ShapeType::Cuboid);
+    }
+
+    #[test]
+    fn non_uniform_scale_is_exact_only_for_some_shapes() {
+        let scale = Vect::new(2.0, 1.0, 1.0);
+        assert!(is_scale_exact(&Collider::cuboid(0.5, 0.5, 0.5), scale));
+        assert!(!is_scale_exact(&Collider::ball(0.5), scale));
+        assert!(is_scale_exact(&Collider::ball(0.5), Vect::splat(2.0)));
+    }
 }
@@ -0,0 +1,202 @@
+use bevy::prelude::*;
+use bevy_rapier3d::{
+    prelude::*,
+    rapier::prelude::{Isometry, Vector},
+    utils,
+};
+
+/// Converts between render space (Bevy units) and physics space (Rapier units).
+///
+/// A length in render space is its length in physics space times `physics_scale`.
+/// Requests and responses are in render space, only the server converts, with the
+/// scale of its world. Every value crossing between the two goes through one of
+/// these helpers, so each quantity is scaled by the right power of the scale in one place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScaleCtx {
+    pub physics_scale: Real,
+}
+
+impl Default for ScaleCtx {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+impl ScaleCtx {
+    pub fn new(physics_scale: Real) -> Self {
+        Self { physics_scale }
+    }
+
+    pub fn to_physics_len(self, len: Real) -> Real {
+        len / self.physics_scale
+    }
+
+    /// Positions, directions with a length, and shape scales.
+    pub fn to_physics_vec(self, vec: Vect) -> Vect {
+        vec / self.physics_scale
+    }
+
+    /// Takes an isometry built by [`transform_to_iso`].
+    pub fn to_physics_iso(self, iso: &Isometry<Real>) -> Isometry<Real> {
+        Isometry::from_parts(
+            (iso.translation.vector / self.physics_scale).into(),
+            iso.rotation,
+        )
+    }
+
+    /// Shrinks the shape, including the scale of its transform, by the physics scale.
+    /// Like `RapierContext` does with its colliders, the scaled shape is scaled again
+    /// uniformly, which every shape supports exactly.
+    pub fn to_physics_collider(self, collider: &Collider) -> Collider {
+        let mut collider = collider.clone();
+        collider.promote_scaled_shape();
+        collider.set_scale(Vect::splat(1.0 / self.physics_scale), 1);
+        collider
+    }
+
+    /// Forces and impulses carry one length unit, like velocities.
+    pub fn to_physics_force(self, force: Vect) -> Vector<Real> {
+        self.to_physics_vec(force).into()
+    }
+
+    /// Like bevy_rapier, torques and torque impulses are passed through unscaled.
+    pub fn to_physics_torque(self, torque: Vect) -> Vector<Real> {
+        torque.into()
+    }
+
+    /// Scales the center of mass by one and the angular inertia by two length units.
+    pub fn to_physics_mass_properties(
+        self,
+        mprops: MassProperties,
+    ) -> bevy_rapier3d::rapier::dynamics::MassProperties {
+        mprops.into_rapier(self.physics_scale)
+    }
+
+    /// Scales the anchors and the linear limits and motors, angular ones are kept.
+    pub fn to_physics_joint(
+        self,
+        joint: GenericJoint,
+    ) -> bevy_rapier3d::rapier::dynamics::GenericJoint {
+        joint.into_rapier(self.physics_scale)
+    }
+
+    /// Returns the linear and angular velocity, see [`Self::to_render_velocity`].
+    pub fn to_physics_velocity(self, velocity: Velocity) -> (Vector<Real>, Vector<Real>) {
+        (
+            self.to_physics_vec(velocity.linvel).into(),
+            velocity.angvel.into(),
+        )
+    }
+
+    pub fn to_render_len(self, len: Real) -> Real {
+        len * self.physics_scale
+    }
+
+    pub fn to_render_vec(self, vec: Vector<Real>) -> Vect {
+        Vect::from(vec) * self.physics_scale
+    }
+
+    /// Matches the writeback of `RapierPhysicsPlugin`: the linear velocity carries a
+    /// length unit, the angular velocity is in radians per second and isn't scaled.
+    pub fn to_render_velocity(self, linvel: Vector<Real>, angvel: Vector<Real>) -> Velocity {
+        Velocity {
+            linvel: self.to_render_vec(linvel),
+            angvel: angvel.into(),
+        }
+    }
+
+    pub fn to_render_transform(self, iso: &Isometry<Real>) -> Transform {
+        utils::iso_to_transform(iso, self.physics_scale)
+    }
+}
+
+/// Transforms go over the wire as render space isometries. The scale of the transform
+/// is dropped, it is applied to the collider shape instead, see [`crate::scale_collider`].
+pub fn transform_to_iso(transform: &Transform) -> Isometry<Real> {
+    Isometry::from_parts(transform.translation.into(), transform.rotation.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCALES: [Real; 2] = [1.0, 100.0];
+
+    fn assert_close(a: Vect, b: Vect) {
+        assert!(
+            (a - b).length() <= 1e-4 * b.length().max(1.0),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn forces_and_impulses_round_trip() {
+        for scale in SCALES.map(ScaleCtx::new) {
+            for value in [Vect::new(3.0, -9.81, 0.5), Vect::new(250.0, 0.0, -40.0)] {
+                let physics = scale.to_physics_force(value);
+                assert_close(Vect::from(physics), value / scale.physics_scale);
+                assert_close(scale.to_render_vec(physics), value);
+            }
+        }
+    }
+
+    #[test]
+    fn torques_are_not_scaled() {
+        let torque = Vect::new(0.0, 12.0, -3.0);
+        for scale in SCALES.map(ScaleCtx::new) {
+            assert_close(scale.to_physics_torque(torque).into(), torque);
+        }
+    }
+
+    #[test]
+    fn velocities_round_trip() {
+        let velocity = Velocity {
+            linvel: Vect::new(1.0, 2.0, 3.0),
+            angvel: Vect::new(0.1, 0.2, 0.3),
+        };
+        for scale in SCALES.map(ScaleCtx::new) {
+            let (linvel, angvel) = scale.to_physics_velocity(velocity);
+            assert_close(angvel.into(), velocity.angvel);
+            let back = scale.to_render_velocity(linvel, angvel);
+            assert_close(back.linvel, velocity.linvel);
+            assert_close(back.angvel, velocity.angvel);
+        }
+    }
+
+    #[test]
+    fn mass_properties_round_trip() {
+        let mprops = MassProperties {
+            local_center_of_mass: Vect::new(0.0, 50.0, 0.0),
+            mass: 2.0,
+            principal_inertia: Vect::new(4.0, 8.0, 16.0),
+            ..default()
+        };
+        for scale in SCALES.map(ScaleCtx::new) {
+            let physics = scale.to_physics_mass_properties(mprops);
+            let s = scale.physics_scale;
+            assert!((physics.mass() - mprops.mass).abs() < 1e-4);
+            assert_close(
+                scale.to_render_vec(physics.local_com.coords),
+                mprops.local_center_of_mass,
+            );
+            assert_close(
+                Vect::from(physics.principal_inertia()) * s * s,
+                mprops.principal_inertia,
+            );
+        }
+    }
+
+    #[test]
+    fn isometries_round_trip() {
+        let transform =
+            Transform::from_xyz(10.0, -20.0, 30.0).with_rotation(Quat::from_rotation_y(0.5));
+        for scale in SCALES.map(ScaleCtx::new) {
+            let back =
+                scale.to_render_transform(&scale.to_physics_iso(&transform_to_iso(&transform)));
+            assert_close(back.translation, transform.translation);
+            assert!(back.rotation.angle_between(transform.rotation) < 1e-4);
+        }
+    }
+}
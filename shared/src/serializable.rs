@@ -1,6 +1,9 @@
+use std::fmt;
+
 use bevy_rapier3d::prelude::*;
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SerializableMassProperties {
@@ -154,6 +157,177 @@ impl From<SerializableRestitution> for Restitution {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableVelocity {
+    pub linvel: Vect,
+    pub angvel: Vect,
+}
+
+impl From<Velocity> for SerializableVelocity {
+    fn from(velocity: Velocity) -> Self {
+        Self {
+            linvel: velocity.linvel,
+            angvel: velocity.angvel,
+        }
+    }
+}
+
+impl From<SerializableVelocity> for Velocity {
+    fn from(velocity: SerializableVelocity) -> Self {
+        Self {
+            linvel: velocity.linvel,
+            angvel: velocity.angvel,
+        }
+    }
+}
+
+/// Any joint, e.g. one built with `RevoluteJointBuilder`, with its anchors and limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableGenericJoint(pub GenericJoint);
+
+impl From<GenericJoint> for SerializableGenericJoint {
+    fn from(joint: GenericJoint) -> Self {
+        Self(joint)
+    }
+}
+
+impl From<SerializableGenericJoint> for GenericJoint {
+    fn from(joint: SerializableGenericJoint) -> Self {
+        joint.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableCcd {
+    pub enabled: bool,
+}
+
+impl From<Ccd> for SerializableCcd {
+    fn from(ccd: Ccd) -> Self {
+        Self {
+            enabled: ccd.enabled,
+        }
+    }
+}
+
+impl From<SerializableCcd> for Ccd {
+    fn from(ccd: SerializableCcd) -> Self {
+        Self {
+            enabled: ccd.enabled,
+        }
+    }
+}
+
+/// The bits of `LockedAxes`, laid out like Rapier's `LockedAxes`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SerializableLockedAxes(pub u8);
+
+impl From<LockedAxes> for SerializableLockedAxes {
+    fn from(locked_axes: LockedAxes) -> Self {
+        Self(locked_axes.bits())
+    }
+}
+
+impl From<SerializableLockedAxes> for LockedAxes {
+    fn from(locked_axes: SerializableLockedAxes) -> Self {
+        Self::from_bits_truncate(locked_axes.0)
+    }
+}
+
+impl From<SerializableLockedAxes> for bevy_rapier3d::rapier::dynamics::LockedAxes {
+    fn from(locked_axes: SerializableLockedAxes) -> Self {
+        Self::from_bits_truncate(locked_axes.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableGravityScale(pub Real);
+
+impl From<GravityScale> for SerializableGravityScale {
+    fn from(gravity_scale: GravityScale) -> Self {
+        Self(gravity_scale.0)
+    }
+}
+
+impl From<SerializableGravityScale> for GravityScale {
+    fn from(gravity_scale: SerializableGravityScale) -> Self {
+        Self(gravity_scale.0)
+    }
+}
+
+/// Damping coefficients are per second and not scaled by the physics scale.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableDamping {
+    pub linear_damping: Real,
+    pub angular_damping: Real,
+}
+
+impl From<Damping> for SerializableDamping {
+    fn from(damping: Damping) -> Self {
+        Self {
+            linear_damping: damping.linear_damping,
+            angular_damping: damping.angular_damping,
+        }
+    }
+}
+
+impl From<SerializableDamping> for Damping {
+    fn from(damping: SerializableDamping) -> Self {
+        Self {
+            linear_damping: damping.linear_damping,
+            angular_damping: damping.angular_damping,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableExternalForce {
+    pub force: Vect,
+    pub torque: Vect,
+}
+
+impl From<ExternalForce> for SerializableExternalForce {
+    fn from(force: ExternalForce) -> Self {
+        Self {
+            force: force.force,
+            torque: force.torque,
+        }
+    }
+}
+
+impl From<SerializableExternalForce> for ExternalForce {
+    fn from(force: SerializableExternalForce) -> Self {
+        Self {
+            force: force.force,
+            torque: force.torque,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableExternalImpulse {
+    pub impulse: Vect,
+    pub torque_impulse: Vect,
+}
+
+impl From<ExternalImpulse> for SerializableExternalImpulse {
+    fn from(impulse: ExternalImpulse) -> Self {
+        Self {
+            impulse: impulse.impulse,
+            torque_impulse: impulse.torque_impulse,
+        }
+    }
+}
+
+impl From<SerializableExternalImpulse> for ExternalImpulse {
+    fn from(impulse: SerializableExternalImpulse) -> Self {
+        Self {
+            impulse: impulse.impulse,
+            torque_impulse: impulse.torque_impulse,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SerializableTimestepMode {
     Fixed {
@@ -232,6 +406,12 @@ pub struct SerializableRapierConfiguration {
     pub timestep_mode: SerializableTimestepMode,
     pub scaled_shape_subdivision: u32,
     pub force_update_from_transform_changes: bool,
+    /// Overrides the contact prediction distance of the whole world, in world units.
+    /// `None` keeps Rapier's default.
+    pub prediction_distance: Option<Real>,
+    /// Length of one physics unit in world units, like `RapierPhysicsPlugin::with_physics_scale`.
+    /// It can only change while the world has no bodies or colliders. `None` keeps the current one.
+    pub physics_scale: Option<Real>,
 }
 
 impl From<RapierConfiguration> for SerializableRapierConfiguration {
@@ -243,6 +423,8 @@ impl From<RapierConfiguration> for SerializableRapierConfiguration {
             timestep_mode: config.timestep_mode.into(),
             scaled_shape_subdivision: config.scaled_shape_subdivision,
             force_update_from_transform_changes: config.force_update_from_transform_changes,
+            prediction_distance: None,
+            physics_scale: None,
         }
     }
 }
@@ -259,3 +441,76 @@ impl From<SerializableRapierConfiguration> for RapierConfiguration {
         }
     }
 }
+
+/// Whether the points span a volume. Parry turns coplanar points into a flat
+/// polyhedron instead of failing, which has no mass and collides poorly.
+fn spans_volume(points: &[Vect]) -> bool {
+    let Some(&a) = points.first() else {
+        return false;
+    };
+    let farthest = |distance: &dyn Fn(Vect) -> f32| {
+        points
+            .iter()
+            .map(|point| (*point, distance(*point)))
+            .max_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+            .unwrap()
+    };
+
+    let (b, extent) = farthest(&|point| point.distance(a));
+    let eps = extent * 1.0e-4;
+    if extent <= 0.0 {
+        return false;
+    }
+    let axis = (b - a) / extent;
+    let (c, off_axis) = farthest(&|point| (point - a).cross(axis).length());
+    if off_axis <= eps {
+        return false;
+    }
+    let normal = axis.cross(c - a).normalize();
+    points
+        .iter()
+        .any(|point| (*point - a).dot(normal).abs() > eps)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SerializableShape {
+    Collider(Collider),
+    /// Convex hull of a point cloud, computed on the server.
+    ConvexHull(Vec<Vect>),
+}
+
+impl SerializableShape {
+    /// Builds the collider shape, `None` if the points don't span a volume.
+    pub fn into_collider(self) -> Option<Collider> {
+        match self {
+            Self::Collider(collider) => Some(collider),
+            Self::ConvexHull(points) if spans_volume(&points) => Collider::convex_hull(&points),
+            Self::ConvexHull(_) => None,
+        }
+    }
+
+    /// SHA-256 of the serialized shape. A client can't make two shapes share a hash,
+    /// so it can't replace a shape cached by another client.
+    pub fn content_hash(&self) -> ContentHash {
+        ContentHash(Sha256::digest(bincode::serialize(self).unwrap()).into())
+    }
+}
+
+/// See [`SerializableShape::content_hash`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ContentHash(pub [u8; 32]);
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(fmt, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ContentHash {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, fmt)
+    }
+}